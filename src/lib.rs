@@ -64,15 +64,10 @@ pub mod cli_app {
     }
     /// Clamp the vertical viewport start row to [0, total_rows − height]
     ///
-    /// On out-of-bounds, pulls the view back by 10 or to zero.
+    /// Any overshoot is corrected in one step; sheets shorter than the
+    /// viewport always start at row 0.
     pub fn clamp_viewport_ve(total_rows: i32, start_row: &mut i32) {
-        if *start_row > total_rows {
-            *start_row -= 10;
-        } else if *start_row > (total_rows - 10) {
-            *start_row = total_rows - 10;
-        } else if *start_row < 0 {
-            *start_row = 0;
-        }
+        clamp_viewport_ve_with(total_rows, 10, start_row);
     }
     /// Clamp a horizontal viewport coordinate so it stays within `[0..max_col]`.
     /// Clamp a column index so it never runs off the left or right of the sheet.
@@ -87,113 +82,490 @@ pub mod cli_app {
     /// # use spreadsheet::cli_app::clamp_viewport_hz;
     /// let mut c = 95;
     /// clamp_viewport_hz(90, &mut c);
-    /// assert_eq!(c, 85);
+    /// assert_eq!(c, 80);
     /// ```
     pub fn clamp_viewport_hz(total_cols: i32, start_col: &mut i32) {
-        if *start_col > total_cols {
-            *start_col -= 10;
-        } else if *start_col > (total_cols - 10) {
-            *start_col = total_cols - 10;
-        } else if *start_col < 0 {
-            *start_col = 0;
+        clamp_viewport_hz_with(total_cols, 10, start_col);
+    }
+    /// Clamp a viewport start row for a viewport `height` rows tall.
+    pub fn clamp_viewport_ve_with(total_rows: i32, height: i32, start_row: &mut i32) {
+        *start_row = (*start_row).clamp(0, (total_rows - height).max(0));
+    }
+    /// Clamp a viewport start column for a viewport `width` columns wide.
+    pub fn clamp_viewport_hz_with(total_cols: i32, width: i32, start_col: &mut i32) {
+        *start_col = (*start_col).clamp(0, (total_cols - width).max(0));
+    }
+    /// Assign `expr` to every cell of the rectangle `start:end`, stopping at
+    /// the first cell that fails; on success `status_msg` reports the count.
+    fn assign_range(
+        sheet: &mut Spreadsheet,
+        start: &str,
+        end: &str,
+        expr: &str,
+        status_msg: &mut String,
+    ) {
+        let (Some((r1, c1)), Some((r2, c2))) =
+            (cell_name_to_coords(start), cell_name_to_coords(end))
+        else {
+            *status_msg = "Invalid cell".to_string();
+            return;
+        };
+        let in_bounds =
+            |r: i32, c: i32| r >= 0 && r < sheet.total_rows && c >= 0 && c < sheet.total_cols;
+        if !in_bounds(r1, c1) || !in_bounds(r2, c2) {
+            *status_msg = "Cell out of bounds".to_string();
+            return;
         }
+        let mut count = 0;
+        for row in r1.min(r2)..=r1.max(r2) {
+            for col in c1.min(c2)..=c1.max(c2) {
+                sheet.update_cell_formula(row, col, expr, status_msg);
+                if status_msg != "Ok" {
+                    return;
+                }
+                count += 1;
+            }
+        }
+        *status_msg = format!("{} cells set", count);
     }
-    /// Process a single user command string, updating `sheet` and `status_msg`.
+    /// Move the CLI cursor by `(dr, dc)`, stopping at the sheet edges.
+    fn move_cursor(sheet: &mut Spreadsheet, dr: i32, dc: i32, status_msg: &mut String) {
+        match sheet.cursor {
+            Some((row, col)) => {
+                sheet.cursor = Some((
+                    (row + dr).clamp(0, sheet.total_rows - 1),
+                    (col + dc).clamp(0, sheet.total_cols - 1),
+                ));
+            }
+            None => *status_msg = "No cell selected".to_string(),
+        }
+    }
+    /// Run a newline-separated list of commands through `process_command`
+    /// without the REPL, returning the status each one finished with.
     ///
-    /// Recognized commands:
-    /// - `w`, `a`, `s`, `d`: scroll viewport  
-    /// - `scroll_to <CELL>`: jump viewport  
-    /// - `disable_output` / `enable_output`  
-    /// - `clear_cache`  
-    /// - `undo` / `redo` (feature-gated)  
-    /// - `<CELL>=<EXPR>`: assign formula to a cell  
-    /// - `history <CELL>` (feature-gated)
-    pub fn process_command(sheet: &mut Box<Spreadsheet>, cmd: &str, status_msg: &mut String) {
-        if cmd == "w" {
-            sheet.top_row -= 10;
-            clamp_viewport_ve(sheet.total_rows, &mut sheet.top_row);
-        } else if cmd == "s" {
-            sheet.top_row += 10;
-            clamp_viewport_ve(sheet.total_rows, &mut sheet.top_row);
-        } else if cmd == "a" {
-            sheet.left_col -= 10;
-            clamp_viewport_hz(sheet.total_cols, &mut sheet.left_col);
-        } else if cmd == "d" {
-            sheet.left_col += 10;
-            clamp_viewport_hz(sheet.total_cols, &mut sheet.left_col);
-        } else if cmd.starts_with("scroll_to") {
-            let parts: Vec<&str> = cmd.split_whitespace().collect();
+    /// Blank lines are skipped and `q` ends the script early, as at the
+    /// prompt. Each command starts from status `ok`, also as at the prompt.
+    pub fn run_script(sheet: &mut Box<Spreadsheet>, commands: &str) -> Vec<String> {
+        let mut statuses = Vec::new();
+        for line in commands.lines() {
+            let cmd = line.trim();
+            if cmd.is_empty() {
+                continue;
+            }
+            if cmd == "q" {
+                break;
+            }
+            let mut status_msg = String::from("ok");
+            process_command(sheet, cmd, &mut status_msg);
+            statuses.push(status_msg);
+        }
+        statuses
+    }
+    /// Direction of a scroll (`w`/`a`/`s`/`d`) or cursor move (`up`/...).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Dir {
+        Up,
+        Down,
+        Left,
+        Right,
+    }
+
+    /// A CLI command as recognized by `parse_command`, before it touches a
+    /// sheet. Cell names are kept as typed; `execute` resolves and
+    /// bounds-checks them.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Command {
+        /// `w`, `a`, `s`, `d`: scroll the viewport one screen
+        Scroll(Dir),
+        /// `scroll_to <CELL>`
+        ScrollTo(String),
+        /// `center_on <CELL>`
+        CenterOn(String),
+        /// `select <CELL>`
+        Select(String),
+        /// `up`, `down`, `left`, `right`
+        MoveCursor(Dir),
+        /// `enable_output` / `disable_output`
+        SetOutput(bool),
+        ClearCache,
+        /// `clear <CELL>`
+        Clear(String),
+        Undo,
+        Redo,
+        /// `undo <N>`
+        UndoN(usize),
+        /// `redo <N>`
+        RedoN(usize),
+        /// `<CELL>=<EXPR>`
+        Assign {
+            cell: String,
+            expr: String,
+        },
+        /// `<CELL>:<CELL>=<EXPR>`
+        AssignRange {
+            start: String,
+            end: String,
+            expr: String,
+        },
+        /// `name <NAME> <RANGE>`: see `Spreadsheet::define_name`
+        Name {
+            name: String,
+            range: String,
+        },
+        /// `note <CELL> <TEXT>`; an empty `text` (`note <CELL>`) removes it
+        Note {
+            cell: String,
+            text: String,
+        },
+        /// `log`: show the command log (feature-gated)
+        Log,
+        /// `clear_log`: empty the command log (feature-gated)
+        ClearLog,
+        /// `history <CELL>`; `None` when not given exactly one argument
+        History(Option<String>),
+        /// Anything else: entered into the cursor cell, if there is one
+        Expr(String),
+        /// A known command with the wrong arguments
+        Invalid,
+    }
+
+    /// Recognize one command line (see `process_command` for the syntax).
+    pub fn parse_command(cmd: &str) -> Command {
+        let parts: Vec<&str> = cmd.split_whitespace().collect();
+        // `<name> <CELL>` commands: exactly one argument
+        let one_arg = |make: fn(String) -> Command| {
             if parts.len() == 2 {
-                let cell_name = parts[1];
-                if let Some((row, col)) = cell_name_to_coords(cell_name) {
-                    if row < 0 || row >= sheet.total_rows || col < 0 || col >= sheet.total_cols {
-                        *status_msg = "Cell reference out of bounds".to_string();
-                    } else {
-                        sheet.top_row = row;
-                        sheet.left_col = col;
-                    }
-                } else {
-                    *status_msg = "Invalid cell".to_string();
-                }
+                make(parts[1].to_string())
             } else {
-                *status_msg = "Invalid command".to_string();
-            }
-        } else if cmd == "disable_output" {
-            sheet.output_enabled = false;
-        } else if cmd == "enable_output" {
-            sheet.output_enabled = true;
-        } else if cmd == "clear_cache" {
-            sheet.cache.clear();
-            sheet.dirty_cells.clear();
-            clear_range_cache();
-            *status_msg = "Cache cleared".to_string();
-        } else if cmd == "undo" {
-            #[cfg(feature = "undo_state")]
-            {
-                sheet.undo(status_msg);
+                Command::Invalid
             }
-            #[cfg(not(feature = "undo_state"))]
-            {
-                *status_msg = "Undo feature is not enabled.".to_string();
+        };
+        match cmd {
+            "w" => return Command::Scroll(Dir::Up),
+            "s" => return Command::Scroll(Dir::Down),
+            "a" => return Command::Scroll(Dir::Left),
+            "d" => return Command::Scroll(Dir::Right),
+            "up" => return Command::MoveCursor(Dir::Up),
+            "down" => return Command::MoveCursor(Dir::Down),
+            "left" => return Command::MoveCursor(Dir::Left),
+            "right" => return Command::MoveCursor(Dir::Right),
+            "disable_output" => return Command::SetOutput(false),
+            "enable_output" => return Command::SetOutput(true),
+            "clear_cache" => return Command::ClearCache,
+            "log" => return Command::Log,
+            "clear_log" => return Command::ClearLog,
+            "undo" => return Command::Undo,
+            "redo" => return Command::Redo,
+            _ => {}
+        }
+        if cmd.starts_with("scroll_to") {
+            one_arg(Command::ScrollTo)
+        } else if cmd.starts_with("center_on") {
+            one_arg(Command::CenterOn)
+        } else if cmd.starts_with("select") {
+            one_arg(Command::Select)
+        } else if cmd.starts_with("clear ") {
+            one_arg(Command::Clear)
+        } else if cmd.starts_with("undo ") || cmd.starts_with("redo ") {
+            match (
+                parts.len(),
+                parts.get(1).and_then(|n| n.parse::<usize>().ok()),
+            ) {
+                (2, Some(count)) if count > 0 && parts[0] == "undo" => Command::UndoN(count),
+                (2, Some(count)) if count > 0 => Command::RedoN(count),
+                _ => Command::Invalid,
             }
-        } else if cmd == "redo" {
-            #[cfg(feature = "undo_state")]
-            {
-                sheet.redo(status_msg);
+        } else if cmd.starts_with("name ") {
+            match parts.as_slice() {
+                [_, name, range] => Command::Name {
+                    name: name.to_string(),
+                    range: range.to_string(),
+                },
+                _ => Command::Invalid,
+            }
+        } else if let Some(rest) = cmd.strip_prefix("note ") {
+            // The note is the rest of the line, inner spacing kept
+            let rest = rest.trim();
+            let (cell, text) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            Command::Note {
+                cell: cell.to_string(),
+                text: text.trim_start().to_string(),
             }
-            #[cfg(not(feature = "undo_state"))]
+        } else if cmd.starts_with("history") {
+            Command::History((parts.len() == 2).then(|| parts[1].to_string()))
+        } else if let Some((cell_name, expr)) = split_assignment(cmd) {
+            // Lotus-style `A1=+B1+C1`: a leading '+' just marks formula entry
+            let expr = expr.strip_prefix('+').unwrap_or(expr).to_string();
+            match cell_name.split_once(':') {
+                Some((start, end)) => Command::AssignRange {
+                    start: start.to_string(),
+                    end: end.to_string(),
+                    expr,
+                },
+                None => Command::Assign {
+                    cell: cell_name.to_string(),
+                    expr,
+                },
+            }
+        } else {
+            Command::Expr(cmd.to_string())
+        }
+    }
+
+    /// Split `A1=expr` at its assignment `=`: the first one outside quotes
+    /// that isn't part of a comparison (`==`, `<=`, `>=`, `!=`), so `B1==2`
+    /// stays a bare expression.
+    fn split_assignment(cmd: &str) -> Option<(&str, &str)> {
+        let bytes = cmd.as_bytes();
+        let mut in_quotes = false;
+        let pos = (0..bytes.len()).find(|&i| {
+            in_quotes ^= bytes[i] == b'"';
+            !in_quotes
+                && bytes[i] == b'='
+                && bytes.get(i + 1) != Some(&b'=')
+                && !(i > 0 && matches!(bytes[i - 1], b'=' | b'<' | b'>' | b'!'))
+        })?;
+        Some((&cmd[..pos], &cmd[pos + 1..]))
+    }
+
+    /// Resolve `name` to an in-bounds cell, or set `status_msg` to
+    /// `invalid` / `out_of_bounds` and return `None`.
+    fn resolve_cell(
+        sheet: &Spreadsheet,
+        name: &str,
+        out_of_bounds: &str,
+        status_msg: &mut String,
+    ) -> Option<(i32, i32)> {
+        match cell_name_to_coords(name) {
+            Some((row, col))
+                if row >= 0 && row < sheet.total_rows && col >= 0 && col < sheet.total_cols =>
             {
-                *status_msg = "Undo/Redo feature is not enabled.".to_string();
-            }
-        } else if cmd.contains('=') {
-            if let Some(eq_pos) = cmd.find('=') {
-                let cell_name = &cmd[..eq_pos];
-                let expr = &cmd[eq_pos + 1..];
-                if let Some((row, col)) = cell_name_to_coords(cell_name) {
-                    if row < 0 || row >= sheet.total_rows || col < 0 || col >= sheet.total_cols {
-                        *status_msg = "Cell out of bounds".to_string();
+                Some((row, col))
+            }
+            Some(_) => {
+                *status_msg = out_of_bounds.to_string();
+                None
+            }
+            None => {
+                *status_msg = "Invalid cell".to_string();
+                None
+            }
+        }
+    }
+
+    /// Apply a parsed command to `sheet`, reporting the outcome in `status_msg`.
+    pub fn execute(sheet: &mut Spreadsheet, cmd: Command, status_msg: &mut String) {
+        match cmd {
+            Command::Scroll(dir) => {
+                match dir {
+                    Dir::Up => sheet.top_row -= sheet.viewport_rows,
+                    Dir::Down => sheet.top_row += sheet.viewport_rows,
+                    Dir::Left => sheet.left_col -= sheet.viewport_cols,
+                    Dir::Right => sheet.left_col += sheet.viewport_cols,
+                }
+                clamp_viewport_ve_with(sheet.total_rows, sheet.viewport_rows, &mut sheet.top_row);
+                clamp_viewport_hz_with(sheet.total_cols, sheet.viewport_cols, &mut sheet.left_col);
+            }
+            Command::ScrollTo(name) => {
+                if let Some((row, col)) =
+                    resolve_cell(sheet, &name, "Cell reference out of bounds", status_msg)
+                {
+                    sheet.top_row = row;
+                    sheet.left_col = col;
+                }
+            }
+            Command::CenterOn(name) => {
+                if let Some((row, col)) =
+                    resolve_cell(sheet, &name, "Cell reference out of bounds", status_msg)
+                {
+                    sheet.top_row = row - sheet.viewport_rows / 2;
+                    sheet.left_col = col - sheet.viewport_cols / 2;
+                    clamp_viewport_ve_with(
+                        sheet.total_rows,
+                        sheet.viewport_rows,
+                        &mut sheet.top_row,
+                    );
+                    clamp_viewport_hz_with(
+                        sheet.total_cols,
+                        sheet.viewport_cols,
+                        &mut sheet.left_col,
+                    );
+                }
+            }
+            Command::Select(name) => {
+                if let Some(pos) = resolve_cell(sheet, &name, "Cell out of bounds", status_msg) {
+                    sheet.cursor = Some(pos);
+                }
+            }
+            Command::MoveCursor(dir) => {
+                let (dr, dc) = match dir {
+                    Dir::Up => (-1, 0),
+                    Dir::Down => (1, 0),
+                    Dir::Left => (0, -1),
+                    Dir::Right => (0, 1),
+                };
+                move_cursor(sheet, dr, dc, status_msg);
+            }
+            Command::SetOutput(enabled) => sheet.output_enabled = enabled,
+            Command::ClearCache => {
+                sheet.cache.clear();
+                sheet.dirty_cells.clear();
+                clear_range_cache();
+                *status_msg = "Cache cleared".to_string();
+            }
+            Command::Clear(name) => {
+                if let Some((row, col)) =
+                    resolve_cell(sheet, &name, "Cell out of bounds", status_msg)
+                {
+                    sheet.clear_cell(row, col, status_msg);
+                    if status_msg == "Ok" {
+                        *status_msg = "Cell cleared".to_string();
+                    }
+                }
+            }
+            Command::Undo => {
+                #[cfg(feature = "undo_state")]
+                {
+                    sheet.undo(status_msg);
+                }
+                #[cfg(not(feature = "undo_state"))]
+                {
+                    *status_msg = "Undo feature is not enabled.".to_string();
+                }
+            }
+            Command::Redo => {
+                #[cfg(feature = "undo_state")]
+                {
+                    sheet.redo(status_msg);
+                }
+                #[cfg(not(feature = "undo_state"))]
+                {
+                    *status_msg = "Undo/Redo feature is not enabled.".to_string();
+                }
+            }
+            Command::UndoN(count) | Command::RedoN(count) => {
+                #[cfg(feature = "undo_state")]
+                {
+                    if let Command::UndoN(_) = cmd {
+                        sheet.undo_n(count, status_msg);
                     } else {
-                        sheet.update_cell_formula(row, col, expr, status_msg);
+                        sheet.redo_n(count, status_msg);
                     }
-                } else {
-                    *status_msg = "Invalid cell".to_string();
+                }
+                #[cfg(not(feature = "undo_state"))]
+                {
+                    let _ = count;
+                    *status_msg = "Undo/Redo feature is not enabled.".to_string();
                 }
             }
-        } else if cmd.starts_with("history") {
-            let parts: Vec<&str> = cmd.split_whitespace().collect();
-            if parts.len() == 2 {
-                #[cfg(feature = "cell_history")]
+            Command::Assign { cell, expr } => {
+                if let Some((row, col)) =
+                    resolve_cell(sheet, &cell, "Cell out of bounds", status_msg)
+                {
+                    sheet.update_cell_formula(row, col, &expr, status_msg);
+                }
+            }
+            Command::AssignRange { start, end, expr } => {
+                assign_range(sheet, &start, &end, &expr, status_msg);
+            }
+            Command::Name { name, range } => sheet.define_name(&name, &range, status_msg),
+            Command::Note { cell, text } => {
+                if let Some((row, col)) =
+                    resolve_cell(sheet, &cell, "Cell out of bounds", status_msg)
+                {
+                    sheet.set_note(row, col, &text);
+                    *status_msg = if text.is_empty() {
+                        "Note removed".to_string()
+                    } else {
+                        "Note set".to_string()
+                    };
+                }
+            }
+            Command::Log => {
+                #[cfg(feature = "command_log")]
                 {
                     sheet.skip_default_display = true;
-                    *status_msg = "History displayed".to_string();
+                    *status_msg = "Log displayed".to_string();
                 }
-                #[cfg(not(feature = "cell_history"))]
+                #[cfg(not(feature = "command_log"))]
                 {
-                    *status_msg = "Cell history feature is not enabled.".to_string();
+                    *status_msg = "Command log feature is not enabled.".to_string();
                 }
             }
-        } else {
-            *status_msg = "unrecognized cmd".to_string();
+            Command::ClearLog => {
+                #[cfg(feature = "command_log")]
+                {
+                    sheet.command_log.clear();
+                    *status_msg = "Log cleared".to_string();
+                }
+                #[cfg(not(feature = "command_log"))]
+                {
+                    *status_msg = "Command log feature is not enabled.".to_string();
+                }
+            }
+            Command::History(cell) => {
+                if cell.is_some() {
+                    #[cfg(feature = "cell_history")]
+                    {
+                        sheet.skip_default_display = true;
+                        *status_msg = "History displayed".to_string();
+                    }
+                    #[cfg(not(feature = "cell_history"))]
+                    {
+                        *status_msg = "Cell history feature is not enabled.".to_string();
+                    }
+                }
+            }
+            Command::Expr(expr) => {
+                if let Some((row, col)) = sheet.cursor {
+                    // A bare expression goes into the cursor cell; on success the
+                    // cursor steps down a row, ready for the next entry
+                    sheet.update_cell_formula(row, col, &expr, status_msg);
+                    if status_msg == "Ok" {
+                        sheet.cursor = Some(((row + 1).min(sheet.total_rows - 1), col));
+                    }
+                } else {
+                    *status_msg = "unrecognized cmd".to_string();
+                }
+            }
+            Command::Invalid => *status_msg = "Invalid command".to_string(),
+        }
+    }
+
+    /// Process a single user command string, updating `sheet` and `status_msg`:
+    /// `parse_command` followed by `execute`.
+    ///
+    /// Recognized commands:
+    /// - `w`, `a`, `s`, `d`: scroll viewport  
+    /// - `scroll_to <CELL>`: jump viewport  
+    /// - `center_on <CELL>`: jump viewport so the cell is centered  
+    /// - `disable_output` / `enable_output`  
+    /// - `clear_cache`  
+    /// - `clear <CELL>`: empty a cell  
+    /// - `undo` / `redo`, `undo <N>` / `redo <N>` (feature-gated)  
+    /// - `select <CELL>`, `up` / `down` / `left` / `right`: move the cursor  
+    /// - `<CELL>=<EXPR>`: assign formula to a cell  
+    /// - `<CELL>:<CELL>=<EXPR>`: assign the same formula to a block  
+    /// - `<EXPR>`: assign to the cursor cell, then move down  
+    /// - `name <NAME> <RANGE>`: define a named range, e.g. `name Revenue B2:B13`  
+    /// - `note <CELL> <TEXT>`: attach a note to a cell; `note <CELL>` removes it  
+    /// - `history <CELL>` (feature-gated)
+    /// - `log` / `clear_log`: show or empty the command log (feature-gated)
+    pub fn process_command(sheet: &mut Box<Spreadsheet>, cmd: &str, status_msg: &mut String) {
+        execute(sheet, parse_command(cmd), status_msg);
+        record_command(sheet, cmd, status_msg);
+    }
+
+    /// Append `cmd` and its final status to the sheet's command log when the
+    /// `command_log` feature is on. `log` and `clear_log` themselves are not
+    /// recorded, so viewing the log doesn't change it.
+    pub fn record_command(sheet: &mut Spreadsheet, cmd: &str, status_msg: &str) {
+        #[cfg(feature = "command_log")]
+        {
+            let cmd = cmd.trim();
+            if !matches!(cmd, "log" | "clear_log") {
+                sheet.log_command(cmd, status_msg);
+            }
         }
     }
 }
@@ -264,19 +636,29 @@ mod lib_tests {
     #[test]
     #[cfg(feature = "cli_app")]
     fn test_clamp_viewport_cli() {
-        // vertical: total_rows = 40, viewport height = 10 → only subtracts 10 once
+        // vertical: total_rows = 40, viewport height = 10 → last window starts at 30
         let mut top = 50;
         cli_app::clamp_viewport_ve(40, &mut top);
-        assert_eq!(top, 40);
+        assert_eq!(top, 30);
+
+        // a big jump is corrected in one pass
+        let mut far = 500;
+        cli_app::clamp_viewport_ve(40, &mut far);
+        assert_eq!(far, 30);
+
+        // sheets shorter than the viewport pin to 0
+        let mut short = 3;
+        cli_app::clamp_viewport_ve(5, &mut short);
+        assert_eq!(short, 0);
 
         let mut too_low = -5;
         cli_app::clamp_viewport_ve(100, &mut too_low);
         assert_eq!(too_low, 0);
 
-        // horizontal: total_cols = 90, viewport width = 10 → only subtracts 10 once
+        // horizontal: total_cols = 90, viewport width = 10 → last window starts at 80
         let mut left = 95;
         cli_app::clamp_viewport_hz(90, &mut left);
-        assert_eq!(left, 85);
+        assert_eq!(left, 80);
 
         let mut too_left = -1;
         cli_app::clamp_viewport_hz(10, &mut too_left);
@@ -327,6 +709,128 @@ mod lib_tests {
         assert!(msg.contains("Invalid command"));
     }
 
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_scroll_uses_viewport_size() {
+        let mut sheet = Box::new(Spreadsheet::new(100, 100));
+        sheet.viewport_rows = 25;
+        sheet.viewport_cols = 4;
+        let mut msg = String::new();
+        cli_app::process_command(&mut sheet, "s", &mut msg);
+        cli_app::process_command(&mut sheet, "d", &mut msg);
+        assert_eq!((sheet.top_row, sheet.left_col), (25, 4));
+
+        // the last page still ends at the bottom of the sheet
+        for _ in 0..5 {
+            cli_app::process_command(&mut sheet, "s", &mut msg);
+        }
+        assert_eq!(sheet.top_row, 75);
+
+        cli_app::process_command(&mut sheet, "center_on J50", &mut msg);
+        assert_eq!((sheet.top_row, sheet.left_col), (37, 7));
+    }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_cursor_select_move_and_entry() {
+        let mut sheet = Box::new(Spreadsheet::new(3, 3));
+        let mut msg = String::new();
+
+        // no cursor yet: bare expressions are still rejected
+        cli_app::process_command(&mut sheet, "5", &mut msg);
+        assert_eq!(msg, "unrecognized cmd");
+        msg.clear();
+        cli_app::process_command(&mut sheet, "down", &mut msg);
+        assert_eq!(msg, "No cell selected");
+
+        msg.clear();
+        cli_app::process_command(&mut sheet, "select B1", &mut msg);
+        assert_eq!(sheet.cursor, Some((0, 1)));
+        cli_app::process_command(&mut sheet, "select D1", &mut msg);
+        assert_eq!(msg, "Cell out of bounds");
+        assert_eq!(sheet.cursor, Some((0, 1)));
+
+        // entries fill downwards
+        cli_app::process_command(&mut sheet, "4", &mut msg);
+        cli_app::process_command(&mut sheet, "B1*2", &mut msg);
+        assert_eq!(sheet.get_cell_value(0, 1), 4);
+        assert_eq!(sheet.get_cell_value(1, 1), 8);
+        assert_eq!(sheet.cursor, Some((2, 1)));
+
+        // movement stops at the edges
+        cli_app::process_command(&mut sheet, "down", &mut msg);
+        cli_app::process_command(&mut sheet, "right", &mut msg);
+        cli_app::process_command(&mut sheet, "right", &mut msg);
+        assert_eq!(sheet.cursor, Some((2, 2)));
+        cli_app::process_command(&mut sheet, "up", &mut msg);
+        cli_app::process_command(&mut sheet, "left", &mut msg);
+        assert_eq!(sheet.cursor, Some((1, 1)));
+
+        // a bad entry leaves the cursor where it is
+        cli_app::process_command(&mut sheet, "Z99+1", &mut msg);
+        assert_ne!(msg, "Ok");
+        assert_eq!(sheet.cursor, Some((1, 1)));
+
+        // shrinking the sheet pulls the cursor back inside it
+        cli_app::process_command(&mut sheet, "select C3", &mut msg);
+        sheet.resize(2, 2);
+        assert_eq!(sheet.cursor, Some((1, 1)));
+        cli_app::process_command(&mut sheet, "9", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(sheet.get_cell_value(1, 1), 9);
+        assert!(sheet.cells.keys().all(|&(r, c)| r < 2 && c < 2));
+    }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_range_assignment() {
+        let mut sheet = Box::new(Spreadsheet::new(5, 5));
+        let mut msg = String::new();
+
+        cli_app::process_command(&mut sheet, "A1:A5=7", &mut msg);
+        assert_eq!(msg, "5 cells set");
+        assert!((0..5).all(|r| sheet.get_cell_value(r, 0) == 7));
+
+        // corners in either order, relative formulas are copied verbatim
+        cli_app::process_command(&mut sheet, "C2:B1=A1+1", &mut msg);
+        assert_eq!(msg, "4 cells set");
+        assert_eq!(sheet.get_cell_value(1, 2), 8);
+        assert_eq!(sheet.get_formula(0, 1).as_deref(), Some("A1+1"));
+
+        cli_app::process_command(&mut sheet, "A1:F1=0", &mut msg);
+        assert_eq!(msg, "Cell out of bounds");
+        cli_app::process_command(&mut sheet, "A1:1A=0", &mut msg);
+        assert_eq!(msg, "Invalid cell");
+        assert_eq!(sheet.get_cell_value(0, 0), 7);
+    }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_process_center_on() {
+        let mut sheet = Box::new(Spreadsheet::new(40, 40));
+        let mut msg = String::new();
+
+        // interior cell lands in the middle of the 10x10 window
+        cli_app::process_command(&mut sheet, "center_on P20", &mut msg);
+        assert_eq!((sheet.top_row, sheet.left_col), (14, 10));
+        assert!(msg.is_empty());
+
+        // near the top-left corner the window stays on the grid
+        cli_app::process_command(&mut sheet, "center_on B2", &mut msg);
+        assert_eq!((sheet.top_row, sheet.left_col), (0, 0));
+
+        // near the bottom-right corner too
+        cli_app::process_command(&mut sheet, "center_on AN40", &mut msg);
+        assert_eq!((sheet.top_row, sheet.left_col), (30, 30));
+
+        // scroll_to still puts the cell in the top-left
+        cli_app::process_command(&mut sheet, "scroll_to P20", &mut msg);
+        assert_eq!((sheet.top_row, sheet.left_col), (19, 15));
+
+        cli_app::process_command(&mut sheet, "center_on A99", &mut msg);
+        assert!(msg.contains("out of bounds"));
+    }
+
     #[test]
     #[cfg(feature = "cli_app")]
     fn test_enable_disable_clear_cache() {
@@ -399,4 +903,211 @@ mod lib_tests {
         assert_eq!(gui_app::coords_to_cell_name(4, 25), "Z5");
         assert_eq!(gui_app::coords_to_cell_name(9, 26), "AA10");
     }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_assignment_with_leading_plus() {
+        let mut sheet = Box::new(Spreadsheet::new(3, 3));
+        let mut msg = String::new();
+        cli_app::process_command(&mut sheet, "B1=4", &mut msg);
+        cli_app::process_command(&mut sheet, "C1=5", &mut msg);
+
+        cli_app::process_command(&mut sheet, "A1=+B1+C1", &mut msg);
+        assert_eq!(msg, "Ok");
+        cli_app::process_command(&mut sheet, "A2=B1+C1", &mut msg);
+        assert_eq!(sheet.get_cell_value(0, 0), 9);
+        assert_eq!(sheet.get_cell_value(0, 0), sheet.get_cell_value(1, 0));
+        assert_eq!(sheet.get_formula(0, 0), sheet.get_formula(1, 0));
+    }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_clear_command() {
+        let mut sheet = Box::new(Spreadsheet::new(3, 3));
+        let mut msg = String::new();
+        cli_app::process_command(&mut sheet, "A1=5", &mut msg);
+        cli_app::process_command(&mut sheet, "B1=A1+1", &mut msg);
+
+        cli_app::process_command(&mut sheet, "clear A1", &mut msg);
+        assert_eq!(msg, "Cell cleared");
+        assert_eq!(sheet.get_cell_raw_content(0, 0), "");
+        assert_eq!(sheet.get_cell_value(0, 1), 1);
+
+        cli_app::process_command(&mut sheet, "clear Z9", &mut msg);
+        assert_eq!(msg, "Cell out of bounds");
+        cli_app::process_command(&mut sheet, "clear 12", &mut msg);
+        assert_eq!(msg, "Invalid cell");
+        cli_app::process_command(&mut sheet, "clear A1 B1", &mut msg);
+        assert_eq!(msg, "Invalid command");
+    }
+
+    #[test]
+    #[cfg(all(feature = "cli_app", feature = "undo_state"))]
+    fn test_undo_n_command() {
+        let mut sheet = Spreadsheet::new(2, 2);
+        let mut msg = String::new();
+        cli_app::process_command(&mut sheet, "A1=1", &mut msg);
+        cli_app::process_command(&mut sheet, "A1=2", &mut msg);
+        cli_app::process_command(&mut sheet, "A1=3", &mut msg);
+
+        cli_app::process_command(&mut sheet, "undo 2", &mut msg);
+        assert_eq!(msg, "Undid 2 of 2");
+        assert_eq!(sheet.get_cell_value(0, 0), 1);
+        cli_app::process_command(&mut sheet, "redo 3", &mut msg);
+        assert_eq!(msg, "Redid 2 of 3 (nothing more to redo)");
+        assert_eq!(sheet.get_cell_value(0, 0), 3);
+
+        cli_app::process_command(&mut sheet, "undo x", &mut msg);
+        assert_eq!(msg, "Invalid command");
+    }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_run_script() {
+        let mut sheet = Spreadsheet::new(3, 3);
+        let script = "A1=5\n\n  B1=A1*2  \nZ9=1\nB1=\nclear A1\nq\nA1=7\n";
+        let statuses = cli_app::run_script(&mut sheet, script);
+        assert_eq!(
+            statuses,
+            vec![
+                "Ok",
+                "Ok",
+                "Cell out of bounds",
+                "Unrecognized",
+                "Cell cleared"
+            ]
+        );
+        assert_eq!(sheet.get_formula(0, 1).as_deref(), Some("A1*2"));
+        assert_eq!(
+            sheet.get_cell_typed(0, 0),
+            crate::sheet::CellValue::Empty,
+            "stopped at q"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_parse_command() {
+        use crate::cli_app::{parse_command, Command, Dir};
+        assert_eq!(parse_command("w"), Command::Scroll(Dir::Up));
+        assert_eq!(parse_command("left"), Command::MoveCursor(Dir::Left));
+        assert_eq!(
+            parse_command("scroll_to B3"),
+            Command::ScrollTo("B3".into())
+        );
+        assert_eq!(parse_command("scroll_to"), Command::Invalid);
+        assert_eq!(parse_command("undo 3"), Command::UndoN(3));
+        assert_eq!(parse_command("redo 0"), Command::Invalid);
+        assert_eq!(
+            parse_command("A1=+B1+1"),
+            Command::Assign {
+                cell: "A1".into(),
+                expr: "B1+1".into()
+            }
+        );
+        assert_eq!(
+            parse_command("A1=B1==2"),
+            Command::Assign {
+                cell: "A1".into(),
+                expr: "B1==2".into()
+            }
+        );
+        for comparison in ["B1==2", "B1>=2", "B1<=2", "B1!=2", "CONCAT(\"a=b\")"] {
+            assert_eq!(
+                parse_command(comparison),
+                Command::Expr(comparison.into()),
+                "{comparison}"
+            );
+        }
+        assert_eq!(
+            parse_command("A1:B2=7"),
+            Command::AssignRange {
+                start: "A1".into(),
+                end: "B2".into(),
+                expr: "7".into()
+            }
+        );
+        assert_eq!(
+            parse_command("history C4"),
+            Command::History(Some("C4".into()))
+        );
+        assert_eq!(parse_command("history"), Command::History(None));
+        assert_eq!(
+            parse_command("name Revenue B2:B13"),
+            Command::Name {
+                name: "Revenue".into(),
+                range: "B2:B13".into()
+            }
+        );
+        assert_eq!(parse_command("name Revenue"), Command::Invalid);
+        assert_eq!(
+            parse_command("SUM(A1:A3)"),
+            Command::Expr("SUM(A1:A3)".into())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_command_log() {
+        use crate::cli_app::{parse_command, process_command, Command};
+        assert_eq!(parse_command("log"), Command::Log);
+        assert_eq!(parse_command("clear_log"), Command::ClearLog);
+
+        let mut sheet = Spreadsheet::new(3, 3);
+        let mut status = String::new();
+        process_command(&mut sheet, "A1=5", &mut status);
+        process_command(&mut sheet, "A1=FOO(", &mut status);
+        process_command(&mut sheet, "log", &mut status);
+        #[cfg(feature = "command_log")]
+        {
+            assert_eq!(status, "Log displayed");
+            let entries: Vec<_> = sheet.command_log.iter().cloned().collect();
+            assert_eq!(
+                entries,
+                vec![
+                    ("A1=5".to_string(), "Ok".to_string()),
+                    ("A1=FOO(".to_string(), "Unrecognized".to_string()),
+                ]
+            );
+            for _ in 0..1005 {
+                sheet.log_command("w", "ok");
+            }
+            assert_eq!(sheet.command_log.len(), 1000);
+            process_command(&mut sheet, "clear_log", &mut status);
+            assert!(sheet.command_log.is_empty());
+        }
+        #[cfg(not(feature = "command_log"))]
+        assert_eq!(status, "Command log feature is not enabled.");
+    }
+
+    #[test]
+    #[cfg(feature = "cli_app")]
+    fn test_note_command() {
+        use crate::cli_app::{parse_command, process_command, Command};
+        assert_eq!(
+            parse_command("note B2 check  this"),
+            Command::Note {
+                cell: "B2".into(),
+                text: "check  this".into()
+            }
+        );
+        assert_eq!(
+            parse_command("note B2"),
+            Command::Note {
+                cell: "B2".into(),
+                text: String::new()
+            }
+        );
+
+        let mut sheet = Spreadsheet::new(3, 3);
+        let mut status = String::new();
+        process_command(&mut sheet, "note B2 from the Q3 report", &mut status);
+        assert_eq!(status, "Note set");
+        assert_eq!(sheet.get_note(1, 1), Some("from the Q3 report"));
+        process_command(&mut sheet, "note Z9 nope", &mut status);
+        assert_eq!(status, "Cell out of bounds");
+        process_command(&mut sheet, "note B2", &mut status);
+        assert_eq!(status, "Note removed");
+        assert_eq!(sheet.get_note(1, 1), None);
+    }
 }
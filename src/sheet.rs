@@ -16,12 +16,22 @@
 //! let mut sheet = Spreadsheet::new(10, 5);
 //! sheet.update_cell_formula(0, 0, "42", &mut String::new());
 //! let cs = CloneableSheet::new(&*sheet);
-//! assert_eq!(cs.get_cell(0, 0).unwrap().value, 42);
+//! assert_eq!(cs.get_cell(0, 0).unwrap().number(), 42);
 //! ```
 #![allow(warnings)]
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 /// The status of a cell after evaluation.
 ///
 /// - `Ok` means the value is valid  
@@ -30,18 +40,75 @@ pub enum CellStatus {
     Ok,
     Error,
 }
+
+/// The typed content of a cell, as stored in `Cell::value` and returned by
+/// `Spreadsheet::get_cell_typed`: `Int` for a numeric cell (the usual case),
+/// `Text` for a string cell, `Empty` when nothing has been entered.
+pub type CellValue = crate::parser::Value;
+
+/// Cell contents captured by `Spreadsheet::copy_cell`: the raw formula plus
+/// the cell it came from, so `paste_cell` can shift relative references.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CopiedFormula {
+    pub formula: String,
+    pub origin: (i32, i32),
+}
+
+/// One difference reported by `Spreadsheet::diff`: the cell's evaluated
+/// value on each side, `None` where that sheet has nothing in the cell.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CellDiff {
+    pub row: i32,
+    pub col: i32,
+    pub left: Option<i32>,
+    pub right: Option<i32>,
+}
+
+/// How `get_cell_display` renders a numeric cell. Display-only: the stored
+/// value is untouched. The default (no decimals, no separators) is plain
+/// `value.to_string()`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct CellFormat {
+    pub decimals: u8,    // Zeros shown after the decimal point
+    pub thousands: bool, // Group the integer part with `,` (`1,234`)
+}
+
+impl CellFormat {
+    /// Render `value` in this format, e.g. `-1234` with 2 decimals and
+    /// separators as `-1,234.00`.
+    pub fn apply(self, value: i32) -> String {
+        let digits = value.unsigned_abs().to_string();
+        let mut out = String::with_capacity(digits.len() + digits.len() / 3 + 2);
+        if value < 0 {
+            out.push('-');
+        }
+        for (i, ch) in digits.chars().enumerate() {
+            if self.thousands && i > 0 && (digits.len() - i) % 3 == 0 {
+                out.push(',');
+            }
+            out.push(ch);
+        }
+        if self.decimals > 0 {
+            out.push('.');
+            out.extend(std::iter::repeat('0').take(self.decimals as usize));
+        }
+        out
+    }
+}
 /// Internal representation of a single spreadsheet cell.
 ///
-/// Holds the current `value`, an optional `formula_idx` into
-/// `Spreadsheet::formula_storage`, its `status`, plus
+/// Holds the current `value` (`Int`, `Text` or `Empty`), an optional
+/// `formula_idx` into `Spreadsheet::formula_storage`, its `status`, plus
 /// `dependencies` and `dependents` for incremental recalculation.
+/// `note` is free-form documentation; evaluation never looks at it.
 // Optimize Cell structure by removing redundant fields and using more compact storage
 pub struct Cell {
-    pub value: i32,
+    pub value: CellValue,           // Numeric readers see text cells as errors
     pub formula_idx: Option<usize>, // Index into formula storage instead of storing entire string
     pub status: CellStatus,
     pub dependencies: HashSet<(i32, i32)>,
     pub dependents: HashSet<(i32, i32)>,
+    pub note: Option<String>, // Comment attached by the user, independent of the value
     // --- Additions for Cell History ---
     #[cfg(feature = "cell_history")]
     pub history: VecDeque<i32>, // Store last N values
@@ -49,6 +116,20 @@ pub struct Cell {
                                 // Removed row and col fields as they can be derived from the cell's position in the HashMap
 }
 
+impl Cell {
+    /// What numeric readers see: the `Int` value, 0 for text and empty cells.
+    pub fn number(&self) -> i32 {
+        self.value.as_int().unwrap_or(0)
+    }
+}
+
+/// Nothing entered: no formula, an `Empty` value and `Ok`. Such a cell only
+/// exists to carry `dependents` links (or a note) and otherwise reads as
+/// never set; an explicit 0 is a value like any other.
+fn is_blank(cell: &Cell) -> bool {
+    cell.formula_idx.is_none() && cell.value == CellValue::Empty && cell.status == CellStatus::Ok
+}
+
 // --- Additions for Undo State ---
 #[cfg(feature = "undo_state")]
 #[derive(Clone, Debug)] // Clone might be useful, Debug for inspection
@@ -58,7 +139,7 @@ struct PreviousCellState {
     row: i32,
     col: i32,
     previous_formula_idx: Option<usize>, // Store index directly
-    previous_value: i32,
+    previous_value: CellValue,
     previous_status: CellStatus,
     previous_dependencies: HashSet<(i32, i32)>,
     // Store the dependents that pointed *to this cell* before the change
@@ -70,9 +151,29 @@ struct PreviousCellState {
 #[cfg(feature = "cell_history")]
 const MAX_HISTORY_SIZE: usize = 10;
 
+/// Append `value` to a cell history, dropping the oldest entries so that at
+/// most `limit` remain (a shrunk limit is applied here, on the next push).
+#[cfg(feature = "cell_history")]
+fn push_history(history: &mut VecDeque<i32>, value: i32, limit: usize) {
+    history.push_back(value);
+    while history.len() > limit {
+        history.pop_front();
+    }
+}
+
+// Most recent commands kept in `Spreadsheet::command_log`
+#[cfg(feature = "command_log")]
+const MAX_COMMAND_LOG: usize = 1000;
+
 // --- Define the maximum number of undo levels ---
 #[cfg(feature = "undo_state")]
-const MAX_UNDO_LEVELS: usize = 10; // Set the desired history limit [User Requirement]
+const MAX_UNDO_LEVELS: usize = 10; // Default for `Spreadsheet::undo_limit`
+
+// Default rows/columns shown by the CLI viewport (`viewport_rows`/`viewport_cols`)
+const VIEWPORT_SIZE: i32 = 10;
+
+// Default for `Spreadsheet::max_sleep`
+const MAX_SLEEP: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 /// A cached result of a range-function (`SUM`, `MIN`, etc.),
@@ -81,6 +182,32 @@ pub struct CachedRange {
     pub value: i32,
     pub dependencies: HashSet<(i32, i32)>,
 }
+/// Plain serializable snapshot of a sheet: its dimensions plus every
+/// non-empty cell. Produced by `Spreadsheet::to_json`.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct SheetDto {
+    pub rows: i32,
+    pub cols: i32,
+    pub cells: Vec<CellDto>,
+}
+
+/// One cell inside a `SheetDto`; `status` serializes as `"ok"` / `"error"`.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct CellDto {
+    pub row: i32,
+    pub col: i32,
+    pub formula: Option<String>,
+    pub value: i32,
+    pub status: CellStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+// Source of `Spreadsheet::cache_id`, unique per sheet for the process
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
+
 /// A sparse spreadsheet of size `total_rows × total_cols`.
 ///
 /// Cells are stored in a `HashMap<(row,col), Cell>` only when
@@ -95,21 +222,185 @@ pub struct Spreadsheet {
     pub total_cols: i32,
     pub cells: HashMap<(i32, i32), Cell>, // Sparse representation instead of Vec<Vec<Cell>>
     pub formula_storage: Vec<String>,     // Central storage for all formulas
+    formula_asts: Vec<Option<crate::parser::ASTNode>>, // Parsed form of each `formula_storage` entry, if it has one
     pub top_row: i32,
     pub left_col: i32,
+    pub viewport_rows: i32,         // Rows the CLI shows and scrolls by
+    pub viewport_cols: i32,         // Columns the CLI shows and scrolls by
+    pub cursor: Option<(i32, i32)>, // CLI current cell; bare expressions are entered here
+    pub frozen_rows: i32,           // Leading rows always shown, whatever `top_row` is
+    pub frozen_cols: i32,           // Leading columns always shown, whatever `left_col` is
+    pub named_ranges: HashMap<String, (i32, i32, i32, i32)>, // `define_name` registry: name -> bounds
+    pub cell_format: HashMap<(i32, i32), CellFormat>, // Display formats; unformatted cells are absent
     pub output_enabled: bool,
     pub skip_default_display: bool,
+    pub sleep_enabled: bool, // When false, SLEEP(n) returns n without blocking
+    pub max_sleep: Duration, // Longest a single SLEEP really blocks (default 10s)
     pub cache: HashMap<String, CachedRange>, // Cached range evaluations
-    pub dirty_cells: HashSet<(i32, i32)>,    // Track cells needing recalculation
+    pub dirty_cells: HashSet<(i32, i32)>, // Track cells needing recalculation
+    pub lazy: bool, // Edits leave dependents dirty until read; see `get_cell_value_computed`
+    pub default_value: i32, // What blank cells read as; see `get_cell_value`
+    pub skip_absent: bool, // Range aggregates leave blank cells out; see `get_cell_value`
+    pub header_row: Option<i32>, // Row whose text cells name the columns below; see `header_columns`
     pub in_degree: HashMap<(i32, i32), usize>,
+    pub revision: u64, // Bumped on every cell write; see `modified_since`
+    pub modified_revs: HashMap<(i32, i32), u64>, // Revision at which each cell last changed
+    #[doc(hidden)]
+    pub force_large_range: bool, // Test hook: always use parser::evaluate_large_range
+    siblings: Vec<(String, Box<Spreadsheet>)>, // Other `Workbook` sheets, lent while this one is edited
+    cache_id: u64, // Sets this sheet's range cache entries apart from other sheets'
+    #[cfg(feature = "cell_history")]
+    pub history_limit: usize, // Max values kept per cell history (default MAX_HISTORY_SIZE)
+    #[cfg(feature = "command_log")]
+    pub command_log: VecDeque<(String, String)>, // (command, resulting status), oldest first
     // --- Modify Undo/Redo State Storage ---
     #[cfg(feature = "undo_state")]
-    undo_stack: Vec<PreviousCellState>, // Use a Vec for undo history [6, 7]
+    undo_stack: VecDeque<PreviousCellState>, // Oldest at the front so trimming is O(1)
+    #[cfg(feature = "undo_state")]
+    pub undo_limit: usize, // Max undo levels kept (default MAX_UNDO_LEVELS)
     #[cfg(feature = "undo_state")]
     redo_stack: Vec<PreviousCellState>, // Use a Vec for redo history [6, 7]
                                         // --- End Modifications ---
 }
 
+/// Configures a `Spreadsheet` before it is created; see `Spreadsheet::builder`.
+///
+/// Anything not set keeps the `Spreadsheet::new` default.
+#[derive(Clone, Debug)]
+pub struct SpreadsheetBuilder {
+    rows: i32,
+    cols: i32,
+    output_enabled: bool,
+    sleep_enabled: bool,
+    max_sleep: Duration,
+    lazy: bool,
+    default_value: i32,
+    skip_absent: bool,
+    header_row: Option<i32>,
+    #[cfg(feature = "cell_history")]
+    history_limit: usize,
+    #[cfg(feature = "undo_state")]
+    undo_limit: usize,
+    range_cache_capacity: Option<usize>,
+}
+
+impl SpreadsheetBuilder {
+    pub fn new(rows: i32, cols: i32) -> Self {
+        SpreadsheetBuilder {
+            rows,
+            cols,
+            output_enabled: true,
+            sleep_enabled: true,
+            max_sleep: MAX_SLEEP,
+            lazy: false,
+            default_value: 0,
+            skip_absent: false,
+            header_row: None,
+            #[cfg(feature = "cell_history")]
+            history_limit: MAX_HISTORY_SIZE,
+            #[cfg(feature = "undo_state")]
+            undo_limit: MAX_UNDO_LEVELS,
+            range_cache_capacity: None,
+        }
+    }
+
+    /// Grid size, replacing the one given to `new`.
+    pub fn dimensions(mut self, rows: i32, cols: i32) -> Self {
+        self.rows = rows;
+        self.cols = cols;
+        self
+    }
+
+    /// Whether the CLI prints the grid after each command.
+    pub fn output_enabled(mut self, enabled: bool) -> Self {
+        self.output_enabled = enabled;
+        self
+    }
+
+    /// Whether `SLEEP(n)` really blocks; see `Spreadsheet::sleep_enabled`.
+    pub fn sleep_enabled(mut self, enabled: bool) -> Self {
+        self.sleep_enabled = enabled;
+        self
+    }
+
+    /// Cap on how long one `SLEEP` blocks; see `Spreadsheet::max_sleep`.
+    pub fn max_sleep(mut self, max: Duration) -> Self {
+        self.max_sleep = max;
+        self
+    }
+
+    /// Recompute dependents on read instead of on edit; see `Spreadsheet::lazy`.
+    pub fn lazy(mut self, lazy: bool) -> Self {
+        self.lazy = lazy;
+        self
+    }
+
+    /// What blank cells read as; see `Spreadsheet::get_cell_value`.
+    pub fn default_value(mut self, value: i32) -> Self {
+        self.default_value = value;
+        self
+    }
+
+    /// Leave blank cells out of range aggregates; see `Spreadsheet::get_cell_value`.
+    pub fn skip_absent(mut self, skip: bool) -> Self {
+        self.skip_absent = skip;
+        self
+    }
+
+    /// Let formulas name columns by their header in `row`; see
+    /// `Spreadsheet::header_columns`.
+    pub fn header_row(mut self, row: i32) -> Self {
+        self.header_row = Some(row);
+        self
+    }
+
+    /// Values kept per cell history.
+    #[cfg(feature = "cell_history")]
+    pub fn history_limit(mut self, limit: usize) -> Self {
+        self.history_limit = limit;
+        self
+    }
+
+    /// Undo levels kept.
+    #[cfg(feature = "undo_state")]
+    pub fn undo_limit(mut self, limit: usize) -> Self {
+        self.undo_limit = limit;
+        self
+    }
+
+    /// Entries kept in the parser's range cache. The cache is not owned by
+    /// the sheet, so `build` applies this through
+    /// `parser::set_range_cache_capacity` (per thread, or shared with the
+    /// `shared_cache` feature).
+    pub fn range_cache_capacity(mut self, capacity: usize) -> Self {
+        self.range_cache_capacity = Some(capacity);
+        self
+    }
+
+    pub fn build(self) -> Box<Spreadsheet> {
+        let mut sheet = Spreadsheet::new(self.rows, self.cols);
+        sheet.output_enabled = self.output_enabled;
+        sheet.sleep_enabled = self.sleep_enabled;
+        sheet.max_sleep = self.max_sleep;
+        sheet.lazy = self.lazy;
+        sheet.default_value = self.default_value;
+        sheet.skip_absent = self.skip_absent;
+        sheet.header_row = self.header_row;
+        #[cfg(feature = "cell_history")]
+        {
+            sheet.history_limit = self.history_limit;
+        }
+        #[cfg(feature = "undo_state")]
+        {
+            sheet.undo_limit = self.undo_limit;
+        }
+        if let Some(capacity) = self.range_cache_capacity {
+            crate::parser::set_range_cache_capacity(capacity);
+        }
+        sheet
+    }
+}
+
 impl Spreadsheet {
     pub fn get_cell_raw_content(&self, row: i32, col: i32) -> String {
         // Look for the cell in the HashMap using the (row, col) tuple as the key.
@@ -156,22 +447,53 @@ impl Spreadsheet {
             total_cols: cols,
             cells: HashMap::new(),
             formula_storage: Vec::new(),
+            formula_asts: Vec::new(),
             top_row: 0,
             left_col: 0,
+            viewport_rows: VIEWPORT_SIZE,
+            viewport_cols: VIEWPORT_SIZE,
+            cursor: None,
+            frozen_rows: 0,
+            frozen_cols: 0,
+            named_ranges: HashMap::new(),
+            cell_format: HashMap::new(),
             output_enabled: true,
             skip_default_display: false,
+            sleep_enabled: true,
+            max_sleep: MAX_SLEEP,
             cache: HashMap::new(),
             dirty_cells: HashSet::new(),
+            lazy: false,
+            default_value: 0,
+            skip_absent: false,
+            header_row: None,
             in_degree: HashMap::new(),
+            revision: 0,
+            modified_revs: HashMap::new(),
+            force_large_range: false,
+            siblings: Vec::new(),
+            cache_id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            #[cfg(feature = "cell_history")]
+            history_limit: MAX_HISTORY_SIZE,
+            #[cfg(feature = "command_log")]
+            command_log: VecDeque::new(),
             // --- Initialize Undo/Redo Stacks ---
             #[cfg(feature = "undo_state")]
-            undo_stack: Vec::with_capacity(MAX_UNDO_LEVELS), // Initialize empty stacks [6, 7]
+            undo_stack: VecDeque::with_capacity(MAX_UNDO_LEVELS), // Initialize empty stacks [6, 7]
+            #[cfg(feature = "undo_state")]
+            undo_limit: MAX_UNDO_LEVELS,
             #[cfg(feature = "undo_state")]
             redo_stack: Vec::new(), // Redo stack often doesn't need strict capacity
                                     // --- End Initialization ---
         })
     }
 
+    /// Start configuring a `rows × cols` sheet, e.g.
+    /// `Spreadsheet::builder(100, 26).output_enabled(false).build()`.
+    pub fn builder(rows: i32, cols: i32) -> SpreadsheetBuilder {
+        SpreadsheetBuilder::new(rows, cols)
+    }
+
     // --- Additions for Undo State ---
     // --- Helper to capture state (used by undo and redo) ---
     /// Capture all fields of a cell so it can be restored later.
@@ -184,7 +506,7 @@ impl Spreadsheet {
                 row,
                 col,
                 previous_formula_idx: cell.formula_idx,
-                previous_value: cell.value,
+                previous_value: cell.value.clone(),
                 previous_status: cell.status.clone(),
                 previous_dependencies: cell.dependencies.clone(),
                 previous_dependents_of_cell: cell.dependents.clone(),
@@ -195,7 +517,7 @@ impl Spreadsheet {
                 row,
                 col,
                 previous_formula_idx: None,
-                previous_value: 0,
+                previous_value: CellValue::Empty,
                 previous_status: CellStatus::Ok,
                 previous_dependencies: HashSet::new(),
                 previous_dependents_of_cell: HashSet::new(),
@@ -215,11 +537,12 @@ impl Spreadsheet {
             self.cells.insert(
                 (row, col),
                 Cell {
-                    value: 0,
+                    value: CellValue::Empty,
                     formula_idx: None,
                     status: CellStatus::Ok,
                     dependencies: HashSet::new(),
                     dependents: HashSet::new(),
+                    note: None,
                     // Initialize cell history if feature is enabled
                     #[cfg(feature = "cell_history")]
                     history: VecDeque::with_capacity(MAX_HISTORY_SIZE),
@@ -228,10 +551,50 @@ impl Spreadsheet {
         }
         self.cells.get_mut(&(row, col)).unwrap()
     }
-    /// Read-only helpers: return 0 / Ok for nonexistent cells.
-    // Helper method to get cell value (returns 0 for non-existent cells)
+    /// Whether `(row,col)` lies on the grid.
+    pub fn is_in_bounds(&self, row: i32, col: i32) -> bool {
+        row >= 0 && row < self.total_rows && col >= 0 && col < self.total_cols
+    }
+    /// The stored cell at `(row,col)`. Unlike the getters below, this tells an
+    /// empty cell apart from a missing one: `None` both for cells never
+    /// written and for coordinates off the grid (see `is_in_bounds`).
+    pub fn get_cell(&self, row: i32, col: i32) -> Option<&Cell> {
+        if !self.is_in_bounds(row, col) {
+            return None;
+        }
+        self.cells.get(&(row, col))
+    }
+    /// Read-only helpers: return `default_value` / Ok for nonexistent cells.
+    // Helper method to get cell value (returns the default for non-existent cells)
+    ///
+    /// A blank cell (one that was never set, or holds nothing: see
+    /// `get_cell_typed`) reads as `default_value`, 0 unless configured, here
+    /// and in formulas. Range aggregates (`SUM`, `MIN`, `MAX`, `AVG`, `STDEV`,
+    /// `STDEVP`, `MODE`) count blank cells with that value too, so `AVG(A1:A4)`
+    /// over one 8 and three blanks is 2. With `skip_absent` they leave blank
+    /// cells out instead (that `AVG` is 8); a range with nothing left in it
+    /// sums to 0 and is a runtime error for the others. Set both through the
+    /// builder; after changing them on a live sheet, call `recalculate_all`.
     pub fn get_cell_value(&self, row: i32, col: i32) -> i32 {
-        self.cells.get(&(row, col)).map_or(0, |cell| cell.value)
+        match self.cells.get(&(row, col)) {
+            Some(cell) if !is_blank(cell) => cell.number(),
+            _ => self.default_value,
+        }
+    }
+    /// Whether `(row,col)` holds nothing; see `get_cell_value`.
+    pub fn is_blank(&self, row: i32, col: i32) -> bool {
+        self.cells.get(&(row, col)).map_or(true, is_blank)
+    }
+    /// Return the cell's content as a `CellValue`: `Text` for text cells,
+    /// `Empty` for cells that hold nothing (see `is_blank`), otherwise `Int`. Error cells report their (zeroed) `Int` value.
+    pub fn get_cell_typed(&self, row: i32, col: i32) -> CellValue {
+        match self.cells.get(&(row, col)) {
+            Some(cell) if !is_blank(cell) => match &cell.value {
+                CellValue::Empty => CellValue::Int(0),
+                value => value.clone(),
+            },
+            _ => CellValue::Empty,
+        }
     }
     /// Return the `CellStatus` or `Ok` if the cell is missing.
     // Helper method to get cell status (returns Ok for non-existent cells)
@@ -240,6 +603,184 @@ impl Spreadsheet {
             .get(&(row, col))
             .map_or(CellStatus::Ok, |cell| cell.status.clone())
     }
+    /// The text a front-end shows for `(row,col)`: `ERR` for error cells,
+    /// an empty string for empty cells (see `get_cell_typed`), the text of a
+    /// text cell, otherwise the value in the cell's `CellFormat`.
+    pub fn get_cell_display(&self, row: i32, col: i32) -> String {
+        if self.get_cell_status(row, col) == CellStatus::Error {
+            return "ERR".to_string();
+        }
+        match self.get_cell_typed(row, col) {
+            CellValue::Int(v) => self.get_cell_format(row, col).apply(v),
+            CellValue::Text(text) => text,
+            _ => String::new(),
+        }
+    }
+    /// Display format of `(row,col)`; the default for unformatted cells.
+    pub fn get_cell_format(&self, row: i32, col: i32) -> CellFormat {
+        self.cell_format
+            .get(&(row, col))
+            .copied()
+            .unwrap_or_default()
+    }
+    /// Set the display format of `(row,col)`. Setting the default format
+    /// drops the entry.
+    pub fn set_cell_format(&mut self, row: i32, col: i32, format: CellFormat) {
+        if format == CellFormat::default() {
+            self.cell_format.remove(&(row, col));
+        } else {
+            self.cell_format.insert((row, col), format);
+        }
+    }
+    /// Note attached to `(row,col)`, if any.
+    pub fn get_note(&self, row: i32, col: i32) -> Option<&str> {
+        self.cells.get(&(row, col))?.note.as_deref()
+    }
+    /// Attach `text` as the note of `(row,col)`; an empty `text` removes it.
+    /// Out-of-bounds cells are ignored. Notes never touch the cell's value,
+    /// formula or dependencies, and are not part of undo history.
+    pub fn set_note(&mut self, row: i32, col: i32, text: &str) {
+        if !self.is_in_bounds(row, col) {
+            return;
+        }
+        if !text.is_empty() {
+            self.get_or_create_cell(row, col).note = Some(text.to_string());
+        } else if let Some(cell) = self.cells.get_mut(&(row, col)) {
+            cell.note = None;
+            // Drop cells that only existed to carry the note
+            if is_blank(cell) && cell.dependencies.is_empty() && cell.dependents.is_empty() {
+                self.cells.remove(&(row, col));
+            }
+        }
+    }
+    /// Values of the rectangle spanned by `(r1,c1)` and `(r2,c2)`, one inner
+    /// `Vec` per row. Corners may be given in any order and are clamped to the
+    /// grid; empty cells read as 0.
+    pub fn get_range_values(&self, r1: i32, c1: i32, r2: i32, c2: i32) -> Vec<Vec<i32>> {
+        self.map_range(r1, c1, r2, c2, |row, col| self.get_cell_value(row, col))
+    }
+    /// Statuses of the same rectangle as `get_range_values` (`Ok` for empty cells).
+    pub fn get_range_statuses(&self, r1: i32, c1: i32, r2: i32, c2: i32) -> Vec<Vec<CellStatus>> {
+        self.map_range(r1, c1, r2, c2, |row, col| self.get_cell_status(row, col))
+    }
+    /// Values of column `col`, top to bottom (see `get_cell_value`); empty
+    /// if `col` is off the grid.
+    pub fn get_column(&self, col: i32) -> Vec<i32> {
+        if col < 0 || col >= self.total_cols {
+            return Vec::new();
+        }
+        (0..self.total_rows)
+            .map(|row| self.get_cell_value(row, col))
+            .collect()
+    }
+    /// Statuses of column `col`, parallel to `get_column`.
+    pub fn get_column_statuses(&self, col: i32) -> Vec<CellStatus> {
+        if col < 0 || col >= self.total_cols {
+            return Vec::new();
+        }
+        (0..self.total_rows)
+            .map(|row| self.get_cell_status(row, col))
+            .collect()
+    }
+    /// Values of row `row`, left to right; empty if `row` is off the grid.
+    pub fn get_row(&self, row: i32) -> Vec<i32> {
+        if row < 0 || row >= self.total_rows {
+            return Vec::new();
+        }
+        (0..self.total_cols)
+            .map(|col| self.get_cell_value(row, col))
+            .collect()
+    }
+    // Shared ordering/clamping for the range getters
+    fn map_range<T>(
+        &self,
+        r1: i32,
+        c1: i32,
+        r2: i32,
+        c2: i32,
+        f: impl Fn(i32, i32) -> T,
+    ) -> Vec<Vec<T>> {
+        if self.total_rows <= 0 || self.total_cols <= 0 {
+            return Vec::new();
+        }
+        let clamp_row = |r: i32| r.clamp(0, self.total_rows - 1);
+        let clamp_col = |c: i32| c.clamp(0, self.total_cols - 1);
+        let (top, bottom) = (clamp_row(r1.min(r2)), clamp_row(r1.max(r2)));
+        let (left, right) = (clamp_col(c1.min(c2)), clamp_col(c1.max(c2)));
+        (top..=bottom)
+            .map(|row| (left..=right).map(|col| f(row, col)).collect())
+            .collect()
+    }
+    /// Number of cells actually stored. A cleared cell that other formulas
+    /// still read stays stored, so it is counted too.
+    pub fn populated_cell_count(&self) -> usize {
+        self.cells.len()
+    }
+    /// Fraction of the grid that is stored, `populated_cell_count / (rows *
+    /// cols)`; 0.0 for a zero-sized sheet.
+    pub fn sparsity(&self) -> f64 {
+        let capacity = self.total_rows.max(0) as f64 * self.total_cols.max(0) as f64;
+        if capacity == 0.0 {
+            return 0.0;
+        }
+        self.populated_cell_count() as f64 / capacity
+    }
+    /// Iterate over the populated cells as `(row, col, &Cell)`, in no
+    /// particular order. Empty cells are never visited.
+    pub fn iter_cells(&self) -> impl Iterator<Item = (i32, i32, &Cell)> + '_ {
+        self.cells
+            .iter()
+            .map(|(&(row, col), cell)| (row, col, cell))
+    }
+    /// Like `iter_cells`, but in row-major order for deterministic output.
+    pub fn iter_cells_ordered(&self) -> impl Iterator<Item = (i32, i32, &Cell)> + '_ {
+        let mut entries: Vec<(i32, i32, &Cell)> = self.iter_cells().collect();
+        entries.sort_unstable_by_key(|&(row, col, _)| (row, col));
+        entries.into_iter()
+    }
+    /// Cells that `(row,col)`'s formula reads, sorted row-major. Empty if
+    /// the cell doesn't exist.
+    pub fn get_dependencies(&self, row: i32, col: i32) -> Vec<(i32, i32)> {
+        self.sorted_links(row, col, |cell| &cell.dependencies)
+    }
+    /// Cells whose formulas read `(row,col)`, sorted row-major. Empty if the
+    /// cell doesn't exist.
+    pub fn get_dependents(&self, row: i32, col: i32) -> Vec<(i32, i32)> {
+        self.sorted_links(row, col, |cell| &cell.dependents)
+    }
+    /// Every cell `(row,col)` ultimately reads from, following
+    /// `dependencies` transitively. The starting cell is excluded, even when
+    /// it sits on a cycle; each cell is visited once so cycles terminate.
+    pub fn trace_precedents(&self, row: i32, col: i32) -> HashSet<(i32, i32)> {
+        let mut visited = HashSet::new();
+        let mut stack = vec![(row, col)];
+
+        while let Some((r, c)) = stack.pop() {
+            if let Some(cell) = self.cells.get(&(r, c)) {
+                for &dep in &cell.dependencies {
+                    if dep != (row, col) && visited.insert(dep) {
+                        stack.push(dep);
+                    }
+                }
+            }
+        }
+
+        visited
+    }
+    fn sorted_links(
+        &self,
+        row: i32,
+        col: i32,
+        links: impl Fn(&Cell) -> &HashSet<(i32, i32)>,
+    ) -> Vec<(i32, i32)> {
+        let mut out: Vec<(i32, i32)> = self
+            .cells
+            .get(&(row, col))
+            .map(|cell| links(cell).iter().copied().collect())
+            .unwrap_or_default();
+        out.sort_unstable();
+        out
+    }
     /// If `(row,col)` has a formula, return it as `Some(String)`, else `None`.
     // Helper to get formula string
     pub fn get_formula(&self, row: i32, col: i32) -> Option<String> {
@@ -250,9 +791,56 @@ impl Spreadsheet {
         }
         None
     }
+    /// Every formula cell paired with its stored formula text, row-major.
+    /// Literal and text cells are left out.
+    pub fn all_formulas(&self) -> Vec<((i32, i32), String)> {
+        self.iter_cells_ordered()
+            .filter_map(|(row, col, cell)| {
+                let idx = cell.formula_idx?;
+                Some(((row, col), self.formula_storage[idx].clone()))
+            })
+            .collect()
+    }
+    /// What `expr` (e.g. `A1*2+SUM(B1:B3)`) evaluates to against the current
+    /// values, without storing it anywhere. A leading `=` is allowed. Range
+    /// results may still be cached, exactly as for a cell formula.
+    pub fn evaluate(&self, expr: &str) -> Result<i32, crate::parser::FormulaError> {
+        // no function depends on the formula's own position, so any cell does
+        crate::parser::evaluate_formula_checked(&CloneableSheet::new(self), expr, 0, 0)
+    }
+    /// Parsed form of `(row,col)`'s formula, for tools that walk formulas
+    /// instead of re-parsing the text. `None` for cells without a formula and
+    /// for formulas the AST can't express (comparisons, `IF`, `COUNTIF`, …;
+    /// see `parser::parse_to_ast`).
+    pub fn get_cell_ast(&self, row: i32, col: i32) -> Option<crate::parser::ASTNode> {
+        let idx = self.cells.get(&(row, col))?.formula_idx?;
+        self.formula_asts.get(idx)?.clone()
+    }
+    /// Intern `formula` in `formula_storage`, returning its index.
+    ///
+    /// Identical formula strings share one slot. The formula is parsed once
+    /// into `formula_asts` (see `parser::parse_to_ast`) so recalculation can
+    /// skip the string parser; formulas the AST can't express get `None`.
+    fn store_formula(&mut self, formula: &str) -> usize {
+        match self.formula_storage.iter().position(|f| f == formula) {
+            Some(idx) => idx,
+            None => {
+                self.formula_storage.push(formula.to_string());
+                self.formula_asts.push(
+                    crate::parser::parse_to_ast(formula, self.total_rows, self.total_cols).ok(),
+                );
+                self.formula_storage.len() - 1
+            }
+        }
+    }
     /// Overwrite the cell’s `value` and `status`.
     ///
     /// If `cell_history` is enabled, push the old value onto its history buffer.
+    /// Cached range results that read this cell are dropped when it changes.
+    ///
+    /// Low-level: a formula on the cell is kept (and will overwrite the value
+    /// on the next recalculation), dependents are not recalculated and
+    /// nothing is recorded for undo. Use `set_literal` to enter a number.
     // Helper to update cell value and potentially its history
     pub fn update_cell_value(
         &mut self,
@@ -261,23 +849,63 @@ impl Spreadsheet {
         new_value: i32,
         new_status: CellStatus,
     ) {
+        #[cfg(feature = "cell_history")]
+        let history_limit = self.history_limit;
         let cell = self.get_or_create_cell(row, col);
 
         // --- Additions for Cell History ---
         // Only add to history if the value actually changes and feature is enabled
         #[cfg(feature = "cell_history")]
         {
-            if cell.value != new_value {
-                if cell.history.len() == MAX_HISTORY_SIZE {
-                    cell.history.pop_front(); // Remove the oldest value
-                }
-                cell.history.push_back(cell.value); // Store the *current* value before overwriting
+            let old_value = cell.number();
+            if old_value != new_value {
+                push_history(&mut cell.history, old_value, history_limit);
             }
         }
         // --- End Additions ---
 
+        let new_value = CellValue::Int(new_value);
+        let changed = cell.value != new_value || cell.status != new_status;
         cell.value = new_value;
         cell.status = new_status;
+        self.stamp_modified(row, col);
+        if changed {
+            crate::parser::invalidate_cache_for_cell(row, col);
+        }
+    }
+    /// Enter the number `value` in `(row,col)` as typing it would, without
+    /// going through the parser: any formula is dropped and unregistered from
+    /// the cells it read, dependents are recalculated (left dirty on a `lazy`
+    /// sheet), and with `undo_state` the edit can be undone. Out-of-bounds
+    /// cells are ignored.
+    pub fn set_literal(&mut self, row: i32, col: i32, value: i32) {
+        if !self.is_in_bounds(row, col) {
+            return;
+        }
+        #[cfg(feature = "undo_state")]
+        {
+            let captured_prev_state = self.capture_current_cell_state(row, col);
+            self.record_undo(captured_prev_state);
+        }
+
+        let old_deps = match self.cells.get_mut(&(row, col)) {
+            Some(cell) => {
+                cell.formula_idx = None;
+                std::mem::take(&mut cell.dependencies)
+            }
+            None => HashSet::new(),
+        };
+        for dep in &old_deps {
+            if let Some(dep_cell) = self.cells.get_mut(dep) {
+                dep_cell.dependents.remove(&(row, col));
+            }
+        }
+        self.dirty_cells.remove(&(row, col));
+        self.update_cell_value(row, col, value, CellStatus::Ok);
+
+        let mut status_msg = String::new();
+        mark_cell_and_dependents_dirty(self, row, col);
+        self.recalc_after_edit(row, col, &mut status_msg);
     }
     // Add getter for cell history if feature enabled
     /// Return the last N values this cell held, most recent last.
@@ -287,6 +915,16 @@ impl Spreadsheet {
             .get(&(row, col))
             .map(|cell| cell.history.iter().cloned().collect())
     }
+    /// Record a processed command and the status it finished with, keeping
+    /// only the most recent `MAX_COMMAND_LOG` entries.
+    #[cfg(feature = "command_log")]
+    pub fn log_command(&mut self, cmd: &str, status: &str) {
+        self.command_log
+            .push_back((cmd.to_string(), status.to_string()));
+        while self.command_log.len() > MAX_COMMAND_LOG {
+            self.command_log.pop_front();
+        }
+    }
     /// Parse-and-apply a new formula at `(row,col)`, updating dependencies,
     /// invalidating cache, marking dirty, and immediate recalculation cascade.
     ///  
@@ -314,27 +952,29 @@ impl Spreadsheet {
         let captured_prev_state = self.capture_current_cell_state(row, col);
         // --- End Additions ---
 
-        if valid_formula(self, formula, status_msg) != 0 {
+        let expanded = self.expand_named_ranges(formula);
+        if let Err(err) =
+            crate::parser::validate_formula(&expanded, self.total_rows, self.total_cols)
+        {
+            status_msg.clear();
+            // same wording as when evaluation itself hits error 4 below
+            status_msg.push_str(match err {
+                crate::parser::FormulaError::OutOfBounds => "Range out of bounds",
+                _ => "Unrecognized",
+            });
+            return;
+        }
+        // validation can't see other sheets, so their references are checked here
+        if let Err(err) = self.check_sheet_refs(formula) {
             status_msg.clear();
-            status_msg.push_str("Unrecognized");
+            status_msg.push_str(err);
             return;
         }
         status_msg.clear();
         status_msg.push_str("Ok");
 
         #[cfg(feature = "undo_state")]
-        {
-            // Push the state *before* the change onto the undo stack
-            self.undo_stack.push(captured_prev_state);
-
-            // Enforce the history limit on the undo stack
-            if self.undo_stack.len() > MAX_UNDO_LEVELS {
-                self.undo_stack.remove(0); // Remove the oldest state [6, 7]
-            }
-
-            // Any new action clears the redo stack [7]
-            self.redo_stack.clear();
-        }
+        self.record_undo(captured_prev_state);
 
         // First, extract old dependencies
         let old_deps = if let Some(cell) = self.cells.get(&(row, col)) {
@@ -348,7 +988,8 @@ impl Spreadsheet {
 
         // Extract new dependencies
         let new_deps = if !formula.chars().all(|ch| ch.is_digit(10) || ch == '-') {
-            extract_dependencies_without_self(formula, self.total_rows, self.total_cols)
+            // `"B2"` inside a string literal is text, not a reference
+            self.formula_dependencies(formula)
         } else {
             HashSet::new()
         };
@@ -367,18 +1008,7 @@ impl Spreadsheet {
         }
 
         // Store the formula centrally and get its index - to avoid borrowing issues
-        let formula_idx = {
-            // Check if formula already exists to avoid duplication
-            let existing_idx = self.formula_storage.iter().position(|f| f == formula);
-            match existing_idx {
-                Some(idx) => idx,
-                None => {
-                    let idx = self.formula_storage.len();
-                    self.formula_storage.push(formula.to_string());
-                    idx
-                }
-            }
-        };
+        let formula_idx = self.store_formula(formula);
 
         // Set new formula and clear old dependencies
         {
@@ -414,23 +1044,7 @@ impl Spreadsheet {
             status_msg.push_str(&cell_name);
 
             // Handle old formula index for restoring
-            let old_formula_idx = if let Some(old_formula_str) = old_formula {
-                // Find or add the old formula back - avoid borrowing issues
-                let existing_idx = self
-                    .formula_storage
-                    .iter()
-                    .position(|f| f == &old_formula_str);
-                match existing_idx {
-                    Some(idx) => Some(idx),
-                    None => {
-                        let idx = self.formula_storage.len();
-                        self.formula_storage.push(old_formula_str);
-                        Some(idx)
-                    }
-                }
-            } else {
-                None
-            };
+            let old_formula_idx = old_formula.map(|f| self.store_formula(&f));
 
             // Now restore the cell's state
             let cell = self.get_or_create_cell(row, col);
@@ -461,6 +1075,13 @@ impl Spreadsheet {
         // Mark this cell as dirty for recalculation
         self.dirty_cells.remove(&(row, col));
 
+        // Lazy mode may have left what the formula reads stale
+        if self.lazy {
+            for &(dep_row, dep_col) in &new_deps {
+                self.get_cell_value_computed(dep_row, dep_col);
+            }
+        }
+
         // Evaluate the formula
         let mut error_flag = 0;
         let mut s_msg = String::new();
@@ -468,7 +1089,7 @@ impl Spreadsheet {
         // Create temporary clone for evaluation
         let new_val = {
             let sheet_clone = CloneableSheet::new(self);
-            crate::parser::evaluate_formula(
+            crate::parser::evaluate_formula_value(
                 &sheet_clone,
                 formula,
                 row,
@@ -479,9 +1100,19 @@ impl Spreadsheet {
         };
 
         if error_flag == 3 {
-            mark_cell_and_dependents_as_error(self, row, col);
+            {
+                let cell = self.get_or_create_cell(row, col);
+                cell.status = CellStatus::Error;
+                cell.value = CellValue::Int(0);
+            }
+            self.stamp_modified(row, col);
+            crate::parser::invalidate_cache_for_cell(row, col);
+            // Dependents are re-evaluated rather than blindly marked, so an
+            // IFERROR over this cell can fall back instead of failing too.
+            mark_cell_and_dependents_dirty(self, row, col);
             status_msg.clear();
             status_msg.push_str("Ok");
+            self.recalc_after_edit(row, col, status_msg);
             return;
         } else if error_flag == 4 {
             status_msg.clear();
@@ -495,19 +1126,20 @@ impl Spreadsheet {
             // Set the value and status first
             // Set the value and status first
             {
+                #[cfg(feature = "cell_history")]
+                let history_limit = self.history_limit;
                 let cell = self.get_or_create_cell(row, col);
                 #[cfg(feature = "cell_history")]
                 {
-                    if cell.value != new_val {
-                        if cell.history.len() == 10 {
-                            cell.history.pop_front(); // Remove the oldest value
-                        }
-                        cell.history.push_back(cell.value); // Store the *current* value before overwriting
+                    let old_value = cell.number();
+                    if old_value != new_val.as_int().unwrap_or(0) {
+                        push_history(&mut cell.history, old_value, history_limit);
                     }
                 }
                 cell.value = new_val;
                 cell.status = CellStatus::Ok;
             }
+            self.stamp_modified(row, col);
 
             // Then get the dependents (to avoid borrowing issues)
             let dependents = if let Some(cell) = self.cells.get(&(row, col)) {
@@ -528,64 +1160,380 @@ impl Spreadsheet {
             mark_cell_and_dependents_dirty(self, row, col);
 
             // Use the optimized recalculation
-            recalc_affected(self, status_msg);
+            self.recalc_after_edit(row, col, status_msg);
         }
     }
-    // --- Apply a captured state (Helper for Undo/Redo) ---
-    /// Restore one `PreviousCellState`, re-wiring dependencies and recalculating.
-    #[cfg(feature = "undo_state")] // <-- Update feature name
-    pub fn apply_state(&mut self, state_to_apply: &PreviousCellState, status_msg: &mut String) {
-        let row = state_to_apply.row;
-        let col = state_to_apply.col;
-
-        // 1. Get current dependencies before overwriting
-        let current_deps = self
-            .cells
-            .get(&(row, col))
-            .map_or(HashSet::new(), |c| c.dependencies.clone());
 
-        // 2. Restore the cell's core properties
-        {
-            let cell = self.get_or_create_cell(row, col);
-            cell.value = state_to_apply.previous_value;
-            cell.status = state_to_apply.previous_status.clone();
-            cell.formula_idx = state_to_apply.previous_formula_idx;
-            cell.dependencies = state_to_apply.previous_dependencies.clone();
-            cell.dependents = state_to_apply.previous_dependents_of_cell.clone();
+    /// Bring the dependents of a just-written `(row,col)` up to date, or in
+    /// `lazy` mode leave them dirty for `get_cell_value_computed`.
+    fn recalc_after_edit(&mut self, row: i32, col: i32, status_msg: &mut String) {
+        if self.header_row == Some(row) {
+            self.refresh_header_users();
+        }
+        if self.lazy {
+            self.dirty_cells.remove(&(row, col));
+        } else {
+            recalc_affected(self, status_msg);
         }
+    }
 
-        // 3. Update dependent links based on the change
-        // Remove the current cell from the dependents list of its *current* dependencies
-        for &(dep_row, dep_col) in &current_deps {
-            if let Some(dep_cell) = self.cells.get_mut(&(dep_row, dep_col)) {
-                dep_cell.dependents.remove(&(row, col));
+    /// Value of `(row,col)` with any stale inputs recomputed first, for
+    /// `lazy` sheets where edits don't recalculate their dependents.
+    ///
+    /// Walks the cell's precedents depth-first and re-evaluates, in
+    /// dependency order, every one that is dirty or reads one that was just
+    /// re-evaluated; the results are stored, so later reads are plain
+    /// lookups. On an eager sheet nothing is dirty and this is
+    /// `get_cell_value`. `recalc_dirty` brings the whole sheet up to date.
+    pub fn get_cell_value_computed(&mut self, row: i32, col: i32) -> i32 {
+        // post-order, so each cell comes after everything it reads
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![((row, col), false)];
+        while let Some((pos, expanded)) = stack.pop() {
+            if expanded {
+                order.push(pos);
+                continue;
+            }
+            if !visited.insert(pos) {
+                continue;
+            }
+            stack.push((pos, true));
+            if let Some(cell) = self.cells.get(&pos) {
+                stack.extend(
+                    cell.dependencies
+                        .iter()
+                        .filter(|dep| !visited.contains(*dep))
+                        .map(|&dep| (dep, false)),
+                );
             }
         }
-        // Add the current cell back to the dependents list of its *applied* dependencies
-        for &(dep_row, dep_col) in &state_to_apply.previous_dependencies {
-            self.get_or_create_cell(dep_row, dep_col)
-                .dependents
-                .insert((row, col));
+
+        let mut recomputed = HashSet::new();
+        for pos in order {
+            let stale = self.dirty_cells.remove(&pos)
+                || self
+                    .cells
+                    .get(&pos)
+                    .is_some_and(|cell| cell.dependencies.iter().any(|d| recomputed.contains(d)));
+            if stale {
+                crate::parser::invalidate_cache_for_cell(pos.0, pos.1);
+                recompute_cell(self, pos.0, pos.1);
+                recomputed.insert(pos);
+            }
         }
+        self.get_cell_value(row, col)
+    }
+    // --- Push a new edit onto the undo stack ---
+    /// Record the state *before* a new edit: push it onto the undo stack,
+    /// enforce the history limit and drop the (now stale) redo stack.
+    #[cfg(feature = "undo_state")]
+    fn record_undo(&mut self, state: PreviousCellState) {
+        self.push_undo(state);
 
-        // 4. Mark dirty and recalculate
-        self.dirty_cells.insert((row, col));
-        mark_cell_and_dependents_dirty(self, row, col);
-        crate::parser::invalidate_cache_for_cell(row, col);
-        recalc_affected(self, status_msg); // Recalculate using passed status_msg
+        // Any new action clears the redo stack [7]
+        self.redo_stack.clear();
     }
-    // --- End Apply State Helper ---
 
-    // --- Modify Undo Method for multi-level ---
+    /// Push onto the undo stack, dropping the oldest entries beyond `undo_limit`.
     #[cfg(feature = "undo_state")]
-    pub fn undo(&mut self, status_msg: &mut String) {
-        status_msg.clear();
+    fn push_undo(&mut self, state: PreviousCellState) {
+        self.undo_stack.push_back(state);
+        while self.undo_stack.len() > self.undo_limit {
+            self.undo_stack.pop_front();
+        }
+    }
 
-        // Pop from undo_stack if not empty [6, 7]
-        if let Some(state_to_restore) = self.undo_stack.pop() {
-            // Capture the current state *before* undoing, for REDO
-            let state_before_undo =
-                self.capture_current_cell_state(state_to_restore.row, state_to_restore.col);
+    /// Wipe the sheet back to empty, keeping its size and settings
+    /// (`output_enabled`, `history_limit`, `lazy`, …): cells, formulas,
+    /// named ranges, display formats, pending recalculation and, with
+    /// `undo_state`, the undo/redo history all go, as does the parser's range
+    /// cache. The viewport returns to A1 and the cursor is unset. Every cell
+    /// that held something is reported by `modified_since`.
+    pub fn clear_all(&mut self) {
+        let cleared: Vec<(i32, i32)> = self.cells.keys().copied().collect();
+        self.cells.clear();
+        self.formula_storage.clear();
+        self.formula_asts.clear();
+        self.named_ranges.clear();
+        self.cell_format.clear();
+        self.cache.clear();
+        self.dirty_cells.clear();
+        self.in_degree.clear();
+        #[cfg(feature = "undo_state")]
+        {
+            self.undo_stack.clear();
+            self.redo_stack.clear();
+        }
+        crate::parser::clear_range_cache();
+        self.top_row = 0;
+        self.left_col = 0;
+        self.cursor = None;
+        for (row, col) in cleared {
+            self.stamp_modified(row, col);
+        }
+    }
+
+    /// Replace the contents with `data`, one inner `Vec` per row, resizing
+    /// the sheet to `data.len()` rows by the longest row's columns (short
+    /// rows leave their remaining cells empty). Settings are kept as with
+    /// `clear_all`, and undo history starts empty.
+    pub fn load_grid(&mut self, data: &[Vec<i32>]) {
+        self.clear_all();
+        let cols = data.iter().map(Vec::len).max().unwrap_or(0);
+        self.resize(data.len() as i32, cols as i32);
+        for (r, values) in data.iter().enumerate() {
+            for (c, &value) in values.iter().enumerate() {
+                self.update_cell_value(r as i32, c as i32, value, CellStatus::Ok);
+            }
+        }
+    }
+
+    /// Like `load_grid`, but each entry is what a user would type: empty
+    /// strings leave the cell empty, integers are stored as plain values, and
+    /// everything else goes through `update_cell_formula` once all the values
+    /// are in place, so formulas may refer to cells on any row.
+    ///
+    /// A formula that is rejected leaves its cell empty and the load carries
+    /// on; `status_msg` is "Ok", or the first rejection followed by the
+    /// cell, e.g. "Unrecognized (B2)" (a circular dependency already names
+    /// it).
+    pub fn load_formulas(&mut self, data: &[Vec<&str>], status_msg: &mut String) {
+        self.clear_all();
+        let cols = data.iter().map(Vec::len).max().unwrap_or(0);
+        self.resize(data.len() as i32, cols as i32);
+
+        let mut formulas = Vec::new();
+        for (r, fields) in data.iter().enumerate() {
+            for (c, field) in fields.iter().enumerate() {
+                let (row, col) = (r as i32, c as i32);
+                let field = field.trim();
+                if field.is_empty() {
+                    continue;
+                }
+                match field.parse::<i32>() {
+                    Ok(v) => self.update_cell_value(row, col, v, CellStatus::Ok),
+                    Err(_) => formulas.push((row, col, field)),
+                }
+            }
+        }
+
+        let mut first_error = None;
+        let mut msg = String::new();
+        for (row, col, formula) in formulas {
+            self.update_cell_formula(row, col, formula, &mut msg);
+            if msg != "Ok" && first_error.is_none() {
+                let cell_name = coords_to_cell_name(row, col);
+                // circular-dependency messages already name the cell
+                first_error = Some(if msg.ends_with(&cell_name) {
+                    msg.clone()
+                } else {
+                    format!("{} ({})", msg, cell_name)
+                });
+            }
+        }
+
+        #[cfg(feature = "undo_state")]
+        {
+            self.undo_stack.clear();
+            self.redo_stack.clear();
+        }
+        status_msg.clear();
+        status_msg.push_str(first_error.as_deref().unwrap_or("Ok"));
+    }
+
+    /// Empty `(row,col)`: drop its formula and value, unregister it from the
+    /// cells it depended on, and recalculate its dependents (which now read
+    /// it as the sheet's `default_value`, or skip it with `skip_absent`).
+    ///
+    /// The entry is removed from `cells` entirely unless other cells still
+    /// reference it; in that case a blank placeholder keeps the `dependents`
+    /// links so later edits to this cell still propagate. A note on the cell
+    /// also survives clearing. Undoable when the `undo_state` feature is on.
+    pub fn clear_cell(&mut self, row: i32, col: i32, status_msg: &mut String) {
+        status_msg.clear();
+        status_msg.push_str("Ok");
+        let (old_deps, keep_cell) = match self.cells.get(&(row, col)) {
+            Some(cell) => (
+                cell.dependencies.clone(),
+                !cell.dependents.is_empty() || cell.note.is_some(),
+            ),
+            None => return, // already empty
+        };
+
+        #[cfg(feature = "undo_state")]
+        {
+            let captured_prev_state = self.capture_current_cell_state(row, col);
+            self.record_undo(captured_prev_state);
+        }
+
+        for dep in &old_deps {
+            if let Some(dep_cell) = self.cells.get_mut(dep) {
+                dep_cell.dependents.remove(&(row, col));
+            }
+        }
+        if keep_cell {
+            let cell = self.get_or_create_cell(row, col);
+            cell.value = CellValue::Empty;
+            cell.status = CellStatus::Ok;
+            cell.formula_idx = None;
+            cell.dependencies.clear();
+        } else {
+            self.cells.remove(&(row, col));
+        }
+        self.dirty_cells.remove(&(row, col));
+        self.stamp_modified(row, col);
+
+        crate::parser::invalidate_cache_for_cell(row, col);
+        mark_cell_and_dependents_dirty(self, row, col);
+        if self.header_row == Some(row) {
+            self.refresh_header_users();
+        }
+        recalc_affected(self, status_msg);
+    }
+
+    /// Capture `(row,col)` for a later `paste_cell`. Plain values are copied
+    /// as their literal; an empty cell copies as an empty formula.
+    pub fn copy_cell(&self, row: i32, col: i32) -> CopiedFormula {
+        let formula = match self.get_formula(row, col) {
+            Some(f) => f,
+            None => match self.get_cell_typed(row, col) {
+                CellValue::Int(v) => v.to_string(),
+                CellValue::Text(t) => format!("\"{}\"", t),
+                _ => String::new(),
+            },
+        };
+        CopiedFormula {
+            formula,
+            origin: (row, col),
+        }
+    }
+
+    /// Paste `copied` into `(row,col)`, moving every relative cell reference
+    /// by the offset from the copied cell (`A1+B1` copied from C1 becomes
+    /// `A2+B2` in C2). Pasting an empty copy clears the cell.
+    ///
+    /// Fails with "Cell out of bounds" if the target or a shifted reference
+    /// falls off the grid.
+    pub fn paste_cell(
+        &mut self,
+        row: i32,
+        col: i32,
+        copied: &CopiedFormula,
+        status_msg: &mut String,
+    ) {
+        if !self.is_in_bounds(row, col) {
+            status_msg.clear();
+            status_msg.push_str("Cell out of bounds");
+            return;
+        }
+        if copied.formula.is_empty() {
+            self.clear_cell(row, col, status_msg);
+            return;
+        }
+        let (d_row, d_col) = (row - copied.origin.0, col - copied.origin.1);
+        match shift_formula_refs(&copied.formula, d_row, d_col) {
+            Some(formula) => self.update_cell_formula(row, col, &formula, status_msg),
+            None => {
+                status_msg.clear();
+                status_msg.push_str("Cell out of bounds");
+            }
+        }
+    }
+
+    /// Copy the cell at `(start_row,col)` into every row below it down to
+    /// `end_row`, shifting relative references per row as `paste_cell` does.
+    ///
+    /// Returns the number of cells filled. Stops at the first paste that
+    /// fails, leaving its message in `status_msg`.
+    pub fn fill_down(
+        &mut self,
+        start_row: i32,
+        end_row: i32,
+        col: i32,
+        status_msg: &mut String,
+    ) -> usize {
+        status_msg.clear();
+        if start_row > end_row {
+            status_msg.push_str("Invalid range");
+            return 0;
+        }
+        if start_row < 0 || end_row >= self.total_rows || col < 0 || col >= self.total_cols {
+            status_msg.push_str("Cell out of bounds");
+            return 0;
+        }
+        status_msg.push_str("Ok");
+        let copied = self.copy_cell(start_row, col);
+        let mut filled = 0;
+        for row in start_row + 1..=end_row {
+            self.paste_cell(row, col, &copied, status_msg);
+            if status_msg != "Ok" {
+                break;
+            }
+            filled += 1;
+        }
+        filled
+    }
+
+    // --- Apply a captured state (Helper for Undo/Redo) ---
+    /// Restore one `PreviousCellState`, re-wiring dependencies and recalculating.
+    #[cfg(feature = "undo_state")] // <-- Update feature name
+    pub fn apply_state(&mut self, state_to_apply: &PreviousCellState, status_msg: &mut String) {
+        let row = state_to_apply.row;
+        let col = state_to_apply.col;
+
+        // 1. Get current dependencies before overwriting
+        let current_deps = self
+            .cells
+            .get(&(row, col))
+            .map_or(HashSet::new(), |c| c.dependencies.clone());
+
+        // 2. Restore the cell's core properties
+        {
+            let cell = self.get_or_create_cell(row, col);
+            cell.value = state_to_apply.previous_value.clone();
+            cell.status = state_to_apply.previous_status.clone();
+            cell.formula_idx = state_to_apply.previous_formula_idx;
+            cell.dependencies = state_to_apply.previous_dependencies.clone();
+            cell.dependents = state_to_apply.previous_dependents_of_cell.clone();
+        }
+        self.stamp_modified(row, col);
+
+        // 3. Update dependent links based on the change
+        // Remove the current cell from the dependents list of its *current* dependencies
+        for &(dep_row, dep_col) in &current_deps {
+            if let Some(dep_cell) = self.cells.get_mut(&(dep_row, dep_col)) {
+                dep_cell.dependents.remove(&(row, col));
+            }
+        }
+        // Add the current cell back to the dependents list of its *applied* dependencies
+        for &(dep_row, dep_col) in &state_to_apply.previous_dependencies {
+            self.get_or_create_cell(dep_row, dep_col)
+                .dependents
+                .insert((row, col));
+        }
+
+        // 4. Mark dirty and recalculate
+        self.dirty_cells.insert((row, col));
+        mark_cell_and_dependents_dirty(self, row, col);
+        crate::parser::invalidate_cache_for_cell(row, col);
+        if self.header_row == Some(row) {
+            self.refresh_header_users();
+        }
+        recalc_affected(self, status_msg); // Recalculate using passed status_msg
+    }
+    // --- End Apply State Helper ---
+
+    // --- Modify Undo Method for multi-level ---
+    #[cfg(feature = "undo_state")]
+    pub fn undo(&mut self, status_msg: &mut String) {
+        status_msg.clear();
+
+        // Pop from undo_stack if not empty [6, 7]
+        if let Some(state_to_restore) = self.undo_stack.pop_back() {
+            // Capture the current state *before* undoing, for REDO
+            let state_before_undo =
+                self.capture_current_cell_state(state_to_restore.row, state_to_restore.col);
             // Push the captured state onto the redo stack [6, 7]
             self.redo_stack.push(state_before_undo);
             // Note: Redo stack size limit isn't typically enforced strictly,
@@ -617,11 +1565,7 @@ impl Spreadsheet {
             let state_before_redo =
                 self.capture_current_cell_state(state_to_redo.row, state_to_redo.col);
             // Push the captured state back onto the undo stack [6, 7]
-            self.undo_stack.push(state_before_redo);
-            // Enforce history limit on undo stack again after redo
-            if self.undo_stack.len() > MAX_UNDO_LEVELS {
-                self.undo_stack.remove(0);
-            }
+            self.push_undo(state_before_redo);
 
             // Apply the redone state using the helper
             self.apply_state(&state_to_redo, status_msg);
@@ -634,1643 +1578,5663 @@ impl Spreadsheet {
         }
     }
     // --- End Redo Method ---
-}
 
-// Utility: converts cell name (e.g. "A1") to (row, col).
-/// Convert `"A1"` → `(0,0)`, `"AA10"` → `(9,26)`, or `None` if invalid.
-pub fn cell_name_to_coords(name: &str) -> Option<(i32, i32)> {
-    let mut pos = 0;
-    let mut col_val = 0;
-    for ch in name.chars() {
-        if ch.is_alphabetic() {
-            col_val = col_val * 26 + (ch.to_ascii_uppercase() as i32 - 'A' as i32 + 1);
-            pos += 1;
-        } else {
-            break;
+    /// Undo up to `count` edits, one `undo` (and recalculation) at a time.
+    ///
+    /// Each step is mirrored onto the redo stack, so `redo_n` with the same
+    /// count restores the sheet. Reports e.g. `"Undid 3 of 5 (nothing more to undo)"`.
+    #[cfg(feature = "undo_state")]
+    pub fn undo_n(&mut self, count: usize, status_msg: &mut String) {
+        let mut done = 0;
+        while done < count && self.can_undo() {
+            self.undo(status_msg);
+            done += 1;
+        }
+        *status_msg = format!("Undid {} of {}", done, count);
+        if done < count {
+            status_msg.push_str(" (nothing more to undo)");
         }
     }
-    if col_val == 0 {
-        return None;
-    }
-    let col = col_val - 1;
-    let mut row_val = 0;
-    for ch in name[pos..].chars() {
-        if ch.is_digit(10) {
-            row_val = row_val * 10 + (ch as i32 - '0' as i32);
-        } else {
-            return None;
+
+    /// Redo up to `count` undone edits; the counterpart of `undo_n`.
+    #[cfg(feature = "undo_state")]
+    pub fn redo_n(&mut self, count: usize, status_msg: &mut String) {
+        let mut done = 0;
+        while done < count && self.can_redo() {
+            self.redo(status_msg);
+            done += 1;
+        }
+        *status_msg = format!("Redid {} of {}", done, count);
+        if done < count {
+            status_msg.push_str(" (nothing more to redo)");
         }
     }
-    if row_val <= 0 {
-        return None;
+
+    /// True if there is at least one edit that `undo` can revert.
+    #[cfg(feature = "undo_state")]
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty()
     }
-    Some((row_val - 1, col))
-}
-/// Trim whitespace from a `String` in place.
-// Trims a string in place.
-pub fn trim(s: &mut String) {
-    *s = s.trim().to_string();
-}
-/// Check basic formula syntax (numbers, cell refs, built-ins) and
-/// set `status_msg` on failure. Returns `0` if syntactically OK.
-// Validates a formula.
-pub fn valid_formula(sheet: &Spreadsheet, formula: &str, status_msg: &mut String) -> i32 {
-    status_msg.clear();
-    let len = formula.len();
-    if len == 0 {
-        status_msg.push_str("Empty formula");
-        return 1;
+
+    /// True if there is at least one undone edit that `redo` can re-apply.
+    #[cfg(feature = "undo_state")]
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
     }
-    if let Some((row, col)) = cell_name_to_coords(formula) {
-        if row < 0 || row >= sheet.total_rows || col < 0 || col >= sheet.total_cols {
-            status_msg.push_str("Cell reference out of bounds");
-            return 1;
-        }
-        return 0;
+
+    /// Number of edits currently on the undo stack.
+    #[cfg(feature = "undo_state")]
+    pub fn undo_depth(&self) -> usize {
+        self.undo_stack.len()
     }
-    if formula.trim().parse::<i32>().is_ok() {
-        return 0;
+
+    /// Number of undone edits currently on the redo stack.
+    #[cfg(feature = "undo_state")]
+    pub fn redo_depth(&self) -> usize {
+        self.redo_stack.len()
     }
-    // ── NEW ── Advanced formulas
 
-    if formula.starts_with("IF(") && cfg!(feature = "advanced_formulas") {
-        // must have two commas and closing ')'
-        let inner = &formula[3..formula.len().saturating_sub(1)];
-        if inner.split(',').count() != 3 {
-            status_msg.push_str("IF needs 3 args");
-            return 1;
-        }
-        return 0;
+    /// Recompute every formula cell from scratch.
+    ///
+    /// Empties the sheet and range caches, marks every cell with a formula
+    /// dirty and runs `recalc_affected`, so chains settle in dependency
+    /// order. Unlike the `clear_cache` command this recomputes values;
+    /// cycles found on the way are reported in `status_msg`. Returns the
+    /// number of cells recomputed.
+    pub fn recalculate_all(&mut self, status_msg: &mut String) -> usize {
+        status_msg.clear();
+        status_msg.push_str("Ok");
+        self.cache.clear();
+        crate::parser::clear_range_cache();
+        let formula_cells: Vec<(i32, i32)> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.formula_idx.is_some())
+            .map(|(&pos, _)| pos)
+            .collect();
+        self.dirty_cells.extend(formula_cells);
+        recalc_affected(self, status_msg)
     }
-    if formula.starts_with("COUNTIF(") && cfg!(feature = "advanced_formulas") {
-        let inner = &formula[8..formula.len().saturating_sub(1)];
-        if inner.split(',').count() != 2 {
-            status_msg.push_str("COUNTIF needs 2 args");
-            return 1;
-        }
-        return 0;
+
+    /// Queue `(row,col)` for the next `recalc_dirty`, e.g. after changing its
+    /// data behind the sheet's back. Its formula (if any) and everything that
+    /// reads it are recomputed then; cached range results over it are dropped
+    /// now.
+    pub fn mark_dirty(&mut self, row: i32, col: i32) {
+        crate::parser::invalidate_cache_for_cell(row, col);
+        self.dirty_cells.insert((row, col));
     }
-    if formula.starts_with("SUMIF(") && cfg!(feature = "advanced_formulas") {
-        let inner = &formula[6..formula.len().saturating_sub(1)];
-        if inner.split(',').count() != 3 {
-            status_msg.push_str("SUMIF needs 3 args");
-            return 1;
-        }
-        return 0;
+
+    /// Cells queued for recalculation, sorted row-major.
+    pub fn pending_recalc(&self) -> Vec<(i32, i32)> {
+        let mut pending: Vec<(i32, i32)> = self.dirty_cells.iter().copied().collect();
+        pending.sort_unstable();
+        pending
     }
-    if formula.starts_with("ROUND(") && cfg!(feature = "advanced_formulas") {
-        let inner = &formula[6..formula.len().saturating_sub(1)];
-        if inner.split(',').count() != 2 {
-            status_msg.push_str("ROUND needs 2 args");
-            return 1;
-        }
-        return 0;
+
+    /// Recompute the cells queued by `mark_dirty` and their dependents (see
+    /// `recalc_affected`). `status_msg` is "Ok" unless recalculation hit an
+    /// error or a cycle. Returns the number of cells recomputed.
+    pub fn recalc_dirty(&mut self, status_msg: &mut String) -> usize {
+        status_msg.clear();
+        status_msg.push_str("Ok");
+        recalc_affected(self, status_msg)
     }
 
-    if formula.starts_with("MAX(")
-        || formula.starts_with("MIN(")
-        || formula.starts_with("SUM(")
-        || formula.starts_with("AVG(")
-        || formula.starts_with("STDEV(")
-    {
-        let pos = formula.find('(').unwrap_or(0);
-        if pos == 0 || formula.chars().nth(pos) != Some('(') {
-            status_msg.push_str("Missing '(' after function name");
-            return 1;
+    /// Name `range_str` (e.g. `B2:B13`) so range functions accept the name in
+    /// its place, as in `SUM(Revenue)`. A name starts with a letter, holds only
+    /// letters, digits and `_`, and must not read as a cell reference or be a
+    /// function name.
+    ///
+    /// Redefining a name rewires and recomputes the formulas that use it; if
+    /// that would make one of them depend on itself the old range is kept.
+    /// `status_msg` is "Ok" on success.
+    pub fn define_name(&mut self, name: &str, range_str: &str, status_msg: &mut String) {
+        status_msg.clear();
+        let mut chars = name.chars();
+        let well_formed = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !well_formed
+            || cell_name_to_coords(name).is_some()
+            || crate::parser::is_function_name(name)
+        {
+            status_msg.push_str("Invalid name");
+            return;
         }
-        if !formula.ends_with(')') {
-            status_msg.push_str("Missing closing parenthesis");
-            return 1;
-        }
-        let inner = &formula[pos + 1..formula.len() - 1];
-        let mut inner = inner.trim().to_string();
-        if let Some(colon) = inner.find(':') {
-            inner.replace_range(colon..colon + 1, ":");
-            let cell1 = inner[..colon].to_string();
-            let cell2 = inner[colon + 1..].to_string();
-            let mut cell1 = cell1.trim().to_string();
-            let mut cell2 = cell2.trim().to_string();
-            if cell_name_to_coords(&cell1).is_none() {
-                status_msg.push_str("Invalid first cell reference");
-                return 1;
-            }
-            if cell_name_to_coords(&cell2).is_none() {
-                status_msg.push_str("Invalid second cell reference");
-                return 1;
-            }
-            let (row1, col1) = cell_name_to_coords(&cell1).unwrap();
-            let (row2, col2) = cell_name_to_coords(&cell2).unwrap();
-            if row1 < 0 || row1 >= sheet.total_rows || col1 < 0 || col1 >= sheet.total_cols {
-                status_msg.push_str("First cell reference out of bounds");
-                return 1;
-            }
-            if row2 < 0 || row2 >= sheet.total_rows || col2 < 0 || col2 >= sheet.total_cols {
-                status_msg.push_str("Second cell reference out of bounds");
-                return 1;
+        let bounds = match range_bounds(range_str.trim(), self.total_rows, self.total_cols) {
+            Some(bounds) => bounds,
+            None => {
+                status_msg.push_str("Invalid range");
+                return;
             }
-            if row1 > row2 || col1 > col2 {
-                status_msg.push_str("Invalid range order");
-                return 1;
-            }
-            return 0;
-        } else {
-            status_msg.push_str("Missing colon in range");
-            return 1;
+        };
+        let (start_row, start_col, end_row, end_col) = bounds;
+        if start_row > end_row || start_col > end_col {
+            status_msg.push_str("Invalid range order");
+            return;
         }
-    } else if formula.starts_with("SLEEP(") {
-        if !formula.ends_with(')') {
-            status_msg.push_str("Missing closing parenthesis in SLEEP");
-            return 1;
+        if start_row < 0
+            || end_row >= self.total_rows
+            || start_col < 0
+            || end_col >= self.total_cols
+        {
+            status_msg.push_str("Range out of bounds");
+            return;
         }
-        let inner = &formula[6..formula.len() - 1];
-        let mut inner = inner.trim().to_string();
-        if inner.parse::<i32>().is_ok() {
-            return 0;
-        } else {
-            if cell_name_to_coords(&inner).is_none() {
-                status_msg.push_str("Invalid cell reference in SLEEP");
-                return 1;
-            }
-            let (row, col) = cell_name_to_coords(&inner).unwrap();
-            if row < 0 || row >= sheet.total_rows || col < 0 || col >= sheet.total_cols {
-                status_msg.push_str("Cell reference in up out of bounds");
-                return 1;
-            }
-            return 0;
+
+        // Only formulas already using the name can be affected: an unknown
+        // name is rejected by `update_cell_formula`
+        let users: Vec<(i32, i32)> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| {
+                cell.formula_idx.is_some_and(|idx| {
+                    blank_string_literals(&self.formula_storage[idx])
+                        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .any(|word| word == name)
+                })
+            })
+            .map(|(&pos, _)| pos)
+            .collect();
+
+        let previous = self.named_ranges.insert(name.to_string(), bounds);
+        self.rewire_dependencies(&users);
+        if users
+            .iter()
+            .any(|&(row, col)| has_circular_dependency_by_index(self, row, col))
+        {
+            match previous {
+                Some(old) => self.named_ranges.insert(name.to_string(), old),
+                None => self.named_ranges.remove(name),
+            };
+            self.rewire_dependencies(&users);
+            status_msg.push_str("Circular dependency detected");
+            return;
         }
+
+        // Cached results are keyed by the resolved range, so the old
+        // definition's entries can't be hit again; only the users need redoing
+        status_msg.push_str("Ok");
+        self.dirty_cells.extend(users);
+        recalc_affected(self, status_msg);
     }
-    let mut op_index = -1;
-    let mut i = if formula.starts_with('-') { 1 } else { 0 };
-    let chars: Vec<char> = formula.chars().collect();
-    while i < chars.len() {
-        if chars[i] == '+' || chars[i] == '-' || chars[i] == '*' || chars[i] == '/' {
-            op_index = i as i32;
-            break;
+
+    /// Column names taken from the text cells of `header_row`, each mapped
+    /// to its column. A header only names its column if it would be a valid
+    /// `define_name` name (so not a cell reference or function name); when
+    /// two headers match, the leftmost wins. Empty when no header row is set.
+    /// Inserting or deleting rows moves the header row with its cells;
+    /// deleting it turns header names off.
+    ///
+    /// Formulas use a header as a range over the rows below it, so with
+    /// `Price` over column C, `SUM(Price)` adds up C2 to the last row. Names
+    /// from `define_name` take precedence. Editing a header cell rewires the
+    /// formulas that use header names; one whose name no longer resolves
+    /// becomes an error until a header provides it again.
+    pub fn header_columns(&self) -> HashMap<String, i32> {
+        let mut columns = HashMap::new();
+        for col in (0..self.total_cols).rev() {
+            if let Some(name) = self.header_name(col) {
+                columns.insert(name.to_string(), col);
+            }
         }
-        i += 1;
+        columns
     }
-    if op_index == -1 {
-        status_msg.push_str("Operator not found");
-        return 1;
-    }
-    let left = formula[..op_index as usize].trim();
-    let right = formula[op_index as usize + 1..].trim();
-    let is_left_int = left.parse::<i32>().is_ok();
-    let is_right_int = right.parse::<i32>().is_ok();
-    let left_is_cell = cell_name_to_coords(left).is_some();
-    let right_is_cell = cell_name_to_coords(right).is_some();
-    if (is_left_int || left_is_cell) && (is_right_int || right_is_cell) {
-        return 0;
+
+    /// Move the header row (`None` turns header names off), rewiring the
+    /// formulas that use header names.
+    pub fn set_header_row(&mut self, row: Option<i32>, status_msg: &mut String) {
+        status_msg.clear();
+        status_msg.push_str("Ok");
+        self.header_row = row;
+        self.refresh_header_users();
+        recalc_affected(self, status_msg);
     }
-    status_msg.push_str("Invalid formula format");
-    1
-}
-/// Scan a formula and return every `(row,col)` it mentions, expanding ranges.
-// Optimized: Extract dependencies from a formula using HashSet
-pub fn extract_dependencies(sheet: &Spreadsheet, formula: &str) -> HashSet<(i32, i32)> {
-    let mut deps: HashSet<(i32, i32)> = HashSet::new();
-    let mut p = formula;
 
-    while !p.is_empty() {
-        while let Some(ch) = p.chars().next() {
-            if ch.is_alphabetic() {
-                break;
-            }
-            p = &p[ch.len_utf8()..];
+    /// Bounds `name` stands for in a formula: a `define_name` range, else the
+    /// column below a header of that name (see `header_columns`).
+    pub fn resolve_name(&self, name: &str) -> Option<(i32, i32, i32, i32)> {
+        if let Some(&bounds) = self.named_ranges.get(name) {
+            return Some(bounds);
         }
-        if p.is_empty() {
-            break;
+        let header_row = self.header_row?;
+        if header_row + 1 >= self.total_rows {
+            return None;
         }
+        let col = (0..self.total_cols).find(|&col| self.header_name(col) == Some(name))?;
+        Some((header_row + 1, col, self.total_rows - 1, col))
+    }
 
-        let start = p;
-        while let Some(ch) = p.chars().next() {
-            if ch.is_alphabetic() {
-                p = &p[ch.len_utf8()..];
-            } else {
-                break;
+    /// The header text of `col`, if it can name the column.
+    fn header_name(&self, col: i32) -> Option<&str> {
+        let text = self.cells.get(&(self.header_row?, col))?.value.as_text()?;
+        let mut chars = text.chars();
+        let well_formed = chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        let usable = well_formed
+            && cell_name_to_coords(text).is_none()
+            && !crate::parser::is_function_name(text);
+        usable.then_some(text)
+    }
+
+    /// Rewire every formula that uses a bare name after the header row or one
+    /// of its cells changed, queueing them for recalculation. A formula whose
+    /// name no longer resolves, or would now read itself, is set to an error
+    /// and unhooked from the cells it read.
+    fn refresh_header_users(&mut self) {
+        let users: Vec<(i32, i32)> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| {
+                cell.formula_idx
+                    .is_some_and(|idx| !bare_names(&self.formula_storage[idx]).is_empty())
+            })
+            .map(|(&pos, _)| pos)
+            .collect();
+        self.rewire_dependencies(&users);
+        for (row, col) in users {
+            let Some(idx) = self
+                .cells
+                .get(&(row, col))
+                .and_then(|cell| cell.formula_idx)
+            else {
+                continue;
+            };
+            let resolves = bare_names(&self.formula_storage[idx])
+                .iter()
+                .all(|name| self.resolve_name(name).is_some());
+            if resolves && !has_circular_dependency_by_index(self, row, col) {
+                self.dirty_cells.insert((row, col));
+                continue;
             }
-        }
-        while let Some(ch) = p.chars().next() {
-            if ch.is_digit(10) {
-                p = &p[ch.len_utf8()..];
-            } else {
-                break;
+            let cell = self.get_or_create_cell(row, col);
+            let old_deps = std::mem::take(&mut cell.dependencies);
+            cell.status = CellStatus::Error;
+            cell.value = CellValue::Int(0);
+            for dep in old_deps {
+                if let Some(dep_cell) = self.cells.get_mut(&dep) {
+                    dep_cell.dependents.remove(&(row, col));
+                }
+            }
+            self.dirty_cells.remove(&(row, col));
+            self.stamp_modified(row, col);
+            crate::parser::invalidate_cache_for_cell(row, col);
+            for dependent in self.get_dependents(row, col) {
+                self.dirty_cells.insert(dependent);
             }
         }
+    }
 
-        if p.starts_with(':') {
-            p = &p[1..];
-            let range_start2 = p;
-            while let Some(ch) = p.chars().next() {
-                if ch.is_alphabetic() {
-                    p = &p[ch.len_utf8()..];
-                } else {
-                    break;
-                }
+    /// `formula` with every defined name outside string literals replaced by
+    /// its range text, so the reference scanners and `validate_formula` see
+    /// the cells it covers. A word followed by `(` is a function call and is
+    /// left alone.
+    fn expand_named_ranges(&self, formula: &str) -> String {
+        if self.named_ranges.is_empty() && self.header_row.is_none() {
+            return formula.to_string();
+        }
+        let mut expanded = String::with_capacity(formula.len());
+        let mut in_quotes = false;
+        let mut rest = formula;
+        while let Some(ch) = rest.chars().next() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            if len == 0 || in_quotes {
+                in_quotes ^= ch == '"';
+                expanded.push(ch);
+                rest = &rest[ch.len_utf8()..];
+                continue;
             }
-            while let Some(ch) = p.chars().next() {
-                if ch.is_digit(10) {
-                    p = &p[ch.len_utf8()..];
-                } else {
-                    break;
+            let word = &rest[..len];
+            let is_call = rest[len..].trim_start().starts_with('(');
+            match self.resolve_name(word).filter(|_| !is_call) {
+                Some((start_row, start_col, end_row, end_col)) => {
+                    expanded.push_str(&coords_to_cell_name(start_row, start_col));
+                    expanded.push(':');
+                    expanded.push_str(&coords_to_cell_name(end_row, end_col));
                 }
+                None => expanded.push_str(word),
             }
+            rest = &rest[len..];
+        }
+        expanded
+    }
 
-            let len1 = start.find(':').unwrap_or(0);
-            let cell_ref1 = &start[..len1];
-            let cell_ref2 = &range_start2[..(range_start2.len() - p.len())];
-
-            if let (Some((r1, c1)), Some((r2, c2))) = (
-                cell_name_to_coords(cell_ref1),
-                cell_name_to_coords(cell_ref2),
-            ) {
-                let (start_row, end_row) = if r1 <= r2 { (r1, r2) } else { (r2, r1) };
-                let (start_col, end_col) = if c1 <= c2 { (c1, c2) } else { (c2, c1) };
+    /// Cells `formula` reads, looking through named ranges; `"B2"` inside a
+    /// string literal is text, not a reference.
+    fn formula_dependencies(&self, formula: &str) -> HashSet<(i32, i32)> {
+        let unquoted = self.expand_named_ranges(&blank_sheet_refs(&blank_string_literals(formula)));
+        extract_dependencies_without_self(&unquoted, self.total_rows, self.total_cols)
+    }
 
-                for rr in start_row..=end_row {
-                    for cc in start_col..=end_col {
-                        deps.insert((rr, cc));
-                    }
+    /// Every `Name!A1` in `formula` must name a sheet lent by a `Workbook`
+    /// and a cell inside it: "Unknown sheet" or "Range out of bounds"
+    /// otherwise.
+    fn check_sheet_refs(&self, formula: &str) -> Result<(), &'static str> {
+        let unquoted = blank_string_literals(formula);
+        let mut p = unquoted.as_str();
+        while !p.is_empty() {
+            let Some(len) = sheet_ref_len(p) else {
+                let word = p.bytes().take_while(u8::is_ascii_alphanumeric).count();
+                p = &p[word.max(p.chars().next().map_or(1, char::len_utf8))..];
+                continue;
+            };
+            let (name, cell) = p[..len].split_once('!').unwrap_or_default();
+            let sibling = CloneableSheet::new(self)
+                .sibling(name)
+                .ok_or("Unknown sheet")?;
+            if let Some((row, col)) = cell_name_to_coords(&cell.to_ascii_uppercase()) {
+                if sibling.get_cell(row, col).is_none() {
+                    return Err("Range out of bounds");
                 }
             }
-        } else {
-            let len = start.len() - p.len();
-            let cell_ref = &start[..len.min(19)];
+            p = &p[len..];
+        }
+        Ok(())
+    }
 
-            if let Some((r, c)) = cell_name_to_coords(cell_ref) {
-                deps.insert((r, c));
+    /// Recompute the dependency edges of the given formula cells from their
+    /// current formulas.
+    fn rewire_dependencies(&mut self, cells: &[(i32, i32)]) {
+        for &pos in cells {
+            let Some(idx) = self.cells.get(&pos).and_then(|cell| cell.formula_idx) else {
+                continue;
+            };
+            let deps = self.formula_dependencies(&self.formula_storage[idx]);
+            let old_deps = std::mem::take(&mut self.get_or_create_cell(pos.0, pos.1).dependencies);
+            for dep in old_deps {
+                if let Some(dep_cell) = self.cells.get_mut(&dep) {
+                    dep_cell.dependents.remove(&pos);
+                }
             }
+            for &dep in &deps {
+                self.get_or_create_cell(dep.0, dep.1).dependents.insert(pos);
+            }
+            self.get_or_create_cell(pos.0, pos.1).dependencies = deps;
         }
     }
 
-    deps
-}
+    /// Formula cells that can't be put in dependency order, sorted row-major.
+    ///
+    /// Runs the same in-degree (Kahn) pass as `recalc_affected`, but over every
+    /// formula cell and without evaluating anything. The cells left with a
+    /// positive in-degree are those on a cycle plus any formula downstream of
+    /// one; an empty result means the sheet is acyclic. Edges are taken from
+    /// `dependencies`; a `dependents` entry without its matching dependency
+    /// is ignored.
+    pub fn cells_in_cycle(&self) -> Vec<(i32, i32)> {
+        let is_formula =
+            |pos: &(i32, i32)| self.cells.get(pos).is_some_and(|c| c.formula_idx.is_some());
+        let mut in_degree: HashMap<(i32, i32), usize> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.formula_idx.is_some())
+            .map(|(&pos, cell)| {
+                (
+                    pos,
+                    cell.dependencies.iter().filter(|d| is_formula(d)).count(),
+                )
+            })
+            .collect();
+
+        let mut ready: Vec<(i32, i32)> = in_degree
+            .iter()
+            .filter(|&(_, &degree)| degree == 0)
+            .map(|(&pos, _)| pos)
+            .collect();
+        while let Some(pos) = ready.pop() {
+            for dependent in &self.cells[&pos].dependents {
+                if !self.cells[dependent].dependencies.contains(&pos) {
+                    continue; // stale edge, not counted in `in_degree`
+                }
+                if let Some(degree) = in_degree.get_mut(dependent) {
+                    *degree -= 1;
+                    if *degree == 0 {
+                        ready.push(*dependent);
+                    }
+                }
+            }
+        }
 
-// Detects circular dependency using DFS with HashSets
-pub fn has_circular_dependency(sheet: &Spreadsheet, row: i32, col: i32) -> bool {
-    let mut visited = HashSet::new();
-    let mut stack = vec![(row, col)];
+        let mut stuck: Vec<(i32, i32)> = in_degree
+            .into_iter()
+            .filter(|&(_, degree)| degree > 0)
+            .map(|(pos, _)| pos)
+            .collect();
+        stuck.sort_unstable();
+        stuck
+    }
 
-    while let Some((r, c)) = stack.pop() {
-        visited.insert((r, c));
+    /// Overwrite every literal cell whose value equals `find` with `replace`,
+    /// then recalculate dependents in a single batch. Returns the number of
+    /// cells changed.
+    ///
+    /// A literal cell is one whose formula is a plain integer (e.g. `A1=5`) or
+    /// a value set directly via `update_cell_value`. Formula cells are left
+    /// alone, as are blank cells, so `find = 0` only matches cells that were
+    /// explicitly given `0`.
+    pub fn replace_value(&mut self, find: i32, replace: i32, status_msg: &mut String) -> usize {
+        status_msg.clear();
+        status_msg.push_str("Ok");
 
-        if let Some(cell) = sheet.cells.get(&(r, c)) {
-            for &(dep_row, dep_col) in &cell.dependencies {
-                if dep_row == row && dep_col == col {
-                    return true;
+        let mut targets: Vec<((i32, i32), bool)> = self
+            .cells
+            .iter()
+            .filter(|(_, cell)| cell.status == CellStatus::Ok && cell.number() == find)
+            .filter_map(|(&pos, cell)| match cell.formula_idx {
+                Some(idx) if self.formula_storage[idx].trim().parse::<i32>().is_ok() => {
+                    Some((pos, true))
                 }
+                Some(_) => None,
+                None if !is_blank(cell) => Some((pos, false)),
+                None => None,
+            })
+            .collect();
+        targets.sort();
+        if targets.is_empty() {
+            return 0;
+        }
 
-                if !visited.contains(&(dep_row, dep_col)) {
-                    stack.push((dep_row, dep_col));
-                }
+        let replace_idx = self.store_formula(&replace.to_string());
+        for &((row, col), has_formula) in &targets {
+            self.update_cell_value(row, col, replace, CellStatus::Ok);
+            if has_formula {
+                self.get_or_create_cell(row, col).formula_idx = Some(replace_idx);
             }
+            crate::parser::invalidate_cache_for_cell(row, col);
+            mark_cell_and_dependents_dirty(self, row, col);
         }
+        recalc_affected(self, status_msg);
+        targets.len()
     }
 
-    false
-}
-/// Convert `(row,col)` → `"A1"`, `"AA10"`, zero-based inputs.
-// Converts (row, col) to cell name.
-pub fn coords_to_cell_name(row: i32, col: i32) -> String {
-    let mut n = col + 1;
-    let mut col_str = String::new();
-    while n > 0 {
-        let rem = (n - 1) % 26;
-        col_str.push((b'A' + rem as u8) as char);
-        n = (n - 1) / 26;
-    }
-    let col_name: String = col_str.chars().rev().collect();
-    format!("{}{}", col_name, row + 1)
-}
-/// Perform a topological batch-based recalculation of all `dirty_cells`,
-/// updating values, statuses, and `status_msg` on the first error encountered.
-// Optimized: Recalculate affected cells using topological sort with batching
-pub fn recalc_affected(sheet: &mut Spreadsheet, status_msg: &mut String) {
-    if sheet.dirty_cells.is_empty() {
-        return;
-    }
-
-    // Improved dependency tracking for recalculation
-    let dirty_cells = sheet.dirty_cells.clone();
-    sheet.dirty_cells.clear(); // Clear before recalculation to allow for new dirty cells
+    /// Change the grid dimensions to `new_rows × new_cols`.
+    ///
+    /// Growing extends the grid; formulas over whole columns or rows (`A:A`,
+    /// `1:1`) or header names then cover the new cells too, and are
+    /// recalculated. Shrinking drops every cell outside the new bounds and
+    /// scrubs the removed coordinates from the remaining cells'
+    /// `dependencies`/`dependents`. Formulas that referenced a removed
+    /// cell (and everything downstream of them) become `CellStatus::Error`,
+    /// and stay that way until re-entered. The viewport and the CLI cursor
+    /// are re-clamped (the cursor is unset if the grid becomes empty).
+    pub fn resize(&mut self, new_rows: i32, new_cols: i32) {
+        let new_rows = new_rows.max(0);
+        let new_cols = new_cols.max(0);
+        let grew = new_rows > self.total_rows || new_cols > self.total_cols;
+        self.total_rows = new_rows;
+        self.total_cols = new_cols;
+        let in_grid = |&(r, c): &(i32, i32)| r >= 0 && r < new_rows && c >= 0 && c < new_cols;
+
+        let removed: Vec<(i32, i32)> = self.cells.keys().filter(|p| !in_grid(p)).cloned().collect();
+        let mut broken = HashSet::new();
+        for pos in &removed {
+            if let Some(cell) = self.cells.remove(pos) {
+                broken.extend(cell.dependents.into_iter().filter(|d| in_grid(d)));
+            }
+        }
+        if !removed.is_empty() {
+            for cell in self.cells.values_mut() {
+                cell.dependencies.retain(|d| in_grid(d));
+                cell.dependents.retain(|d| in_grid(d));
+            }
+            self.dirty_cells.retain(|d| in_grid(d));
+            self.modified_revs.retain(|d, _| in_grid(d));
+            crate::parser::clear_range_cache();
+            // Undo entries may point at cells that no longer exist
+            #[cfg(feature = "undo_state")]
+            {
+                self.undo_stack.clear();
+                self.redo_stack.clear();
+            }
+        }
+        let mut broken: Vec<(i32, i32)> = broken.into_iter().collect();
+        broken.sort();
+        for (row, col) in broken {
+            mark_cell_and_dependents_as_error(self, row, col);
+        }
 
-    // For large dependency chains, we'll use a more efficient approach
-    let mut dependencies_map: HashMap<(i32, i32), HashSet<(i32, i32)>> = HashMap::new();
-    let mut in_degree: HashMap<(i32, i32), usize> = HashMap::new();
-    let mut to_process = HashSet::new();
+        if grew {
+            // `A:A`, `1:1` and header names now reach the new cells
+            let mut formulas: Vec<(i32, i32)> = self
+                .cells
+                .iter()
+                .filter(|(_, cell)| cell.formula_idx.is_some())
+                .map(|(&pos, _)| pos)
+                .collect();
+            formulas.sort();
+            let before: Vec<HashSet<(i32, i32)>> = formulas
+                .iter()
+                .map(|pos| self.cells[pos].dependencies.clone())
+                .collect();
+            self.rewire_dependencies(&formulas);
+            crate::parser::clear_range_cache();
+            for (pos, deps) in formulas.into_iter().zip(before) {
+                if self.cells[&pos].dependencies != deps {
+                    self.mark_dirty(pos.0, pos.1);
+                }
+            }
+            self.recalc_dirty(&mut String::new());
+        }
 
-    // Build the dependency graph more efficiently
-    for &(row, col) in &dirty_cells {
-        build_dependency_graph(sheet, row, col, &mut dependencies_map, &mut to_process);
+        self.top_row = self.top_row.min(new_rows - self.viewport_rows).max(0);
+        self.left_col = self.left_col.min(new_cols - self.viewport_cols).max(0);
+        self.cursor = self
+            .cursor
+            .filter(|_| new_rows > 0 && new_cols > 0)
+            .map(|(r, c)| (r.min(new_rows - 1), c.min(new_cols - 1)));
     }
 
-    // Calculate in-degree for each cell (how many cells it depends on)
-    for &node in &to_process {
-        in_degree.entry(node).or_insert(0);
+    /// Insert an empty row before row index `at` (`at == total_rows`
+    /// appends). Cells at or below `at` move down one row, and every formula
+    /// is rewritten to follow them; a range spanning `at` grows by one row.
+    pub fn insert_row(&mut self, at: i32) {
+        if at < 0 || at > self.total_rows {
+            return;
+        }
+        let shift = |r: i32| if r >= at { r + 1 } else { r };
+        self.remap_rows(
+            self.total_rows + 1,
+            |r| Some(shift(r)),
+            |lo, hi| Some((shift(lo), shift(hi))),
+        );
     }
 
-    for (&cell, deps) in &dependencies_map {
-        for &dep in deps {
-            if to_process.contains(&dep) {
-                *in_degree.entry(dep).or_insert(0) += 1;
-            }
+    /// Delete row index `at`, moving the cells below it up one row and
+    /// rewriting formulas to match; a range spanning `at` shrinks by one row.
+    ///
+    /// A formula that referred to a cell in the deleted row (or to a range
+    /// lying entirely inside it) can't be rewritten: the cell loses its
+    /// formula and becomes a `#REF` error (`CellStatus::Error`), which then
+    /// propagates to its dependents.
+    ///
+    /// Does nothing if `at` is out of range or is the sheet's only row.
+    pub fn delete_row(&mut self, at: i32) {
+        if at < 0 || at >= self.total_rows || self.total_rows == 1 {
+            return;
         }
+        self.remap_rows(
+            self.total_rows - 1,
+            |r| match r.cmp(&at) {
+                std::cmp::Ordering::Less => Some(r),
+                std::cmp::Ordering::Equal => None,
+                std::cmp::Ordering::Greater => Some(r - 1),
+            },
+            |lo, hi| {
+                let lo = if lo > at { lo - 1 } else { lo };
+                let hi = if hi >= at { hi - 1 } else { hi };
+                if lo > hi {
+                    None
+                } else {
+                    Some((lo, hi))
+                }
+            },
+        );
     }
 
-    // Process in batches for better performance on large chains
-    let mut ready_cells: Vec<(i32, i32)> = in_degree
-        .iter()
-        .filter(|&(_, &degree)| degree == 0)
-        .map(|(&cell, _)| cell)
-        .collect();
+    /// Shared body of `insert_row`/`delete_row`: move every cell to
+    /// `row_of(row)` (dropping it on `None`) along with its display format,
+    /// rewrite formulas and named
+    /// ranges through `span` (see `remap_formula_refs`), move the header row,
+    /// rebuild the dependency graph and recalculate. A name whose rows are
+    /// all gone is dropped (as are header names when the header row is
+    /// deleted), and formulas using it become `#REF` errors.
+    fn remap_rows(
+        &mut self,
+        new_total_rows: i32,
+        row_of: impl Fn(i32) -> Option<i32>,
+        span: impl Fn(i32, i32) -> Option<(i32, i32)>,
+    ) {
+        let old_formulas = std::mem::take(&mut self.formula_storage);
+        self.formula_asts.clear();
+        self.total_rows = new_total_rows;
+        self.header_row = self.header_row.and_then(&row_of);
+        self.named_ranges = std::mem::take(&mut self.named_ranges)
+            .into_iter()
+            .filter_map(|(name, (start_row, start_col, end_row, end_col))| {
+                let (start_row, end_row) = span(start_row, end_row)?;
+                Some((name, (start_row, start_col, end_row, end_col)))
+            })
+            .collect();
+        self.cell_format = std::mem::take(&mut self.cell_format)
+            .into_iter()
+            .filter_map(|((r, c), format)| Some(((row_of(r)?, c), format)))
+            .collect();
+
+        let old_cells = std::mem::take(&mut self.cells);
+        let mut touched: HashSet<(i32, i32)> = old_cells.keys().copied().collect();
+        let mut formulas = Vec::new();
+        for ((r, c), mut cell) in old_cells {
+            let Some(r) = row_of(r) else { continue };
+            cell.dependencies.clear();
+            cell.dependents.clear();
+            if let Some(idx) = cell.formula_idx.take() {
+                match remap_formula_refs(&old_formulas[idx], 0, &span) {
+                    Some(formula) => formulas.push(((r, c), formula)),
+                    None => {
+                        // #REF: the formula pointed into the deleted row
+                        cell.status = CellStatus::Error;
+                        cell.value = CellValue::Int(0);
+                    }
+                }
+            }
+            self.cells.insert((r, c), cell);
+        }
 
-    const BATCH_SIZE: usize = 256; // Process cells in batches for better cache locality
+        for ((r, c), formula) in formulas {
+            if !bare_names(&formula)
+                .iter()
+                .all(|name| self.resolve_name(name).is_some())
+            {
+                // #REF: the formula used a name or header whose rows were deleted
+                let cell = self.get_or_create_cell(r, c);
+                cell.status = CellStatus::Error;
+                cell.value = CellValue::Int(0);
+                continue;
+            }
+            let idx = self.store_formula(&formula);
+            let deps = self.formula_dependencies(&formula);
+            for &dep in &deps {
+                self.get_or_create_cell(dep.0, dep.1)
+                    .dependents
+                    .insert((r, c));
+            }
+            let cell = self.get_or_create_cell(r, c);
+            cell.formula_idx = Some(idx);
+            cell.dependencies = deps;
+        }
 
-    while !ready_cells.is_empty() {
-        let batch_end = ready_cells.len().min(BATCH_SIZE);
-        let batch = ready_cells.drain(..batch_end).collect::<Vec<_>>();
-
-        // Process this batch
-        for (row, col) in batch {
-            if let Some(formula) = sheet.get_formula(row, col) {
-                let mut error_flag = 0;
-                let mut s_msg = String::new();
-
-                // Create a temporary clone to avoid borrowing issues
-                let sheet_clone = CloneableSheet::new(sheet);
-                let new_val = crate::parser::evaluate_formula(
-                    &sheet_clone,
-                    &formula,
-                    row,
-                    col,
-                    &mut error_flag,
-                    &mut s_msg,
-                );
-
-                let cell = sheet.get_or_create_cell(row, col);
-                if error_flag == 3 {
-                    cell.status = CellStatus::Error;
-                    cell.value = 0;
-                } else if error_flag != 0 {
-                    status_msg.clear();
-                    if error_flag == 2 {
-                        status_msg.push_str("Invalid range");
-                    } else {
-                        status_msg.push_str("Error in formula");
-                    }
-                    return;
-                } else {
-                    #[cfg(feature = "cell_history")]
-                    {
-                        if cell.value != new_val {
-                            if cell.history.len() == 10 {
-                                cell.history.pop_front(); // Remove the oldest value
-                            }
-                            cell.history.push_back(cell.value); // Store the *current* value before overwriting
-                        }
-                    }
-                    cell.value = new_val;
-                    cell.status = CellStatus::Ok;
-                }
-            }
-
-            // Update dependents of this cell and their in-degree
-            if let Some(dependents) = dependencies_map.get(&(row, col)) {
-                for &dep in dependents {
-                    if let Some(deg) = in_degree.get_mut(&dep) {
-                        *deg -= 1;
-                        if *deg == 0 {
-                            ready_cells.push(dep);
-                        }
-                    }
-                }
-            }
+        self.modified_revs = std::mem::take(&mut self.modified_revs)
+            .into_iter()
+            .filter_map(|((r, c), rev)| row_of(r).map(|r| ((r, c), rev)))
+            .collect();
+        self.dirty_cells.clear();
+        self.in_degree.clear();
+        self.cursor = self
+            .cursor
+            .map(|(r, c)| (r.min(new_total_rows - 1).max(0), c));
+        self.top_row = self.top_row.min(new_total_rows - self.viewport_rows).max(0);
+        // Undo entries hold pre-shift coordinates and formula indices
+        #[cfg(feature = "undo_state")]
+        {
+            self.undo_stack.clear();
+            self.redo_stack.clear();
+        }
+        let mut status_msg = String::new();
+        self.recalculate_all(&mut status_msg);
+        // Both the vacated and the newly occupied positions changed
+        touched.extend(self.cells.keys().copied());
+        touched.retain(|&(r, _)| r < new_total_rows);
+        let mut touched: Vec<(i32, i32)> = touched.into_iter().collect();
+        touched.sort();
+        for (r, c) in touched {
+            self.stamp_modified(r, c);
         }
     }
 
-    // Check for cycles (any remaining cells with non-zero in-degree)
-    let cells_with_cycles: Vec<(i32, i32)> = in_degree
-        .iter()
-        .filter(|&(_, &degree)| degree > 0)
-        .map(|(&cell, _)| cell)
-        .collect();
-
-    // Mark any cells with cycles as errors
-    for (row, col) in cells_with_cycles {
-        let cell = sheet.get_or_create_cell(row, col);
-        cell.status = CellStatus::Error;
-        cell.value = 0;
+    /// Bump the sheet revision and record it as the last-modified
+    /// revision of `(row,col)`.
+    fn stamp_modified(&mut self, row: i32, col: i32) {
+        self.revision += 1;
+        self.modified_revs.insert((row, col), self.revision);
     }
-}
 
-// More efficient dependency graph building for large chains
-pub fn build_dependency_graph(
-    sheet: &Spreadsheet,
-    row: i32,
-    col: i32,
-    dependencies_map: &mut HashMap<(i32, i32), HashSet<(i32, i32)>>,
-    to_process: &mut HashSet<(i32, i32)>,
-) {
-    let mut queue = VecDeque::new();
-    queue.push_back((row, col));
-    to_process.insert((row, col));
+    /// Cells whose value or status changed after revision `rev`, in row-major order.
+    ///
+    /// Pass a previously observed `sheet.revision` to get everything that
+    /// changed since then (including cells updated by recalculation).
+    pub fn modified_since(&self, rev: u64) -> Vec<(i32, i32)> {
+        let mut changed: Vec<(i32, i32)> = self
+            .modified_revs
+            .iter()
+            .filter(|&(_, &r)| r > rev)
+            .map(|(&pos, _)| pos)
+            .collect();
+        changed.sort();
+        changed
+    }
 
-    while let Some((r, c)) = queue.pop_front() {
-        // Get dependents (cells that depend on this cell)
-        if let Some(cell) = sheet.cells.get(&(r, c)) {
-            let dependents = &cell.dependents;
+    /// Cells whose evaluated contents differ between `self` (left) and
+    /// `other` (right), in row-major order.
+    ///
+    /// A cell with no formula, no text and a plain 0 counts as absent, so a
+    /// stored-but-empty cell matches one that was never set. Present cells
+    /// are compared on value, status and text; error and text cells report
+    /// their (0) value. Cells outside one sheet's grid are absent from it.
+    pub fn diff(&self, other: &Spreadsheet) -> Vec<CellDiff> {
+        fn contents(sheet: &Spreadsheet, pos: (i32, i32)) -> Option<&Cell> {
+            sheet.cells.get(&pos).filter(|cell| {
+                cell.formula_idx.is_some()
+                    || !matches!(cell.value, CellValue::Empty | CellValue::Int(0))
+                    || cell.status != CellStatus::Ok
+            })
+        }
 
-            for &(dep_row, dep_col) in dependents {
-                // Add this dependency to the map
-                dependencies_map
-                    .entry((r, c))
-                    .or_insert_with(HashSet::new)
-                    .insert((dep_row, dep_col));
+        let mut positions: Vec<(i32, i32)> = self
+            .cells
+            .keys()
+            .chain(other.cells.keys())
+            .copied()
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+            .into_iter()
+            .filter_map(|pos| {
+                let (left, right) = (contents(self, pos), contents(other, pos));
+                let same = match (left, right) {
+                    (Some(l), Some(r)) => {
+                        l.number() == r.number()
+                            && l.status == r.status
+                            && l.value.as_text() == r.value.as_text()
+                    }
+                    (l, r) => l.is_none() && r.is_none(),
+                };
+                (!same).then(|| CellDiff {
+                    row: pos.0,
+                    col: pos.1,
+                    left: left.map(Cell::number),
+                    right: right.map(Cell::number),
+                })
+            })
+            .collect()
+    }
 
-                // If this dependent hasn't been processed yet, add it to the queue
-                if !to_process.contains(&(dep_row, dep_col)) {
-                    to_process.insert((dep_row, dep_col));
-                    queue.push_back((dep_row, dep_col));
+    /// Render one sheet row as a delimited line (no trailing newline).
+    ///
+    /// Missing cells are written as empty fields, error cells as the
+    /// delimiter's error marker.
+    fn delimited_row(&self, row: i32, delimiter: Delimiter) -> String {
+        let mut fields = Vec::with_capacity(self.total_cols as usize);
+        for col in 0..self.total_cols {
+            fields.push(match self.cells.get(&(row, col)) {
+                None => String::new(),
+                Some(cell) if cell.status == CellStatus::Error => {
+                    delimiter.error_marker().to_string()
                 }
-            }
+                Some(Cell {
+                    value: CellValue::Text(text),
+                    ..
+                }) => delimiter.escape(text),
+                Some(cell) => cell.number().to_string(),
+            });
         }
+        fields.join(&delimiter.separator().to_string())
     }
-}
-
-// Extract dependencies without borrowing the sheet - optimized for large formulas
-pub fn extract_dependencies_without_self(
-    formula: &str,
-    total_rows: i32,
-    total_cols: i32,
-) -> HashSet<(i32, i32)> {
-    // For large range expressions, use a more space-efficient implementation
-    // if formula.len() > 100 && formula.contains(':') {
-    //     return extract_range_dependencies_optimized(formula, total_rows, total_cols);
-    // }
-
-    // Original implementation for smaller formulas
-    let mut deps: HashSet<(i32, i32)> = HashSet::new();
-    let mut p = formula;
 
-    while !p.is_empty() {
-        while let Some(ch) = p.chars().next() {
-            if ch.is_alphabetic() {
-                break;
-            }
-            p = &p[ch.len_utf8()..];
-        }
-        if p.is_empty() {
-            break;
+    /// The evaluated grid as tab-separated values with `\r\n` line endings,
+    /// ready to paste into Excel or Google Sheets. Error cells are `#ERR`,
+    /// cells that were never set are empty.
+    pub fn to_tsv(&self) -> String {
+        let mut out = String::new();
+        for row in 0..self.total_rows {
+            out.push_str(&self.delimited_row(row, Delimiter::Tab));
+            out.push_str("\r\n");
         }
+        out
+    }
 
-        let start = p;
-        while let Some(ch) = p.chars().next() {
-            if ch.is_alphabetic() {
-                p = &p[ch.len_utf8()..];
-            } else {
-                break;
-            }
-        }
-        while let Some(ch) = p.chars().next() {
-            if ch.is_digit(10) {
-                p = &p[ch.len_utf8()..];
-            } else {
-                break;
-            }
+    /// Save the evaluated sheet as CSV: rows `0..total_rows`, columns
+    /// `0..total_cols`, one sheet row per line.
+    ///
+    /// Each field is the cell's value (or text), `ERR` for `CellStatus::Error`,
+    /// or empty for a cell that was never set (so empties don't turn into `0`).
+    pub fn save_to_csv(&self, path: &Path) -> io::Result<()> {
+        let mut out = String::new();
+        for row in 0..self.total_rows {
+            out.push_str(&self.delimited_row(row, Delimiter::Comma));
+            out.push('\n');
         }
+        fs::write(path, out)
+    }
 
-        if p.starts_with(':') {
-            p = &p[1..];
-            let range_start2 = p;
-            while let Some(ch) = p.chars().next() {
-                if ch.is_alphabetic() {
-                    p = &p[ch.len_utf8()..];
-                } else {
-                    break;
+    /// Build a sheet from a CSV file, sized to the number of lines and the
+    /// widest line seen (short lines are padded with empty cells).
+    ///
+    /// Integer fields become plain values, fields starting with `=` are
+    /// entered as formulas once all values are in place, and empty fields stay
+    /// empty. Anything else (including `ERR` from `save_to_csv`, or a formula
+    /// that fails to apply) is stored as a `CellStatus::Error` cell rather
+    /// than aborting the load. Undo history starts empty.
+    pub fn load_from_csv(path: &Path) -> io::Result<Box<Spreadsheet>> {
+        let text = fs::read_to_string(path)?;
+        let grid: Vec<Vec<String>> = text.lines().map(csv_split).collect();
+        let rows = grid.len() as i32;
+        let cols = grid.iter().map(Vec::len).max().unwrap_or(0) as i32;
+        let mut sheet = Spreadsheet::new(rows, cols);
+
+        let mut formulas = Vec::new();
+        for (r, fields) in grid.iter().enumerate() {
+            for (c, field) in fields.iter().enumerate() {
+                let (row, col) = (r as i32, c as i32);
+                let field = field.trim();
+                if field.is_empty() {
+                    continue;
                 }
-            }
-            while let Some(ch) = p.chars().next() {
-                if ch.is_digit(10) {
-                    p = &p[ch.len_utf8()..];
+                if let Some(formula) = field.strip_prefix('=') {
+                    formulas.push((row, col, formula.trim().to_string()));
+                } else if let Ok(v) = field.parse::<i32>() {
+                    sheet.update_cell_value(row, col, v, CellStatus::Ok);
                 } else {
-                    break;
+                    sheet.update_cell_value(row, col, 0, CellStatus::Error);
                 }
             }
+        }
 
-            let len1 = start.find(':').unwrap_or(0);
-            let cell_ref1 = &start[..len1];
-            let cell_ref2 = &range_start2[..(range_start2.len() - p.len())];
-
-            if let (Some((r1, c1)), Some((r2, c2))) = (
-                cell_name_to_coords(cell_ref1),
-                cell_name_to_coords(cell_ref2),
-            ) {
-                if r1 >= 0
-                    && r1 < total_rows
-                    && c1 >= 0
-                    && c1 < total_cols
-                    && r2 >= 0
-                    && r2 < total_rows
-                    && c2 >= 0
-                    && c2 < total_cols
-                {
-                    let (start_row, end_row) = if r1 <= r2 { (r1, r2) } else { (r2, r1) };
-                    let (start_col, end_col) = if c1 <= c2 { (c1, c2) } else { (c2, c1) };
-
-                    for rr in start_row..=end_row {
-                        for cc in start_col..=end_col {
-                            deps.insert((rr, cc));
-                        }
-                    }
-                }
+        let mut status_msg = String::new();
+        for (row, col, formula) in formulas {
+            sheet.update_cell_formula(row, col, &formula, &mut status_msg);
+            if status_msg != "Ok" {
+                sheet.update_cell_value(row, col, 0, CellStatus::Error);
             }
-        } else {
-            let len = start.len() - p.len();
-            let cell_ref = &start[..len.min(19)];
+        }
 
-            if let Some((r, c)) = cell_name_to_coords(cell_ref) {
-                if r >= 0 && r < total_rows && c >= 0 && c < total_cols {
-                    deps.insert((r, c));
-                }
-            }
+        #[cfg(feature = "undo_state")]
+        {
+            sheet.undo_stack.clear();
+            sheet.redo_stack.clear();
         }
+        Ok(sheet)
     }
 
-    deps
-}
+    /// Snapshot the sheet as a `SheetDto`, in row-major order.
+    ///
+    /// Cells still in their default state (no formula, value 0, `Ok`) are
+    /// skipped so the output stays as sparse as the sheet.
+    #[cfg(feature = "serde")]
+    pub fn to_dto(&self) -> SheetDto {
+        let cells: Vec<CellDto> = self
+            .iter_cells_ordered()
+            .filter(|(_, _, cell)| {
+                cell.formula_idx.is_some()
+                    || cell.number() != 0
+                    || cell.status != CellStatus::Ok
+                    || cell.note.is_some()
+            })
+            .map(|(row, col, cell)| CellDto {
+                row,
+                col,
+                formula: cell
+                    .formula_idx
+                    .map(|idx| self.formula_storage[idx].clone()),
+                value: cell.number(),
+                status: cell.status.clone(),
+                note: cell.note.clone(),
+            })
+            .collect();
+        SheetDto {
+            rows: self.total_rows,
+            cols: self.total_cols,
+            cells,
+        }
+    }
 
-// // Optimized extraction for large ranges
-// pub fn extract_range_dependencies_optimized(formula: &str, total_rows: i32, total_cols: i32) -> HashSet<(i32, i32)> {
-//     let mut deps = HashSet::new();
+    /// Serialize the sheet to JSON, e.g.
+    /// `{"rows":2,"cols":2,"cells":[{"row":0,"col":0,"formula":"1+1","value":2,"status":"ok"}]}`.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.to_dto()).expect("SheetDto is always serializable")
+    }
 
-//     // Fast path for range functions
-//     if let Some(open_paren) = formula.find('(') {
-//         if let Some(close_paren) = formula.find(')') {
-//             let range_part = &formula[open_paren+1..close_paren];
-//             if let Some(colon) = range_part.find(':') {
-//                 let cell1 = range_part[..colon].trim();
-//                 let cell2 = range_part[colon+1..].trim();
+    /// Rebuild a sheet from a `SheetDto`.
+    ///
+    /// Plain values are restored first; formulas are then re-entered through
+    /// `update_cell_formula`, so their values and statuses are recomputed
+    /// rather than trusted from the input. Undo history starts empty.
+    #[cfg(feature = "serde")]
+    pub fn from_dto(dto: &SheetDto) -> Box<Spreadsheet> {
+        let mut sheet = Spreadsheet::new(dto.rows, dto.cols);
+        for cell in dto.cells.iter().filter(|c| c.formula.is_none()) {
+            sheet.update_cell_value(cell.row, cell.col, cell.value, cell.status.clone());
+        }
+        let mut status_msg = String::new();
+        for cell in &dto.cells {
+            if let Some(formula) = &cell.formula {
+                sheet.update_cell_formula(cell.row, cell.col, formula, &mut status_msg);
+            }
+            if let Some(note) = &cell.note {
+                sheet.set_note(cell.row, cell.col, note);
+            }
+        }
+        #[cfg(feature = "undo_state")]
+        {
+            sheet.undo_stack.clear();
+            sheet.redo_stack.clear();
+        }
+        sheet
+    }
 
-//                 if let (Some((r1, c1)), Some((r2, c2))) =
-//                     (cell_name_to_coords(cell1), cell_name_to_coords(cell2)) {
+    /// Parse JSON produced by `to_json` back into a sheet.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<Box<Spreadsheet>, serde_json::Error> {
+        let dto: SheetDto = serde_json::from_str(json)?;
+        Ok(Spreadsheet::from_dto(&dto))
+    }
 
-//                     if r1 >= 0 && r1 < total_rows && c1 >= 0 && c1 < total_cols &&
-//                        r2 >= 0 && r2 < total_rows && c2 >= 0 && c2 < total_cols {
+    /// Write every cell's raw formula text (as shown in a formula bar) instead
+    /// of its computed value, one sheet row per line. Empty cells and cells
+    /// without a formula export as empty fields.
+    ///
+    /// Formulas containing commas or quotes (e.g. `SUMIF(A1:A3,">2",B1:B3)`)
+    /// are quoted CSV-style.
+    pub fn export_formulas_csv<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = String::new();
+        for row in 0..self.total_rows {
+            let fields: Vec<String> = (0..self.total_cols)
+                .map(|col| csv_escape(&self.get_cell_raw_content(row, col)))
+                .collect();
+            out.push_str(&fields.join(","));
+            out.push('\n');
+        }
+        fs::write(path, out)
+    }
 
-//                         let (start_row, end_row) = if r1 <= r2 { (r1, r2) } else { (r2, r1) };
-//                         let (start_col, end_col) = if c1 <= c2 { (c1, c2) } else { (c2, c1) };
+    /// Incrementally sync a CSV export: only rows containing a cell modified
+    /// after revision `rev` are re-rendered; every other line of the existing
+    /// file is kept byte-for-byte. Returns the number of rows written.
+    ///
+    /// File format: no header, line `i` holds sheet row `i`. If the file is
+    /// missing or shorter than the sheet, the missing rows are rendered in
+    /// full, so the first sync can simply pass `rev = 0`. The file is still
+    /// replaced as a whole on disk, since CSV lines have no fixed width to
+    /// patch in place.
+    pub fn export_csv_changed_since<P: AsRef<Path>>(&self, path: P, rev: u64) -> io::Result<usize> {
+        let path = path.as_ref();
+        let mut lines: Vec<String> = match fs::read_to_string(path) {
+            Ok(text) => text.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        lines.truncate(self.total_rows.max(0) as usize);
 
-//                         // For very large ranges, don't materialize all cell references
-//                         // if (end_row - start_row + 1) * (end_col - start_col + 1) > 999_999_999 {
-//                         //     // Just record the range boundaries to save memory
-//                         //     deps.insert((start_row, start_col));
-//                         //     deps.insert((start_row, end_col));
-//                         //     deps.insert((end_row, start_col));
-//                         //     deps.insert((end_row, end_col));
-//                         //     // Add some sample cells from the middle
-//                         //     deps.insert(((start_row + end_row) / 2, (start_col + end_col) / 2));
-//                         //     return deps;
-//                         // }
+        let mut written = 0;
+        while (lines.len() as i32) < self.total_rows {
+            lines.push(self.delimited_row(lines.len() as i32, Delimiter::Comma));
+            written += 1;
+        }
+        let mut rows: Vec<i32> = self
+            .modified_since(rev)
+            .into_iter()
+            .map(|(row, _)| row)
+            .filter(|&row| row >= 0 && row < self.total_rows)
+            .collect();
+        rows.dedup();
+        for &row in &rows {
+            let idx = row as usize;
+            let line = self.delimited_row(row, Delimiter::Comma);
+            if lines[idx] != line {
+                lines[idx] = line;
+                written += 1;
+            }
+        }
 
-//                         // For smaller ranges, add all cells
-//                         for r in start_row..=end_row {
-//                             for c in start_col..=end_col {
-//                                 deps.insert((r, c));
-//                             }
-//                         }
-//                         return deps;
-//                     }
-//                 }
-//             }
-//         }
-//     }
+        let mut out = lines.join("\n");
+        out.push('\n');
+        fs::write(path, out)?;
+        Ok(written)
+    }
+}
 
-//     // Fall back to standard extraction for other formulas
-//     extract_dependencies_without_self(formula, total_rows, total_cols)
-// }
-/// DFS from `(row,col)`, marking that cell and all downstream dependents
-/// as `Error` with `value = 0`.
-// Marks a cell and its dependents as error
-pub fn mark_cell_and_dependents_as_error(sheet: &mut Spreadsheet, row: i32, col: i32) {
-    let mut stack = vec![(row, col)];
-    let mut visited = HashSet::new();
+/// Replace the contents of every `"..."` literal with spaces (keeping the
+/// quotes), so reference scanners skip quoted text.
+fn blank_string_literals(formula: &str) -> String {
+    let mut in_quotes = false;
+    formula
+        .chars()
+        .map(|ch| {
+            if ch == '"' {
+                in_quotes = !in_quotes;
+                ch
+            } else if in_quotes {
+                ' '
+            } else {
+                ch
+            }
+        })
+        .collect()
+}
 
-    while let Some((r, c)) = stack.pop() {
-        if !visited.insert((r, c)) {
-            continue;
-        }
+/// Field separator for the evaluated-grid writers (`save_to_csv`, `to_tsv`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delimiter {
+    Comma,
+    Tab,
+}
 
-        let cell = sheet.get_or_create_cell(r, c);
-        if cell.status == CellStatus::Error {
-            continue;
+impl Delimiter {
+    pub fn separator(self) -> char {
+        match self {
+            Delimiter::Comma => ',',
+            Delimiter::Tab => '\t',
         }
+    }
 
-        cell.status = CellStatus::Error;
-        cell.value = 0;
+    /// What an error cell is written as: `ERR` in CSV (read back by
+    /// `load_from_csv`), `#ERR` in TSV, which spreadsheet apps show as-is.
+    pub fn error_marker(self) -> &'static str {
+        match self {
+            Delimiter::Comma => "ERR",
+            Delimiter::Tab => "#ERR",
+        }
+    }
 
-        let dependents = cell.dependents.clone();
-        for &(dep_row, dep_col) in &dependents {
-            stack.push((dep_row, dep_col));
+    /// Quote a field if it contains the separator, a quote or a line break,
+    /// doubling any embedded quotes.
+    fn escape(self, field: &str) -> String {
+        let sep = self.separator();
+        if field.contains(|ch| ch == sep || ch == '"' || ch == '\n' || ch == '\r') {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
         }
     }
 }
 
-// Create a cloneable wrapper to avoid borrowing issues
-/// A read-only wrapper for parser/evaluator that only exposes `get_cell`,
-/// `total_rows`, and `total_cols`.
-#[derive(Clone)]
-pub struct CloneableSheet<'a> {
-    sheet: &'a Spreadsheet,
+/// Quote a CSV field if it contains a comma, quote or line break,
+/// doubling any embedded quotes.
+fn csv_escape(field: &str) -> String {
+    Delimiter::Comma.escape(field)
 }
 
-impl<'a> CloneableSheet<'a> {
-    pub fn new(sheet: &'a Spreadsheet) -> Self {
-        Self { sheet }
-    }
-
-    pub fn get_cell(&self, row: i32, col: i32) -> Option<CellView> {
-        if row >= 0 && row < self.sheet.total_rows && col >= 0 && col < self.sheet.total_cols {
-            if let Some(cell) = self.sheet.cells.get(&(row, col)) {
-                return Some(CellView {
-                    value: cell.value,
-                    status: cell.status.clone(),
-                });
+/// Split one CSV line into fields, honouring `"…"` quoting and `""` escapes.
+fn csv_split(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
             }
-            // Return default cell for non-existent cells
-            return Some(CellView {
-                value: 0,
-                status: CellStatus::Ok,
-            });
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(ch),
         }
-        None
     }
+    fields.push(field);
+    fields
+}
 
-    pub fn total_rows(&self) -> i32 {
-        self.sheet.total_rows
+/// Why a cell name such as `"A1"` could not be converted to coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoordError {
+    /// No column letters before the row (e.g. `"12"` or `""`).
+    MissingColumn,
+    /// No row number after the letters, or row `0` (e.g. `"A"`, `"B0"`).
+    MissingRow,
+    /// A character that is neither a column letter nor a row digit (e.g. `'$'` in `"A$1"`).
+    BadCharacter(char),
+    /// The column or row number does not fit in an `i32`.
+    Overflow,
+}
+
+impl std::fmt::Display for CoordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoordError::MissingColumn => write!(f, "missing column letters"),
+            CoordError::MissingRow => write!(f, "missing row number"),
+            CoordError::BadCharacter(ch) => write!(f, "unexpected character '{}'", ch),
+            CoordError::Overflow => write!(f, "cell reference too large"),
+        }
     }
+}
 
-    pub fn total_cols(&self) -> i32 {
-        self.sheet.total_cols
+impl std::error::Error for CoordError {}
+
+/// Convert `"A1"` → `(0,0)`, `"AA10"` → `(9,26)`, or a [`CoordError`] saying
+/// why the name is invalid.
+pub fn cell_name_to_coords_checked(name: &str) -> Result<(i32, i32), CoordError> {
+    let mut pos = 0;
+    let mut col_val: i32 = 0;
+    for ch in name.chars() {
+        if ch.is_alphabetic() {
+            // long letter runs (e.g. function names like PERCENTILE) must not overflow
+            col_val = col_val
+                .checked_mul(26)
+                .and_then(|v| v.checked_add(ch.to_ascii_uppercase() as i32 - 'A' as i32 + 1))
+                .ok_or(CoordError::Overflow)?;
+            pos += ch.len_utf8();
+        } else {
+            break;
+        }
+    }
+    if col_val == 0 {
+        return Err(CoordError::MissingColumn);
+    }
+    let col = col_val - 1;
+    let mut row_val: i32 = 0;
+    for ch in name[pos..].chars() {
+        if let Some(d) = ch.to_digit(10) {
+            row_val = row_val
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(d as i32))
+                .ok_or(CoordError::Overflow)?;
+        } else {
+            return Err(CoordError::BadCharacter(ch));
+        }
     }
+    if row_val <= 0 {
+        return Err(CoordError::MissingRow);
+    }
+    Ok((row_val - 1, col))
 }
 
-// Light-weight view of cell data for read-only operations
-pub struct CellView {
-    pub value: i32,
-    pub status: CellStatus,
+// Utility: converts cell name (e.g. "A1") to (row, col).
+/// Convert `"A1"` → `(0,0)`, `"AA10"` → `(9,26)`, or `None` if invalid.
+///
+/// Use [`cell_name_to_coords_checked`] to find out why a name was rejected.
+pub fn cell_name_to_coords(name: &str) -> Option<(i32, i32)> {
+    cell_name_to_coords_checked(name).ok()
+}
+/// Resolve a range reference into `(start_row, start_col, end_row, end_col)`.
+///
+/// Besides `A1:B3`, accepts whole-column ranges (`A:A`, `A:C`), which span
+/// rows `0..total_rows`, and whole-row ranges (`1:1`, `2:5`), which span
+/// columns `0..total_cols`. Both ends must be the same kind of reference.
+/// Order and bounds are left to the caller; returns `None` if malformed.
+pub fn range_bounds(range: &str, total_rows: i32, total_cols: i32) -> Option<(i32, i32, i32, i32)> {
+    let colon = range.find(':')?;
+    let a = range[..colon].trim();
+    let b = range[colon + 1..].trim();
+    let is_col = |s: &str| !s.is_empty() && s.chars().all(|ch| ch.is_ascii_alphabetic());
+    let is_row = |s: &str| !s.is_empty() && s.chars().all(|ch| ch.is_ascii_digit());
+
+    if is_col(a) && is_col(b) {
+        // reuse the letter decoding of cell_name_to_coords by pinning row 1
+        let (_, c1) = cell_name_to_coords(&format!("{}1", a))?;
+        let (_, c2) = cell_name_to_coords(&format!("{}1", b))?;
+        Some((0, c1, total_rows - 1, c2))
+    } else if is_row(a) && is_row(b) {
+        let r1 = a.parse::<i32>().ok().filter(|&r| r > 0)?;
+        let r2 = b.parse::<i32>().ok().filter(|&r| r > 0)?;
+        Some((r1 - 1, 0, r2 - 1, total_cols - 1))
+    } else {
+        let (r1, c1) = cell_name_to_coords(a)?;
+        let (r2, c2) = cell_name_to_coords(b)?;
+        Some((r1, c1, r2, c2))
+    }
 }
 
-// Detects circular dependency using DFS with indexes to avoid borrowing issues
-pub fn has_circular_dependency_by_index(sheet: &Spreadsheet, row: i32, col: i32) -> bool {
-    let mut visited = HashSet::new();
-    let mut stack = vec![(row, col)];
+/// Length of a cross-sheet reference such as `Sheet2!A1` at the start of
+/// `p`, if any.
+fn sheet_ref_len(p: &str) -> Option<usize> {
+    let word = |s: &str| s.bytes().take_while(u8::is_ascii_alphanumeric).count();
+    let name = word(p);
+    if name == 0 || !p[name..].starts_with('!') {
+        return None;
+    }
+    let cell = word(&p[name + 1..]);
+    (cell > 0).then_some(name + 1 + cell)
+}
 
-    while let Some((r, c)) = stack.pop() {
-        if !visited.insert((r, c)) {
-            continue;
-        }
+/// Replace cross-sheet references (`Sheet2!A1`) with spaces: they name
+/// cells of another `Workbook` sheet, not of this one.
+fn blank_sheet_refs(formula: &str) -> String {
+    let mut out = String::with_capacity(formula.len());
+    let mut p = formula;
+    while let Some(ch) = p.chars().next() {
+        let len = match sheet_ref_len(p) {
+            Some(len) => {
+                out.extend(std::iter::repeat(' ').take(len));
+                len
+            }
+            None if ch.is_ascii_alphanumeric() => {
+                let len = p.bytes().take_while(u8::is_ascii_alphanumeric).count();
+                out.push_str(&p[..len]);
+                len
+            }
+            None => {
+                out.push(ch);
+                ch.len_utf8()
+            }
+        };
+        p = &p[len..];
+    }
+    out
+}
 
-        // Get dependencies for the current cell
-        if let Some(cell) = sheet.cells.get(&(r, c)) {
-            // Check for circular dependency
-            for &(dep_row, dep_col) in &cell.dependencies {
-                if dep_row == row && dep_col == col {
-                    return true;
-                }
+/// Names of the sheets `formula` reads through `Name!A1` references, as
+/// written.
+fn referenced_sheets(formula: &str) -> Vec<String> {
+    let unquoted = blank_string_literals(formula);
+    let mut sheets = Vec::new();
+    let mut p = unquoted.as_str();
+    while let Some(ch) = p.chars().next() {
+        let len = match sheet_ref_len(p) {
+            Some(len) => {
+                sheets.push(p[..p.find('!').unwrap_or(len)].to_string());
+                len
+            }
+            None if ch.is_ascii_alphanumeric() => {
+                p.bytes().take_while(u8::is_ascii_alphanumeric).count()
+            }
+            None => ch.len_utf8(),
+        };
+        p = &p[len..];
+    }
+    sheets
+}
 
-                if !visited.contains(&(dep_row, dep_col)) {
-                    stack.push((dep_row, dep_col));
-                }
+/// Rewrite the `sheet!A1` references in `formula` (references into another
+/// `Workbook` sheet, matched case-insensitively) with their rows sent
+/// through `row_of`. `None` if one of them points at a row that is gone.
+fn remap_sheet_refs(
+    formula: &str,
+    sheet: &str,
+    row_of: impl Fn(i32) -> Option<i32>,
+) -> Option<String> {
+    let mut out = String::with_capacity(formula.len());
+    let mut p = formula;
+    while let Some(ch) = p.chars().next() {
+        let len = if ch == '"' {
+            p[1..].find('"').map_or(p.len(), |i| i + 2)
+        } else if let Some(len) = sheet_ref_len(p) {
+            len
+        } else if ch.is_ascii_alphanumeric() {
+            p.bytes().take_while(u8::is_ascii_alphanumeric).count()
+        } else {
+            ch.len_utf8()
+        };
+        let (name, cell) = p[..len].split_once('!').unwrap_or_default();
+        match cell_name_to_coords(&cell.to_ascii_uppercase()) {
+            Some((row, col)) if ch != '"' && name.eq_ignore_ascii_case(sheet) => {
+                out.push_str(name);
+                out.push('!');
+                out.push_str(&coords_to_cell_name(row_of(row)?, col));
             }
+            _ => out.push_str(&p[..len]),
         }
+        p = &p[len..];
     }
-
-    false
+    Some(out)
 }
-/// Mark `(row,col)` and its dependents dirty so they’ll be re-evaluated.
-// More memory-efficient dirty cells handling
-pub fn mark_cell_and_dependents_dirty(sheet: &mut Spreadsheet, row: i32, col: i32) {
-    // For large spreadsheets, avoid excessive memory usage
-    const MAX_DIRTY_CELLS: usize = 1000000;
 
-    // if sheet.dirty_cells.len() > MAX_DIRTY_CELLS {
-    //     // If we have too many dirty cells already, do a partial recalculation now
-    //     let status_msg = &mut String::new();
-    //     recalc_affected(sheet, status_msg);
-    // }
+/// Identifiers in `formula` that aren't cell references, function names,
+/// `A:A` column bounds, cross-sheet references or inside string literals:
+/// the words a defined name or a header has to supply.
+fn bare_names(formula: &str) -> Vec<String> {
+    let unquoted = blank_sheet_refs(&blank_string_literals(formula));
+    let mut names = Vec::new();
+    let mut after_colon = false;
+    let mut p = unquoted.as_str();
+    while let Some(ch) = p.chars().next() {
+        let len = p
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(p.len());
+        if len == 0 {
+            after_colon = ch == ':';
+            p = &p[ch.len_utf8()..];
+            continue;
+        }
+        let (word, rest) = p.split_at(len);
+        let next = rest.trim_start().chars().next();
+        if ch.is_ascii_alphabetic()
+            && !after_colon
+            && !matches!(next, Some('(') | Some(':'))
+            && cell_name_to_coords(word).is_none()
+        {
+            names.push(word.to_string());
+        }
+        after_colon = false;
+        p = rest;
+    }
+    names
+}
 
-    let mut queue = VecDeque::new();
-    let mut visited = HashSet::new();
+/// Length of a whole-row range such as `2:5` at the start of `p`, if any.
+fn row_range_len(p: &str) -> Option<usize> {
+    let d1 = p.bytes().take_while(u8::is_ascii_digit).count();
+    if d1 == 0 || !p[d1..].starts_with(':') {
+        return None;
+    }
+    let d2 = p[d1 + 1..].bytes().take_while(u8::is_ascii_digit).count();
+    if d2 == 0 {
+        None
+    } else {
+        Some(d1 + 1 + d2)
+    }
+}
 
-    // Start with the immediate dependents
-    let dependents = if let Some(cell) = sheet.cells.get(&(row, col)) {
-        cell.dependents.clone()
+/// Trim whitespace from a `String` in place.
+// Trims a string in place.
+pub fn trim(s: &mut String) {
+    *s = s.trim().to_string();
+}
+/// Check basic formula syntax (numbers, cell refs, built-ins) and
+/// set `status_msg` on failure. Returns `0` if syntactically OK.
+///
+/// Superseded by `parser::validate_formula`, which `update_cell_formula`
+/// uses: it runs the evaluator's own parser, so it accepts compound
+/// expressions this check rejects.
+// Validates a formula.
+#[deprecated(note = "use parser::validate_formula")]
+pub fn valid_formula(sheet: &Spreadsheet, formula: &str, status_msg: &mut String) -> i32 {
+    status_msg.clear();
+    let len = formula.len();
+    if len == 0 {
+        status_msg.push_str("Empty formula");
+        return 1;
+    }
+    if let Some((row, col)) = cell_name_to_coords(formula) {
+        if row < 0 || row >= sheet.total_rows || col < 0 || col >= sheet.total_cols {
+            status_msg.push_str("Cell reference out of bounds");
+            return 1;
+        }
+        return 0;
+    }
+    if crate::parser::parse_number_literal(formula).is_some() {
+        return 0;
+    }
+    // Text literal, e.g. "Total"
+    if crate::parser::parse_string_literal(formula).is_some() {
+        return 0;
+    }
+    // Function names are case-insensitive: `sum(A1:B2)` is `SUM(A1:B2)`
+    let name_len = formula
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(len);
+    let normalized;
+    let formula = if name_len > 0 && formula[name_len..].starts_with('(') {
+        normalized = formula[..name_len].to_ascii_uppercase() + &formula[name_len..];
+        normalized.as_str()
     } else {
-        HashSet::new()
+        formula
     };
+    // ── NEW ── Advanced formulas
 
-    for &(dep_row, dep_col) in &dependents {
-        queue.push_back((dep_row, dep_col));
+    if formula.starts_with("CONCAT(") && cfg!(feature = "advanced_formulas") {
+        if !formula.ends_with(')') {
+            status_msg.push_str("Missing closing parenthesis in CONCAT");
+            return 1;
+        }
+        return 0;
     }
 
-    // Process the dependency graph with a limit to avoid stack overflows
-    let mut cells_visited = 0;
-    const MAX_VISIT: usize = 5000;
+    if formula.starts_with("ABS(") || formula.starts_with("SIGN(") {
+        if !formula.ends_with(')') {
+            status_msg.push_str("Missing closing parenthesis");
+            return 1;
+        }
+        let pos = formula.find('(').unwrap_or(0);
+        return valid_formula(
+            sheet,
+            formula[pos + 1..formula.len() - 1].trim(),
+            status_msg,
+        );
+    }
 
-    while let Some((r, c)) = queue.pop_front() {
-        cells_visited += 1;
-        // if cells_visited > MAX_VISIT {
-        //     // Too many cells in the chain, mark them for batch processing later
-        //     sheet.dirty_cells.insert((r, c));
-        //     continue;
-        // }
+    if formula.starts_with("IFERROR(") && cfg!(feature = "advanced_formulas") {
+        if !formula.ends_with(')') {
+            status_msg.push_str("Missing closing parenthesis");
+            return 1;
+        }
+        let parts = crate::parser::split_text_args(&formula[8..formula.len() - 1]);
+        if parts.len() != 2 {
+            status_msg.push_str("IFERROR needs 2 args");
+            return 1;
+        }
+        for part in parts {
+            let err = valid_formula(sheet, part.trim(), status_msg);
+            if err != 0 {
+                return err;
+            }
+        }
+        return 0;
+    }
 
-        if !visited.insert((r, c)) {
-            continue; // Already visited
+    if (formula.starts_with("AND(") || formula.starts_with("OR(") || formula.starts_with("NOT("))
+        && cfg!(feature = "advanced_formulas")
+    {
+        if !formula.ends_with(')') {
+            status_msg.push_str("Missing closing parenthesis");
+            return 1;
+        }
+        let pos = formula.find('(').unwrap_or(0);
+        let parts = crate::parser::split_text_args(&formula[pos + 1..formula.len() - 1]);
+        if formula.starts_with("NOT(") && parts.len() != 1 {
+            status_msg.push_str("NOT needs 1 arg");
+            return 1;
         }
+        if parts.iter().any(|p| p.trim().is_empty()) {
+            status_msg.push_str("Empty argument");
+            return 1;
+        }
+        return 0;
+    }
 
-        // Mark as dirty
-        sheet.dirty_cells.insert((r, c));
+    if formula.starts_with("IF(") && cfg!(feature = "advanced_formulas") {
+        // must have two commas and closing ')'
+        let inner = &formula[3..formula.len().saturating_sub(1)];
+        if crate::parser::split_text_args(inner).len() != 3 {
+            status_msg.push_str("IF needs 3 args");
+            return 1;
+        }
+        return 0;
+    }
+    if formula.starts_with("COUNTIF(") && cfg!(feature = "advanced_formulas") {
+        let inner = &formula[8..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 2 {
+            status_msg.push_str("COUNTIF needs 2 args");
+            return 1;
+        }
+        return 0;
+    }
+    if formula.starts_with("SUMIF(") && cfg!(feature = "advanced_formulas") {
+        let inner = &formula[6..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 3 {
+            status_msg.push_str("SUMIF needs 3 args");
+            return 1;
+        }
+        return 0;
+    }
+    if formula.starts_with("AVERAGEIF(") && cfg!(feature = "advanced_formulas") {
+        let inner = &formula[10..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 2 {
+            status_msg.push_str("AVERAGEIF needs 2 args");
+            return 1;
+        }
+        return 0;
+    }
+    if formula.starts_with("ROUND(") && cfg!(feature = "advanced_formulas") {
+        let inner = &formula[6..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 2 {
+            status_msg.push_str("ROUND needs 2 args");
+            return 1;
+        }
+        return 0;
+    }
+    if formula.starts_with("MOD(") && cfg!(feature = "advanced_formulas") {
+        let inner = &formula[4..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 2 {
+            status_msg.push_str("MOD needs 2 args");
+            return 1;
+        }
+        return 0;
+    }
+    if (formula.starts_with("GCD(") || formula.starts_with("LCM("))
+        && cfg!(feature = "advanced_formulas")
+    {
+        let inner = &formula[4..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 2 {
+            status_msg.push_str(&format!("{} needs 2 args", &formula[..3]));
+            return 1;
+        }
+        return 0;
+    }
+    if formula.starts_with("PERCENTILE(") && cfg!(feature = "advanced_formulas") {
+        let inner = &formula[11..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 2 {
+            status_msg.push_str("PERCENTILE needs 2 args");
+            return 1;
+        }
+        return 0;
+    }
+    if formula.starts_with("SUMPRODUCT(") && cfg!(feature = "advanced_formulas") {
+        let inner = &formula[11..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 2 {
+            status_msg.push_str("SUMPRODUCT needs 2 args");
+            return 1;
+        }
+        return 0;
+    }
+    if formula.starts_with("POWER(") && cfg!(feature = "advanced_formulas") {
+        let inner = &formula[6..formula.len().saturating_sub(1)];
+        if inner.split(',').count() != 2 {
+            status_msg.push_str("POWER needs 2 args");
+            return 1;
+        }
+        return 0;
+    }
+
+    if formula.starts_with("MAX(")
+        || formula.starts_with("MIN(")
+        || formula.starts_with("SUM(")
+        || formula.starts_with("AVG(")
+        || formula.starts_with("STDEV(")
+        || formula.starts_with("STDEVP(")
+        || formula.starts_with("MODE(")
+    {
+        let pos = formula.find('(').unwrap_or(0);
+        if pos == 0 || formula.chars().nth(pos) != Some('(') {
+            status_msg.push_str("Missing '(' after function name");
+            return 1;
+        }
+        if !formula.ends_with(')') {
+            status_msg.push_str("Missing closing parenthesis");
+            return 1;
+        }
+        let inner = &formula[pos + 1..formula.len() - 1];
+        let mut inner = inner.trim().to_string();
+        if sheet.resolve_name(&inner).is_some() {
+            // bounds were checked by `define_name` / `header_columns`
+            return 0;
+        }
+        if let Some(colon) = inner.find(':') {
+            inner.replace_range(colon..colon + 1, ":");
+            let cell1 = inner[..colon].to_string();
+            let cell2 = inner[colon + 1..].to_string();
+            let mut cell1 = cell1.trim().to_string();
+            let mut cell2 = cell2.trim().to_string();
+            // `A:A` / `1:1` whole-column and whole-row ranges resolve here too
+            let (row1, col1, row2, col2) =
+                match range_bounds(&inner, sheet.total_rows, sheet.total_cols) {
+                    Some(bounds) => bounds,
+                    None if cell_name_to_coords(&cell1).is_none() => {
+                        status_msg.push_str("Invalid first cell reference");
+                        return 1;
+                    }
+                    None => {
+                        status_msg.push_str("Invalid second cell reference");
+                        return 1;
+                    }
+                };
+            // same bounds and wording as `evaluate_range_function` (error 4)
+            // and `update_cell_formula`
+            if !sheet.is_in_bounds(row1, col1) || !sheet.is_in_bounds(row2, col2) {
+                status_msg.push_str("Range out of bounds");
+                return 1;
+            }
+            if row1 > row2 || col1 > col2 {
+                status_msg.push_str("Invalid range order");
+                return 1;
+            }
+            return 0;
+        } else {
+            status_msg.push_str("Missing colon in range");
+            return 1;
+        }
+    } else if formula.starts_with("SLEEP(") {
+        if !formula.ends_with(')') {
+            status_msg.push_str("Missing closing parenthesis in SLEEP");
+            return 1;
+        }
+        let inner = &formula[6..formula.len() - 1];
+        let mut inner = inner.trim().to_string();
+        if crate::parser::parse_number_literal(&inner).is_some() {
+            return 0;
+        } else {
+            if cell_name_to_coords(&inner).is_none() {
+                status_msg.push_str("Invalid cell reference in SLEEP");
+                return 1;
+            }
+            let (row, col) = cell_name_to_coords(&inner).unwrap();
+            if row < 0 || row >= sheet.total_rows || col < 0 || col >= sheet.total_cols {
+                status_msg.push_str("Cell reference in up out of bounds");
+                return 1;
+            }
+            return 0;
+        }
+    }
+    let mut op_index = -1;
+    let mut i = if formula.starts_with('-') { 1 } else { 0 };
+    let chars: Vec<char> = formula.chars().collect();
+    while i < chars.len() {
+        // the '+' of an exponent (`2e+3`) is part of the number
+        let exponent_sign = chars[i] == '+'
+            && i >= 2
+            && matches!(chars[i - 1], 'e' | 'E')
+            && chars[i - 2].is_ascii_digit();
+        if !exponent_sign
+            && (chars[i] == '+' || chars[i] == '-' || chars[i] == '*' || chars[i] == '/')
+        {
+            op_index = i as i32;
+            break;
+        }
+        i += 1;
+    }
+    if op_index == -1 {
+        status_msg.push_str("Operator not found");
+        return 1;
+    }
+    let left = formula[..op_index as usize].trim();
+    let right = formula[op_index as usize + 1..].trim();
+    let is_left_int = crate::parser::parse_number_literal(left).is_some();
+    let is_right_int = crate::parser::parse_number_literal(right).is_some();
+    let left_is_cell = cell_name_to_coords(left).is_some();
+    let right_is_cell = cell_name_to_coords(right).is_some();
+    if (is_left_int || left_is_cell) && (is_right_int || right_is_cell) {
+        return 0;
+    }
+    status_msg.push_str("Invalid formula format");
+    1
+}
+/// Scan a formula and return every `(row,col)` it mentions, expanding ranges.
+// Optimized: Extract dependencies from a formula using HashSet
+pub fn extract_dependencies(sheet: &Spreadsheet, formula: &str) -> HashSet<(i32, i32)> {
+    let mut deps: HashSet<(i32, i32)> = HashSet::new();
+    let mut p = formula;
+
+    while !p.is_empty() {
+        while let Some(ch) = p.chars().next() {
+            if ch.is_alphabetic() {
+                break;
+            }
+            // whole-row ranges (`1:3`) contain no letters, so catch them here
+            if let Some(len) = row_range_len(p) {
+                if let Some(bounds) = range_bounds(&p[..len], sheet.total_rows, sheet.total_cols) {
+                    insert_range_deps(&mut deps, bounds);
+                }
+                p = &p[len..];
+                continue;
+            }
+            // so the `e6` of `1e6` isn't read as a cell reference
+            if ch.is_ascii_digit() {
+                p = &p[crate::parser::number_literal_len(p)..];
+                continue;
+            }
+            p = &p[ch.len_utf8()..];
+        }
+        if p.is_empty() {
+            break;
+        }
+
+        let start = p;
+        while let Some(ch) = p.chars().next() {
+            if ch.is_alphabetic() {
+                p = &p[ch.len_utf8()..];
+            } else {
+                break;
+            }
+        }
+        while let Some(ch) = p.chars().next() {
+            if ch.is_digit(10) {
+                p = &p[ch.len_utf8()..];
+            } else {
+                break;
+            }
+        }
+
+        if p.starts_with(':') {
+            p = &p[1..];
+            while let Some(ch) = p.chars().next() {
+                if ch.is_alphabetic() {
+                    p = &p[ch.len_utf8()..];
+                } else {
+                    break;
+                }
+            }
+            while let Some(ch) = p.chars().next() {
+                if ch.is_digit(10) {
+                    p = &p[ch.len_utf8()..];
+                } else {
+                    break;
+                }
+            }
+
+            let range_ref = &start[..start.len() - p.len()];
+
+            if let Some(bounds) = range_bounds(range_ref, sheet.total_rows, sheet.total_cols) {
+                insert_range_deps(&mut deps, bounds);
+            }
+        } else {
+            let len = start.len() - p.len();
+            let cell_ref = &start[..len.min(19)];
+
+            if let Some((r, c)) = cell_name_to_coords(cell_ref) {
+                deps.insert((r, c));
+            }
+        }
+    }
+
+    deps
+}
+
+/// Add every cell of `(r1, c1, r2, c2)` to `deps`, whichever way the corners are given.
+fn insert_range_deps(deps: &mut HashSet<(i32, i32)>, (r1, c1, r2, c2): (i32, i32, i32, i32)) {
+    let (start_row, end_row) = if r1 <= r2 { (r1, r2) } else { (r2, r1) };
+    let (start_col, end_col) = if c1 <= c2 { (c1, c2) } else { (c2, c1) };
+
+    for rr in start_row..=end_row {
+        for cc in start_col..=end_col {
+            deps.insert((rr, cc));
+        }
+    }
+}
+
+/// Rewrite every cell reference in `formula` moved by `(d_row, d_col)`,
+/// including whole-row ranges like `1:3` (rows only) and whole-column
+/// ranges like `A:C` (columns only). Function names, numbers and quoted
+/// text are left alone. `None` if a reference would move above row 1 or
+/// left of column A.
+fn shift_formula_refs(formula: &str, d_row: i32, d_col: i32) -> Option<String> {
+    remap_formula_refs(formula, d_col, |lo, hi| {
+        if lo + d_row < 0 || hi + d_row < 0 {
+            None
+        } else {
+            Some((lo + d_row, hi + d_row))
+        }
+    })
+}
+
+/// Rewrite the references in `formula`: columns move by `d_col`, and the
+/// rows of each reference go through `rows(first, last)` (a single cell is
+/// passed as `first == last`, a range as its two corner rows). `rows`
+/// returns `None` for a reference that no longer exists, which makes the
+/// whole rewrite `None`. Whole-row ranges only go through `rows`, and
+/// whole-column ranges only move by `d_col`.
+fn remap_formula_refs(
+    formula: &str,
+    d_col: i32,
+    rows: impl Fn(i32, i32) -> Option<(i32, i32)>,
+) -> Option<String> {
+    let mut out = String::with_capacity(formula.len());
+    let mut p = formula;
+
+    while let Some(ch) = p.chars().next() {
+        if ch == '"' {
+            let end = p[1..].find('"').map_or(p.len(), |i| i + 2);
+            out.push_str(&p[..end]);
+            p = &p[end..];
+        } else if let Some(len) = sheet_ref_len(p) {
+            // another sheet's cells don't move with this sheet's rows
+            out.push_str(&p[..len]);
+            p = &p[len..];
+        } else if let Some(len) = row_range_len(p) {
+            let (first, last) = p[..len].split_once(':')?;
+            let (first, last) = rows(
+                first.parse::<i32>().ok()? - 1,
+                last.parse::<i32>().ok()? - 1,
+            )?;
+            out.push_str(&format!("{}:{}", first + 1, last + 1));
+            p = &p[len..];
+        } else if let Some((len, (c1, c2))) = lex_col_range(p) {
+            let (c1, c2) = (c1 + d_col, c2 + d_col);
+            if c1 < 0 || c2 < 0 {
+                return None;
+            }
+            let letters = |c: i32| coords_to_cell_name(0, c).trim_end_matches('1').to_string();
+            out.push_str(&format!("{}:{}", letters(c1), letters(c2)));
+            p = &p[len..];
+        } else if let Some((len, (r1, c1))) = lex_cell_ref(p) {
+            let range_end = p[len..]
+                .strip_prefix(':')
+                .and_then(lex_cell_ref)
+                .map(|(len2, end)| (len + 1 + len2, end));
+            let (consumed, (r2, c2)) = range_end.unwrap_or((len, (r1, c1)));
+            let (r1, r2) = rows(r1, r2)?;
+            let (c1, c2) = (c1 + d_col, c2 + d_col);
+            if c1 < 0 || c2 < 0 {
+                return None;
+            }
+            out.push_str(&coords_to_cell_name(r1, c1));
+            if range_end.is_some() {
+                out.push(':');
+                out.push_str(&coords_to_cell_name(r2, c2));
+            }
+            p = &p[consumed..];
+        } else if ch.is_ascii_alphanumeric() {
+            // function names and numbers, copied as whole words
+            let len = p.bytes().take_while(u8::is_ascii_alphanumeric).count();
+            out.push_str(&p[..len]);
+            p = &p[len..];
+        } else {
+            out.push(ch);
+            p = &p[ch.len_utf8()..];
+        }
+    }
+    Some(out)
+}
+
+/// A whole-column range such as `A:C` at the start of `p`: its length and
+/// the two columns.
+fn lex_col_range(p: &str) -> Option<(usize, (i32, i32))> {
+    let letters = |s: &str| s.bytes().take_while(u8::is_ascii_alphabetic).count();
+    let l1 = letters(p);
+    if l1 == 0 || !p[l1..].starts_with(':') {
+        return None;
+    }
+    let rest = &p[l1 + 1..];
+    let l2 = letters(rest);
+    if l2 == 0 || rest[l2..].starts_with(|ch: char| ch.is_ascii_alphanumeric() || ch == '_') {
+        return None;
+    }
+    let column = |s: &str| cell_name_to_coords(&format!("{}1", s)).map(|(_, c)| c);
+    Some((l1 + 1 + l2, (column(&p[..l1])?, column(&rest[..l2])?)))
+}
+
+/// A cell name (letters then digits) at the start of `p`: its length and coordinates.
+fn lex_cell_ref(p: &str) -> Option<(usize, (i32, i32))> {
+    let letters = p.bytes().take_while(u8::is_ascii_alphabetic).count();
+    let digits = p[letters..].bytes().take_while(u8::is_ascii_digit).count();
+    if letters == 0 || digits == 0 {
+        return None;
+    }
+    cell_name_to_coords(&p[..letters + digits]).map(|pos| (letters + digits, pos))
+}
+
+/// True if both corners of `(r1, c1, r2, c2)` lie on the grid.
+fn range_in_bounds(
+    (r1, c1, r2, c2): (i32, i32, i32, i32),
+    total_rows: i32,
+    total_cols: i32,
+) -> bool {
+    r1 >= 0
+        && r1 < total_rows
+        && c1 >= 0
+        && c1 < total_cols
+        && r2 >= 0
+        && r2 < total_rows
+        && c2 >= 0
+        && c2 < total_cols
+}
+
+// Detects circular dependency using DFS with HashSets
+pub fn has_circular_dependency(sheet: &Spreadsheet, row: i32, col: i32) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack = vec![(row, col)];
+
+    while let Some((r, c)) = stack.pop() {
+        visited.insert((r, c));
+
+        if let Some(cell) = sheet.cells.get(&(r, c)) {
+            for &(dep_row, dep_col) in &cell.dependencies {
+                if dep_row == row && dep_col == col {
+                    return true;
+                }
+
+                if !visited.contains(&(dep_row, dep_col)) {
+                    stack.push((dep_row, dep_col));
+                }
+            }
+        }
+    }
+
+    false
+}
+/// Convert `(row,col)` → `"A1"`, `"AA10"`, zero-based inputs.
+// Converts (row, col) to cell name.
+pub fn coords_to_cell_name(row: i32, col: i32) -> String {
+    let mut n = col + 1;
+    let mut col_str = String::new();
+    while n > 0 {
+        let rem = (n - 1) % 26;
+        col_str.push((b'A' + rem as u8) as char);
+        n = (n - 1) / 26;
+    }
+    let col_name: String = col_str.chars().rev().collect();
+    format!("{}{}", col_name, row + 1)
+}
+/// Re-evaluate the formula of `(row,col)` from the current values of the
+/// cells it reads and store the result. Returns the evaluator's error code:
+/// on 3 the cell becomes an `Error` cell; on 1, 2 or 4 it is left as it was.
+fn recompute_cell(sheet: &mut Spreadsheet, row: i32, col: i32) -> i32 {
+    let Some(formula_idx) = sheet.cells.get(&(row, col)).and_then(|c| c.formula_idx) else {
+        return 0;
+    };
+    let mut error_flag = 0;
+    let mut s_msg = String::new();
+
+    // Create a temporary clone to avoid borrowing issues
+    let sheet_clone = CloneableSheet::new(sheet);
+    // Use the cached AST when there is one; text formulas and
+    // anything the AST can't express go through the string parser
+    let new_val = match sheet.formula_asts.get(formula_idx) {
+        Some(Some(ast)) => CellValue::Int(crate::parser::evaluate_ast(
+            &sheet_clone,
+            ast,
+            row,
+            col,
+            &mut error_flag,
+        )),
+        _ => crate::parser::evaluate_formula_value(
+            &sheet_clone,
+            &sheet.formula_storage[formula_idx],
+            row,
+            col,
+            &mut error_flag,
+            &mut s_msg,
+        ),
+    };
+
+    sheet.stamp_modified(row, col);
+    #[cfg(feature = "cell_history")]
+    let history_limit = sheet.history_limit;
+    let cell = sheet.get_or_create_cell(row, col);
+    if error_flag == 3 {
+        cell.status = CellStatus::Error;
+        cell.value = CellValue::Int(0);
+    } else if error_flag == 0 {
+        #[cfg(feature = "cell_history")]
+        {
+            let old_value = cell.number();
+            if old_value != new_val.as_int().unwrap_or(0) {
+                push_history(&mut cell.history, old_value, history_limit);
+            }
+        }
+        cell.value = new_val;
+        cell.status = CellStatus::Ok;
+    }
+    error_flag
+}
+
+/// Perform a topological batch-based recalculation of all `dirty_cells`,
+/// updating values, statuses, and `status_msg` on the first error encountered.
+/// Cells still waiting on a dependency once the queue drains are part of a
+/// cycle; they are set to `Error` and listed in `status_msg`.
+///
+/// Returns how many formula cells were re-evaluated.
+// Optimized: Recalculate affected cells using topological sort with batching
+pub fn recalc_affected(sheet: &mut Spreadsheet, status_msg: &mut String) -> usize {
+    if sheet.dirty_cells.is_empty() {
+        return 0;
+    }
+
+    // Improved dependency tracking for recalculation
+    let dirty_cells = sheet.dirty_cells.clone();
+    sheet.dirty_cells.clear(); // Clear before recalculation to allow for new dirty cells
+
+    // For large dependency chains, we'll use a more efficient approach
+    let mut dependencies_map: HashMap<(i32, i32), HashSet<(i32, i32)>> = HashMap::new();
+    let mut in_degree: HashMap<(i32, i32), usize> = HashMap::new();
+    let mut to_process = HashSet::new();
+
+    // Build the dependency graph more efficiently
+    for &(row, col) in &dirty_cells {
+        build_dependency_graph(sheet, row, col, &mut dependencies_map, &mut to_process);
+    }
+
+    // Calculate in-degree for each cell (how many cells it depends on).
+    // `dependencies_map` is keyed precedent -> dependents, so each edge counts
+    // against the dependent: a cell only becomes ready once every precedent
+    // in this pass has been recomputed (diamonds included).
+    for &node in &to_process {
+        in_degree.entry(node).or_insert(0);
+    }
+
+    for dependents in dependencies_map.values() {
+        for &dependent in dependents {
+            if to_process.contains(&dependent) {
+                *in_degree.entry(dependent).or_insert(0) += 1;
+            }
+        }
+    }
+
+    // Process in batches for better performance on large chains
+    let mut ready_cells: Vec<(i32, i32)> = in_degree
+        .iter()
+        .filter(|&(_, &degree)| degree == 0)
+        .map(|(&cell, _)| cell)
+        .collect();
+
+    const BATCH_SIZE: usize = 256; // Process cells in batches for better cache locality
+    let mut recalculated = 0;
+
+    while !ready_cells.is_empty() {
+        let batch_end = ready_cells.len().min(BATCH_SIZE);
+        let batch = ready_cells.drain(..batch_end).collect::<Vec<_>>();
+
+        // Process this batch
+        for (row, col) in batch {
+            if sheet
+                .cells
+                .get(&(row, col))
+                .is_some_and(|c| c.formula_idx.is_some())
+            {
+                let error_flag = recompute_cell(sheet, row, col);
+                recalculated += 1;
+                if error_flag != 0 && error_flag != 3 {
+                    status_msg.clear();
+                    if error_flag == 2 {
+                        status_msg.push_str("Invalid range");
+                    } else {
+                        status_msg.push_str("Error in formula");
+                    }
+                    return recalculated;
+                }
+            }
+
+            // Update dependents of this cell and their in-degree
+            if let Some(dependents) = dependencies_map.get(&(row, col)) {
+                for &dep in dependents {
+                    if let Some(deg) = in_degree.get_mut(&dep) {
+                        *deg -= 1;
+                        if *deg == 0 {
+                            ready_cells.push(dep);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Check for cycles (any remaining cells with non-zero in-degree)
+    let mut cells_with_cycles: Vec<(i32, i32)> = in_degree
+        .iter()
+        .filter(|&(_, &degree)| degree > 0)
+        .map(|(&cell, _)| cell)
+        .collect();
+    if cells_with_cycles.is_empty() {
+        return recalculated;
+    }
+    cells_with_cycles.sort_unstable();
+
+    // Mark any cells with cycles as errors
+    for &(row, col) in &cells_with_cycles {
+        let cell = sheet.get_or_create_cell(row, col);
+        cell.status = CellStatus::Error;
+        cell.value = CellValue::Int(0);
+        sheet.stamp_modified(row, col);
+    }
+
+    let names: Vec<String> = cells_with_cycles
+        .iter()
+        .map(|&(row, col)| coords_to_cell_name(row, col))
+        .collect();
+    status_msg.clear();
+    status_msg.push_str("Circular dependency detected during recalculation in ");
+    status_msg.push_str(&names.join(", "));
+    recalculated
+}
+
+// More efficient dependency graph building for large chains
+pub fn build_dependency_graph(
+    sheet: &Spreadsheet,
+    row: i32,
+    col: i32,
+    dependencies_map: &mut HashMap<(i32, i32), HashSet<(i32, i32)>>,
+    to_process: &mut HashSet<(i32, i32)>,
+) {
+    let mut queue = VecDeque::new();
+    queue.push_back((row, col));
+    to_process.insert((row, col));
+
+    while let Some((r, c)) = queue.pop_front() {
+        // Get dependents (cells that depend on this cell)
+        if let Some(cell) = sheet.cells.get(&(r, c)) {
+            let dependents = &cell.dependents;
+
+            for &(dep_row, dep_col) in dependents {
+                // Add this dependency to the map
+                dependencies_map
+                    .entry((r, c))
+                    .or_insert_with(HashSet::new)
+                    .insert((dep_row, dep_col));
+
+                // If this dependent hasn't been processed yet, add it to the queue
+                if !to_process.contains(&(dep_row, dep_col)) {
+                    to_process.insert((dep_row, dep_col));
+                    queue.push_back((dep_row, dep_col));
+                }
+            }
+        }
+    }
+}
+
+// Extract dependencies without borrowing the sheet - optimized for large formulas
+pub fn extract_dependencies_without_self(
+    formula: &str,
+    total_rows: i32,
+    total_cols: i32,
+) -> HashSet<(i32, i32)> {
+    // For large range expressions, use a more space-efficient implementation
+    // if formula.len() > 100 && formula.contains(':') {
+    //     return extract_range_dependencies_optimized(formula, total_rows, total_cols);
+    // }
+
+    // Original implementation for smaller formulas
+    let mut deps: HashSet<(i32, i32)> = HashSet::new();
+    let mut p = formula;
+
+    while !p.is_empty() {
+        while let Some(ch) = p.chars().next() {
+            if ch.is_alphabetic() {
+                break;
+            }
+            // whole-row ranges (`1:3`) contain no letters, so catch them here
+            if let Some(len) = row_range_len(p) {
+                if let Some(bounds) = range_bounds(&p[..len], total_rows, total_cols) {
+                    if range_in_bounds(bounds, total_rows, total_cols) {
+                        insert_range_deps(&mut deps, bounds);
+                    }
+                }
+                p = &p[len..];
+                continue;
+            }
+            // so the `e6` of `1e6` isn't read as a cell reference
+            if ch.is_ascii_digit() {
+                p = &p[crate::parser::number_literal_len(p)..];
+                continue;
+            }
+            p = &p[ch.len_utf8()..];
+        }
+        if p.is_empty() {
+            break;
+        }
+
+        let start = p;
+        while let Some(ch) = p.chars().next() {
+            if ch.is_alphabetic() {
+                p = &p[ch.len_utf8()..];
+            } else {
+                break;
+            }
+        }
+        while let Some(ch) = p.chars().next() {
+            if ch.is_digit(10) {
+                p = &p[ch.len_utf8()..];
+            } else {
+                break;
+            }
+        }
+
+        if p.starts_with(':') {
+            p = &p[1..];
+            while let Some(ch) = p.chars().next() {
+                if ch.is_alphabetic() {
+                    p = &p[ch.len_utf8()..];
+                } else {
+                    break;
+                }
+            }
+            while let Some(ch) = p.chars().next() {
+                if ch.is_digit(10) {
+                    p = &p[ch.len_utf8()..];
+                } else {
+                    break;
+                }
+            }
+
+            let range_ref = &start[..start.len() - p.len()];
+
+            if let Some(bounds) = range_bounds(range_ref, total_rows, total_cols) {
+                if range_in_bounds(bounds, total_rows, total_cols) {
+                    insert_range_deps(&mut deps, bounds);
+                }
+            }
+        } else {
+            let len = start.len() - p.len();
+            let cell_ref = &start[..len.min(19)];
+
+            if let Some((r, c)) = cell_name_to_coords(cell_ref) {
+                if r >= 0 && r < total_rows && c >= 0 && c < total_cols {
+                    deps.insert((r, c));
+                }
+            }
+        }
+    }
+
+    deps
+}
+
+// // Optimized extraction for large ranges
+// pub fn extract_range_dependencies_optimized(formula: &str, total_rows: i32, total_cols: i32) -> HashSet<(i32, i32)> {
+//     let mut deps = HashSet::new();
+
+//     // Fast path for range functions
+//     if let Some(open_paren) = formula.find('(') {
+//         if let Some(close_paren) = formula.find(')') {
+//             let range_part = &formula[open_paren+1..close_paren];
+//             if let Some(colon) = range_part.find(':') {
+//                 let cell1 = range_part[..colon].trim();
+//                 let cell2 = range_part[colon+1..].trim();
+
+//                 if let (Some((r1, c1)), Some((r2, c2))) =
+//                     (cell_name_to_coords(cell1), cell_name_to_coords(cell2)) {
+
+//                     if r1 >= 0 && r1 < total_rows && c1 >= 0 && c1 < total_cols &&
+//                        r2 >= 0 && r2 < total_rows && c2 >= 0 && c2 < total_cols {
+
+//                         let (start_row, end_row) = if r1 <= r2 { (r1, r2) } else { (r2, r1) };
+//                         let (start_col, end_col) = if c1 <= c2 { (c1, c2) } else { (c2, c1) };
+
+//                         // For very large ranges, don't materialize all cell references
+//                         // if (end_row - start_row + 1) * (end_col - start_col + 1) > 999_999_999 {
+//                         //     // Just record the range boundaries to save memory
+//                         //     deps.insert((start_row, start_col));
+//                         //     deps.insert((start_row, end_col));
+//                         //     deps.insert((end_row, start_col));
+//                         //     deps.insert((end_row, end_col));
+//                         //     // Add some sample cells from the middle
+//                         //     deps.insert(((start_row + end_row) / 2, (start_col + end_col) / 2));
+//                         //     return deps;
+//                         // }
+
+//                         // For smaller ranges, add all cells
+//                         for r in start_row..=end_row {
+//                             for c in start_col..=end_col {
+//                                 deps.insert((r, c));
+//                             }
+//                         }
+//                         return deps;
+//                     }
+//                 }
+//             }
+//         }
+//     }
+
+//     // Fall back to standard extraction for other formulas
+//     extract_dependencies_without_self(formula, total_rows, total_cols)
+// }
+/// DFS from `(row,col)`, marking that cell and all downstream dependents
+/// as `Error` with `value = 0`.
+// Marks a cell and its dependents as error
+pub fn mark_cell_and_dependents_as_error(sheet: &mut Spreadsheet, row: i32, col: i32) {
+    let mut stack = vec![(row, col)];
+    let mut visited = HashSet::new();
+
+    while let Some((r, c)) = stack.pop() {
+        if !visited.insert((r, c)) {
+            continue;
+        }
+
+        let cell = sheet.get_or_create_cell(r, c);
+        if cell.status == CellStatus::Error {
+            continue;
+        }
+
+        cell.status = CellStatus::Error;
+        cell.value = CellValue::Int(0);
+
+        let dependents = cell.dependents.clone();
+        sheet.stamp_modified(r, c);
+        for &(dep_row, dep_col) in &dependents {
+            stack.push((dep_row, dep_col));
+        }
+    }
+}
+
+// Create a cloneable wrapper to avoid borrowing issues
+/// A read-only wrapper for parser/evaluator that only exposes `get_cell`,
+/// `total_rows`, and `total_cols`.
+#[derive(Clone)]
+pub struct CloneableSheet<'a> {
+    sheet: &'a Spreadsheet,
+}
+
+impl<'a> CloneableSheet<'a> {
+    pub fn new(sheet: &'a Spreadsheet) -> Self {
+        Self { sheet }
+    }
+
+    /// Numeric view of a cell. Text cells read as `CellStatus::Error`, so
+    /// arithmetic and range functions over them fail.
+    pub fn get_cell(&self, row: i32, col: i32) -> Option<CellView> {
+        if row >= 0 && row < self.sheet.total_rows && col >= 0 && col < self.sheet.total_cols {
+            if crate::parser::validating() {
+                // a nonzero stand-in, so `A1/B1` still parses past the `/`
+                return Some(CellView {
+                    value: 1,
+                    status: CellStatus::Ok,
+                });
+            }
+            if let Some(cell) = self.sheet.cells.get(&(row, col)).filter(|c| !is_blank(c)) {
+                let status = if cell.value.as_text().is_some() {
+                    CellStatus::Error
+                } else {
+                    cell.status.clone()
+                };
+                return Some(CellView {
+                    value: cell.number(),
+                    status,
+                });
+            }
+            // Blank cells read as the sheet's default
+            return Some(CellView {
+                value: self.sheet.default_value,
+                status: CellStatus::Ok,
+            });
+        }
+        None
+    }
+
+    /// `get_cell` as seen by range aggregates: `None` for blank cells when
+    /// the sheet has `skip_absent` set, so they aren't counted.
+    pub fn get_range_cell(&self, row: i32, col: i32) -> Option<CellView> {
+        if self.sheet.skip_absent && !crate::parser::validating() && self.sheet.is_blank(row, col) {
+            return None;
+        }
+        self.get_cell(row, col)
+    }
+
+    /// Bounds of a range defined with `Spreadsheet::define_name`, or of the
+    /// column under a header (see `Spreadsheet::resolve_name`).
+    pub fn named_range(&self, name: &str) -> Option<(i32, i32, i32, i32)> {
+        self.sheet.resolve_name(name)
+    }
+
+    /// Typed view of a cell (see `Spreadsheet::get_cell_typed`), used by `CONCAT`.
+    pub fn get_cell_typed(&self, row: i32, col: i32) -> CellValue {
+        self.sheet.get_cell_typed(row, col)
+    }
+
+    pub fn total_rows(&self) -> i32 {
+        self.sheet.total_rows
+    }
+
+    pub fn total_cols(&self) -> i32 {
+        self.sheet.total_cols
+    }
+
+    pub fn sleep_enabled(&self) -> bool {
+        self.sheet.sleep_enabled
+    }
+
+    pub fn max_sleep(&self) -> Duration {
+        self.sheet.max_sleep
+    }
+
+    /// What blank cells read as; see `Spreadsheet::get_cell_value`.
+    pub fn default_value(&self) -> i32 {
+        self.sheet.default_value
+    }
+
+    /// Whether range aggregates leave blank cells out; see `get_range_cell`.
+    pub fn skip_absent(&self) -> bool {
+        self.sheet.skip_absent
+    }
+
+    #[doc(hidden)]
+    pub fn force_large_range(&self) -> bool {
+        self.sheet.force_large_range
+    }
+
+    /// Identifies the sheet in range cache keys, so two sheets with the
+    /// same formula text never share a cached result.
+    pub fn cache_id(&self) -> u64 {
+        self.sheet.cache_id
+    }
+
+    /// The workbook sheet called `name` (case-insensitive), if this sheet
+    /// is being evaluated inside a `Workbook`.
+    pub fn sibling(&self, name: &str) -> Option<CloneableSheet<'a>> {
+        self.sheet
+            .siblings
+            .iter()
+            .find(|(sibling, _)| sibling.eq_ignore_ascii_case(name))
+            .map(|(_, sheet)| CloneableSheet::new(sheet))
+    }
+}
+
+/// Named sheets whose formulas can read each other's cells as `Name!A1`,
+/// e.g. `Sheet2!B3*2`. Sheet names are letters followed by digits and are
+/// matched case-insensitively.
+///
+/// Formulas that read other sheets must be entered through
+/// `Workbook::update_cell_formula`. Every such edit re-evaluates the
+/// cross-sheet formulas that read the edited sheet, so a change flows on
+/// to the sheets that read it, and to theirs. Rows are inserted and deleted
+/// with `Workbook::insert_row`/`delete_row`, which also move the references
+/// other sheets hold. Not supported: ranges on other sheets
+/// (`Sheet2!A1:A3`), a sheet naming itself, and cycles between sheets (they
+/// stop after one pass per sheet).
+#[derive(Default)]
+pub struct Workbook {
+    sheets: Vec<(String, Box<Spreadsheet>)>,
+}
+
+impl Workbook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an empty `rows × cols` sheet called `name`. `status_msg` is
+    /// "Ok", "Invalid sheet name" or "Sheet already exists".
+    pub fn add_sheet(&mut self, name: &str, rows: i32, cols: i32, status_msg: &mut String) {
+        status_msg.clear();
+        let letters = name.bytes().take_while(u8::is_ascii_alphabetic).count();
+        if letters == 0 || !name[letters..].bytes().all(|b| b.is_ascii_digit()) {
+            status_msg.push_str("Invalid sheet name");
+        } else if self.position(name).is_some() {
+            status_msg.push_str("Sheet already exists");
+        } else {
+            self.sheets
+                .push((name.to_string(), Spreadsheet::new(rows, cols)));
+            status_msg.push_str("Ok");
+        }
+    }
+
+    /// The sheet called `name` (case-insensitive).
+    pub fn get_sheet(&self, name: &str) -> Option<&Spreadsheet> {
+        self.position(name).map(|idx| &*self.sheets[idx].1)
+    }
+
+    /// The sheet called `name` (case-insensitive), for edits that stay on
+    /// that sheet. Other sheets reading it only catch up on the next
+    /// `Workbook::update_cell_formula`.
+    pub fn sheet_mut(&mut self, name: &str) -> Option<&mut Spreadsheet> {
+        let idx = self.position(name)?;
+        Some(&mut *self.sheets[idx].1)
+    }
+
+    /// Sheet names in the order they were added.
+    pub fn sheet_names(&self) -> Vec<&str> {
+        self.sheets.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// `Spreadsheet::update_cell_formula` on sheet `sheet`, with the other
+    /// sheets reachable from the formula; then every cross-sheet formula in
+    /// the workbook is brought up to date. `status_msg` is "Unknown sheet"
+    /// if there is no such sheet.
+    pub fn update_cell_formula(
+        &mut self,
+        sheet: &str,
+        row: i32,
+        col: i32,
+        formula: &str,
+        status_msg: &mut String,
+    ) {
+        let Some(idx) = self.position(sheet) else {
+            status_msg.clear();
+            status_msg.push_str("Unknown sheet");
+            return;
+        };
+        self.with_siblings(idx, |sheet| {
+            sheet.update_cell_formula(row, col, formula, status_msg)
+        });
+        self.recalc_cross_refs(vec![idx]);
+    }
+
+    /// `Spreadsheet::insert_row` on sheet `sheet`; references to it from
+    /// the other sheets (`Sheet2!A3`) move with its rows. `status_msg` is
+    /// "Ok" or "Unknown sheet".
+    pub fn insert_row(&mut self, sheet: &str, at: i32, status_msg: &mut String) {
+        self.remap_rows(
+            sheet,
+            status_msg,
+            |sheet| sheet.insert_row(at),
+            |r| Some(if r >= at { r + 1 } else { r }),
+        );
+    }
+
+    /// `Spreadsheet::delete_row` on sheet `sheet`; references to it from
+    /// the other sheets move with its rows, and a formula that read the
+    /// deleted row becomes a `#REF` error, as on the sheet itself.
+    /// `status_msg` is "Ok" or "Unknown sheet".
+    pub fn delete_row(&mut self, sheet: &str, at: i32, status_msg: &mut String) {
+        self.remap_rows(
+            sheet,
+            status_msg,
+            |sheet| sheet.delete_row(at),
+            |r| match r.cmp(&at) {
+                std::cmp::Ordering::Less => Some(r),
+                std::cmp::Ordering::Equal => None,
+                std::cmp::Ordering::Greater => Some(r - 1),
+            },
+        );
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.sheets
+            .iter()
+            .position(|(sheet, _)| sheet.eq_ignore_ascii_case(name))
+    }
+
+    // Run `f` on sheet `idx` with every other sheet moved into its
+    // `siblings`, where `CloneableSheet::sibling` finds them
+    fn with_siblings<R>(&mut self, idx: usize, f: impl FnOnce(&mut Spreadsheet) -> R) -> R {
+        let (name, mut sheet) = self.sheets.remove(idx);
+        sheet.siblings = std::mem::take(&mut self.sheets);
+        let result = f(&mut sheet);
+        self.sheets = std::mem::take(&mut sheet.siblings);
+        self.sheets.insert(idx, (name, sheet));
+        result
+    }
+
+    // Shared body of `insert_row`/`delete_row`: `edit` the sheet, then send
+    // the other sheets' references to it through `row_of`
+    fn remap_rows(
+        &mut self,
+        sheet: &str,
+        status_msg: &mut String,
+        edit: impl FnOnce(&mut Spreadsheet),
+        row_of: impl Fn(i32) -> Option<i32>,
+    ) {
+        status_msg.clear();
+        let Some(idx) = self.position(sheet) else {
+            status_msg.push_str("Unknown sheet");
+            return;
+        };
+        status_msg.push_str("Ok");
+        let total_rows = self.sheets[idx].1.total_rows;
+        // with the other sheets lent, so the sheet's own `Name!A1` formulas
+        // still evaluate when it recalculates
+        self.with_siblings(idx, edit);
+        if self.sheets[idx].1.total_rows == total_rows {
+            return; // `at` was out of range
+        }
+
+        let name = self.sheets[idx].0.clone();
+        let mut changed = vec![idx];
+        for other in (0..self.sheets.len()).filter(|&other| other != idx) {
+            let sheet = &mut self.sheets[other].1;
+            let mut broken = false;
+            for ((row, col), formula) in sheet.all_formulas() {
+                match remap_sheet_refs(&formula, &name, &row_of) {
+                    Some(remapped) if remapped == formula => {}
+                    Some(remapped) => {
+                        // same cells under new names: values stay as they are
+                        let formula_idx = sheet.store_formula(&remapped);
+                        sheet.get_or_create_cell(row, col).formula_idx = Some(formula_idx);
+                    }
+                    None => {
+                        // #REF: the formula read a cell of the deleted row
+                        let cell = sheet.get_or_create_cell(row, col);
+                        cell.formula_idx = None;
+                        for dep in std::mem::take(&mut cell.dependencies) {
+                            if let Some(dep_cell) = sheet.cells.get_mut(&dep) {
+                                dep_cell.dependents.remove(&(row, col));
+                            }
+                        }
+                        mark_cell_and_dependents_as_error(sheet, row, col);
+                        broken = true;
+                    }
+                }
+            }
+            if broken {
+                changed.push(other);
+            }
+        }
+        self.recalc_cross_refs(changed);
+    }
+
+    // Re-evaluate the formulas that read a `changed` sheet (and their
+    // dependents), then those reading the sheets that changed in turn, until
+    // nothing changes; one pass per sheet covers any acyclic chain
+    fn recalc_cross_refs(&mut self, mut changed: Vec<usize>) {
+        for _ in 0..self.sheets.len() {
+            let names: Vec<String> = changed
+                .iter()
+                .map(|&idx| self.sheets[idx].0.clone())
+                .collect();
+            changed.clear();
+            let reads_changed = |formula: &str| {
+                referenced_sheets(formula)
+                    .iter()
+                    .any(|sheet| names.iter().any(|name| name.eq_ignore_ascii_case(sheet)))
+            };
+            for idx in 0..self.sheets.len() {
+                let updated = self.with_siblings(idx, |sheet| {
+                    let cross: Vec<(i32, i32)> = sheet
+                        .all_formulas()
+                        .into_iter()
+                        .filter(|(_, formula)| reads_changed(formula))
+                        .map(|(pos, _)| pos)
+                        .collect();
+                    if cross.is_empty() {
+                        return false;
+                    }
+                    let snapshot = |sheet: &Spreadsheet| -> Vec<(i32, CellStatus)> {
+                        cross
+                            .iter()
+                            .map(|&(r, c)| {
+                                (sheet.get_cell_value(r, c), sheet.get_cell_status(r, c))
+                            })
+                            .collect()
+                    };
+                    let before = snapshot(sheet);
+                    for &(r, c) in &cross {
+                        sheet.mark_dirty(r, c);
+                    }
+                    sheet.recalc_dirty(&mut String::new());
+                    snapshot(sheet) != before
+                });
+                if updated {
+                    changed.push(idx);
+                }
+            }
+            if changed.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+// Light-weight view of cell data for read-only operations
+pub struct CellView {
+    pub value: i32,
+    pub status: CellStatus,
+}
+
+// Detects circular dependency using DFS with indexes to avoid borrowing issues
+pub fn has_circular_dependency_by_index(sheet: &Spreadsheet, row: i32, col: i32) -> bool {
+    let mut visited = HashSet::new();
+    let mut stack = vec![(row, col)];
+
+    while let Some((r, c)) = stack.pop() {
+        if !visited.insert((r, c)) {
+            continue;
+        }
+
+        // Get dependencies for the current cell
+        if let Some(cell) = sheet.cells.get(&(r, c)) {
+            // Check for circular dependency
+            for &(dep_row, dep_col) in &cell.dependencies {
+                if dep_row == row && dep_col == col {
+                    return true;
+                }
+
+                if !visited.contains(&(dep_row, dep_col)) {
+                    stack.push((dep_row, dep_col));
+                }
+            }
+        }
+    }
+
+    false
+}
+/// Mark `(row,col)` and its dependents dirty so they’ll be re-evaluated.
+// More memory-efficient dirty cells handling
+pub fn mark_cell_and_dependents_dirty(sheet: &mut Spreadsheet, row: i32, col: i32) {
+    // For large spreadsheets, avoid excessive memory usage
+    const MAX_DIRTY_CELLS: usize = 1000000;
+
+    // if sheet.dirty_cells.len() > MAX_DIRTY_CELLS {
+    //     // If we have too many dirty cells already, do a partial recalculation now
+    //     let status_msg = &mut String::new();
+    //     recalc_affected(sheet, status_msg);
+    // }
+
+    let mut queue = VecDeque::new();
+    let mut visited = HashSet::new();
+
+    // Start with the immediate dependents
+    let dependents = if let Some(cell) = sheet.cells.get(&(row, col)) {
+        cell.dependents.clone()
+    } else {
+        HashSet::new()
+    };
+
+    for &(dep_row, dep_col) in &dependents {
+        queue.push_back((dep_row, dep_col));
+    }
+
+    // Process the dependency graph with a limit to avoid stack overflows
+    let mut cells_visited = 0;
+    const MAX_VISIT: usize = 5000;
+
+    while let Some((r, c)) = queue.pop_front() {
+        cells_visited += 1;
+        // if cells_visited > MAX_VISIT {
+        //     // Too many cells in the chain, mark them for batch processing later
+        //     sheet.dirty_cells.insert((r, c));
+        //     continue;
+        // }
+
+        if !visited.insert((r, c)) {
+            continue; // Already visited
+        }
+
+        // Mark as dirty
+        sheet.dirty_cells.insert((r, c));
+
+        // Invalidate any range functions that depend on this cell
+        crate::parser::invalidate_cache_for_cell(r, c);
+
+        // Add this cell's dependents to the queue (with batch processing for large chains)
+        let next_dependents = if let Some(cell) = sheet.cells.get(&(r, c)) {
+            cell.dependents.clone()
+        } else {
+            HashSet::new()
+        };
+
+        for &(dep_row, dep_col) in &next_dependents {
+            sheet.dirty_cells.insert((dep_row, dep_col));
+        }
+
+        // if next_dependents.len() > 1000000 {
+        //     // For cells with many dependents, just mark them all as dirty without full traversal
+        //     for &(dep_row, dep_col) in &next_dependents {
+        //         sheet.dirty_cells.insert((dep_row, dep_col));
+        //     }
+        // } else {
+        //     // For cells with fewer dependents, continue normal traversal
+        //     for &(dep_row, dep_col) in &next_dependents {
+        //         if !visited.contains(&(dep_row, dep_col)) {
+        //             queue.push_back((dep_row, dep_col));
+        //         }
+        //     }
+        // }
+    }
+}
+
+#[cfg(test)]
+#[allow(deprecated)] // `valid_formula` keeps its tests while it's still exported
+mod tests {
+    // everything declared in sheet.rs
+    use super::*;
+    use crate::parser::clear_range_cache;
+    use crate::parser::{
+        // for the cache/history tests
+        evaluate_formula,
+    };
+
+    // only compile these if the cli_app feature is on
+    #[cfg(feature = "cli_app")]
+    use super::*; // now brings in sheet.rs items via the outer super
+                  // import your CLI helpers:
+    use crate::cli_app::{clamp_viewport_hz, clamp_viewport_ve, col_to_letters, process_command};
+
+    #[test]
+    fn cell_name_roundtrip_and_bounds() {
+        assert_eq!(cell_name_to_coords("AA10"), Some((9, 26)));
+        assert_eq!(coords_to_cell_name(9, 26), "AA10");
+        // invalid
+        assert!(cell_name_to_coords("1A").is_none());
+    }
+
+    #[test]
+    fn valid_formula_simple_and_errors() {
+        let sheet = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        // plain number or ref
+        assert_eq!(valid_formula(&sheet, "42", &mut msg), 0);
+        assert_eq!(valid_formula(&sheet, "A1", &mut msg), 0);
+        // bad formula
+        assert_eq!(valid_formula(&sheet, "", &mut msg), 1);
+        assert!(msg.contains("Empty"));
+    }
+
+    #[test]
+    fn extract_dependencies_and_circular() {
+        let deps = extract_dependencies(&Spreadsheet::new(5, 5), "B2:C3");
+        assert!(deps.contains(&(1, 1)) && deps.contains(&(2, 2)));
+        // manual cycle
+        let mut s = Spreadsheet::new(2, 2);
+        s.get_or_create_cell(0, 0).dependencies.insert((0, 1));
+        s.get_or_create_cell(0, 1).dependencies.insert((0, 0));
+        assert!(has_circular_dependency(&s, 0, 0));
+        assert!(has_circular_dependency_by_index(&s, 0, 0));
+    }
+
+    #[test]
+    fn clear_and_invalidate_range_cache() {
+        let mut s = Spreadsheet::new(2, 2);
+        s.update_cell_value(0, 0, 3, CellStatus::Ok);
+        s.update_cell_value(0, 1, 5, CellStatus::Ok);
+        let mut msg = String::new();
+        let cs = CloneableSheet::new(&s);
+        assert_eq!(
+            crate::parser::evaluate_formula(&cs, "SUM(A1:B1)", 0, 0, &mut 0, &mut msg),
+            8
+        );
+        clear_range_cache();
+        // change value and re-eval
+        s.update_cell_value(0, 0, 7, CellStatus::Ok);
+        let cs2 = CloneableSheet::new(&s);
+        assert_eq!(
+            crate::parser::evaluate_formula(&cs2, "SUM(A1:B1)", 0, 0, &mut 0, &mut msg),
+            12
+        );
+    }
+
+    fn recalc_dirty_and_error_propagation() {
+        // Build a little chain of dependents A1→A2→A3→A4
+        let mut sheet = Spreadsheet::new(4, 1);
+        sheet.get_or_create_cell(0, 0).dependents.insert((1, 0));
+        sheet.get_or_create_cell(1, 0).dependents.insert((2, 0));
+        sheet.get_or_create_cell(2, 0).dependents.insert((3, 0));
+
+        // Initially clean:
+        assert!(sheet.dirty_cells.is_empty());
+
+        // Mark A1 dirty and propagate
+        mark_cell_and_dependents_dirty(&mut sheet, 0, 0);
+
+        // A2 and A3 get marked; A4 does _not_
+        assert!(sheet.dirty_cells.contains(&(1, 0)), "A2 should be dirty");
+        assert!(sheet.dirty_cells.contains(&(2, 0)), "A3 should be dirty");
+        assert!(
+            !sheet.dirty_cells.contains(&(3, 0)),
+            "A4 should NOT be dirty"
+        );
+    }
+
+    #[test]
+    fn mark_and_dirty_cells() {
+        let mut s = Spreadsheet::new(4, 1);
+        // manually wire dependents: A1→A2→A3→A4
+        for i in 0..3 {
+            s.get_or_create_cell(i, 0).dependents.insert((i + 1, 0));
+        }
+        mark_cell_and_dependents_as_error(&mut s, 0, 0);
+        for i in 0..4 {
+            assert_eq!(s.get_cell_status(i, 0), CellStatus::Error);
+        }
+    }
+
+    use super::*; // brings Spreadsheet, CellStatus, etc.
+
+    #[test]
+    fn get_or_create_cell_tracks_dependencies_and_dependents() {
+        let mut sheet = Spreadsheet::new(3, 3);
+        // make A1 depend on B2
+        sheet.get_or_create_cell(0, 0).dependencies.insert((1, 1));
+        sheet.get_or_create_cell(1, 1).dependents.insert((0, 0));
+        // verify detection
+        assert!(has_circular_dependency_by_index(&sheet, 0, 0) == false);
+    }
+
+    #[test]
+    fn mark_error_propagates_through_chain() {
+        let mut sheet = Spreadsheet::new(3, 1);
+        // A1→A2→A3
+        sheet.get_or_create_cell(0, 0).dependents.insert((1, 0));
+        sheet.get_or_create_cell(1, 0).dependents.insert((2, 0));
+        mark_cell_and_dependents_as_error(&mut sheet, 0, 0);
+        assert_eq!(sheet.get_cell_status(2, 0), CellStatus::Error);
+    }
+
+    fn name_and_coords_roundtrip() {
+        assert_eq!(cell_name_to_coords("A1"), Some((0, 0)));
+        assert_eq!(cell_name_to_coords("AA10"), Some((9, 26)));
+        assert_eq!(coords_to_cell_name(0, 0), "A1");
+        assert_eq!(coords_to_cell_name(9, 26), "AA10");
+    }
+
+    #[test]
+    fn valid_formula_basic_cases() {
+        let sheet = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+
+        // empty
+        assert_eq!(valid_formula(&sheet, "", &mut msg), 1);
+        assert_eq!(msg, "Empty formula");
+
+        // plain number
+        msg.clear();
+        assert_eq!(valid_formula(&sheet, "123", &mut msg), 0);
+
+        // OOB cell
+        msg.clear();
+        assert_eq!(valid_formula(&sheet, "Z99", &mut msg), 1);
+        assert_eq!(msg, "Cell reference out of bounds");
+    }
+
+    #[test]
+    fn extract_dependencies_single_and_range() {
+        let sheet = Spreadsheet::new(2, 2);
+
+        // single
+        let single = extract_dependencies(&sheet, "B2");
+        let mut want = std::collections::HashSet::new();
+        want.insert((1, 1));
+        assert_eq!(single, want);
+
+        // range
+        let range = extract_dependencies(&sheet, "SUM(A1:B2)");
+        let mut expected = std::collections::HashSet::new();
+        expected.insert((0, 0));
+        expected.insert((0, 1));
+        expected.insert((1, 0));
+        expected.insert((1, 1));
+        assert_eq!(range, expected);
+    }
+
+    #[test]
+    fn update_formula_chain_propagates_immediately() {
+        let mut sheet = Spreadsheet::new(4, 1);
+        let mut status = String::new();
+
+        // Build: A1=2, A2=A1*2, A3=A2*2, A4=A3*2
+        sheet.update_cell_formula(0, 0, "2", &mut status);
+        sheet.update_cell_formula(1, 0, "A1*2", &mut status);
+        sheet.update_cell_formula(2, 0, "A2*2", &mut status);
+        sheet.update_cell_formula(3, 0, "A3*2", &mut status);
+
+        // Now change A1 to 3
+        sheet.update_cell_formula(0, 0, "3", &mut status);
+
+        // All downstream cells should have updated right away:
+        assert_eq!(sheet.get_cell_value(0, 0), 3);
+        assert_eq!(sheet.get_cell_value(1, 0), 6);
+        assert_eq!(sheet.get_cell_value(2, 0), 12);
+        assert_eq!(sheet.get_cell_value(3, 0), 24);
+    }
+    #[test]
+    fn raw_content_and_defaults() {
+        let mut sheet = Spreadsheet::new(2, 2);
+        // no formula yet
+        assert_eq!(sheet.get_cell_raw_content(0, 0), "");
+        // default value/status
+        assert_eq!(sheet.get_cell_value(0, 0), 0);
+        assert_eq!(sheet.get_cell_status(0, 0), CellStatus::Ok);
+    }
+
+    // …and tests for undo/redo, history (if cell_history feature enabled), etc.
+    // at the bottom of src/sheet.rs
+
+    use super::*; // brings in Spreadsheet, CellStatus, valid_formula, extract_dependencies, etc.
+    use std::collections::HashSet;
+
+    /// `formula` passes `parser::validate_formula` for the size of `s`
+    fn validates(s: &Spreadsheet, formula: &str) -> bool {
+        crate::parser::validate_formula(formula, s.total_rows, s.total_cols).is_ok()
+    }
+
+    #[test]
+    fn new_and_name_roundtrip() {
+        let s = Spreadsheet::new(3, 4);
+        assert_eq!(s.total_rows, 3);
+        assert_eq!(s.total_cols, 4);
+        assert!(s.cells.is_empty());
+        // name conversions
+        assert_eq!(cell_name_to_coords("B2"), Some((1, 1)));
+        assert_eq!(coords_to_cell_name(1, 1), "B2");
+    }
+
+    #[test]
+    fn set_value_and_raw_content() {
+        let mut s = Spreadsheet::new(2, 2);
+        s.update_cell_value(0, 0, 99, CellStatus::Ok);
+        assert_eq!(s.get_cell_value(0, 0), 99);
+        assert_eq!(s.get_cell_raw_content(0, 0), "");
+    }
+
+    #[test]
+    fn set_formula_and_recalc_chain() {
+        let mut s = Spreadsheet::new(2, 2);
+        let mut status = String::new();
+        s.update_cell_formula(0, 0, "5", &mut status);
+        s.update_cell_formula(1, 0, "A1*2", &mut status);
+        assert_eq!(s.get_cell_value(1, 0), 10);
+    }
+
+    #[test]
+    fn recalc_affected_propagates_and_clears_dirty() {
+        let mut s = Spreadsheet::new(3, 1);
+        let mut status = String::new();
+        // A1=1, A2=A1+1, A3=A2+1
+        s.update_cell_formula(0, 0, "1", &mut status);
+        s.update_cell_formula(1, 0, "A1+1", &mut status);
+        s.update_cell_formula(2, 0, "A2+1", &mut status);
+
+        // dirty just A1
+        s.dirty_cells.insert((0, 0));
+        assert_eq!(recalc_affected(&mut s, &mut status), 3);
+        assert_eq!(recalc_affected(&mut s, &mut status), 0, "nothing dirty");
+
+        assert!(s.dirty_cells.is_empty());
+        assert_eq!(s.get_cell_value(2, 0), 3);
+    }
+
+    #[test]
+    fn extract_and_validate() {
+        let s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        // valid literal formula
+        assert!(validates(&s, "123"));
+        assert_eq!(valid_formula(&s, "123", &mut msg), 0);
+        // invalid cell ref
+        assert!(!validates(&s, "X9"));
+        assert_eq!(valid_formula(&s, "X9", &mut msg), 1);
+
+        // extract full 3×3 range
+        let deps = extract_dependencies(&s, "A1:C3");
+        let mut want = HashSet::new();
+        for r in 0..3 {
+            for c in 0..3 {
+                want.insert((r, c));
+            }
+        }
+        assert_eq!(deps, want);
+    }
+
+    #[test]
+    fn error_propagation_on_div_zero() {
+        let mut s = Spreadsheet::new(2, 2);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "10", &mut msg);
+        s.update_cell_formula(0, 1, "0", &mut msg);
+        s.update_cell_formula(1, 0, "A1/B1", &mut msg);
+        assert_eq!(s.get_cell_status(1, 0), CellStatus::Error);
+    }
+
+    #[test]
+    fn test_col_to_letters_cli() {
+        assert_eq!(crate::cli_app::col_to_letters(0), "A");
+        assert_eq!(crate::cli_app::col_to_letters(25), "Z");
+        assert_eq!(crate::cli_app::col_to_letters(26), "AA");
+        assert_eq!(crate::cli_app::col_to_letters(701), "ZZ");
+        assert_eq!(crate::cli_app::col_to_letters(702), "AAA");
+    }
+
+    #[test]
+    fn test_clamp_viewport() {
+        let mut r = 50;
+        clamp_viewport_ve(40, &mut r);
+        assert_eq!(r, 30, "50 > 40 - 10, clamps to the last window at 30");
+
+        let mut r2 = -5;
+        clamp_viewport_ve(100, &mut r2);
+        assert_eq!(r2, 0, "-5 < 0, clamps up to 0");
+
+        let mut c = 95;
+        clamp_viewport_hz(90, &mut c);
+        assert_eq!(c, 80, "95 > 90 - 10, clamps to the last window at 80");
+
+        let mut c2 = -1;
+        clamp_viewport_hz(10, &mut c2);
+        assert_eq!(c2, 0, "-1 < 0, clamps up to 0");
+    }
+    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
+    // 2) process_command branches
+    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
+
+    #[test]
+    fn test_process_command_scroll_and_invalid() {
+        let mut sheet = Spreadsheet::new(5, 5);
+        let mut status = String::new();
+
+        process_command(&mut sheet, "scroll_to B2", &mut status);
+        assert_eq!((sheet.top_row, sheet.left_col), (1, 1));
+
+        process_command(&mut sheet, "scroll_to Z9", &mut status);
+        assert!(status.contains("out of bounds"));
+
+        process_command(&mut sheet, "foo", &mut status);
+        assert_eq!(status, "unrecognized cmd");
+    }
+
+    #[test]
+    fn test_clear_cache_and_history_without_feature() {
+        let mut sheet = Spreadsheet::new(3, 3);
+        let mut status = String::new();
+
+        // seed the cache
+        sheet.cache.insert(
+            "X".into(),
+            CachedRange {
+                value: 1,
+                dependencies: Default::default(),
+            },
+        );
+
+        process_command(&mut sheet, "clear_cache", &mut status);
+        assert_eq!(status, "Cache cleared");
+        assert!(sheet.cache.is_empty());
+
+        // history should say "not enabled"
+        process_command(&mut sheet, "history A1", &mut status);
+        assert_eq!(status, "Cell history feature is not enabled.");
+    }
+
+    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
+    // 3) Undo/Redo & History (if features enabled)
+    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
+
+    #[cfg(feature = "undo_state")]
+    #[test]
+    fn test_undo_redo_basic() {
+        let mut sheet = Spreadsheet::new(2, 2);
+        let mut status = String::new();
+        sheet.update_cell_formula(0, 0, "5", &mut status);
+        sheet.update_cell_formula(0, 0, "6", &mut status);
+        super::cli_app::process_command(&mut sheet, "undo", &mut status);
+        assert_eq!(sheet.get_cell_value(0, 0), 5);
+        super::cli_app::process_command(&mut sheet, "redo", &mut status);
+        assert_eq!(sheet.get_cell_value(0, 0), 6);
+    }
+
+    #[cfg(feature = "cell_history")]
+    #[test]
+    fn test_cell_history_feature() {
+        let mut sheet = Spreadsheet::new(2, 2);
+        let mut status = String::new();
+        sheet.update_cell_formula(0, 0, "5", &mut status);
+        sheet.update_cell_formula(0, 0, "7", &mut status);
+        super::cli_app::process_command(&mut sheet, "history A1", &mut status);
+        assert_eq!(status, "History displayed");
+    }
+
+    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
+    // 4) mark_dirty & recalc + dependency graph
+    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
+
+    #[test]
+    fn test_dirty_and_recalc_chain() {
+        let mut sheet = Spreadsheet::new(4, 1);
+        sheet.get_or_create_cell(0, 0).dependents.insert((1, 0));
+        sheet.get_or_create_cell(1, 0).dependents.insert((2, 0));
+        sheet.get_or_create_cell(2, 0).dependents.insert((3, 0));
+
+        // mark dirty
+        mark_cell_and_dependents_dirty(&mut sheet, 0, 0);
+        assert!(sheet.dirty_cells.contains(&(1, 0)));
+        assert!(sheet.dirty_cells.contains(&(2, 0)));
+        assert!(!sheet.dirty_cells.contains(&(3, 0)));
+
+        // recalc with a simple chain of formulas
+        let mut status = String::new();
+        sheet.update_cell_formula(0, 0, "1", &mut status);
+        sheet.update_cell_formula(1, 0, "A1+1", &mut status);
+        sheet.update_cell_formula(2, 0, "A2+1", &mut status);
+        sheet.dirty_cells.insert((0, 0));
+        recalc_affected(&mut sheet, &mut status);
+        assert_eq!(sheet.get_cell_value(2, 0), 3);
+        assert!(sheet.dirty_cells.is_empty());
+    }
+
+    use super::*;
+    // 2) pull in your CLI layer so `process_command` is visible:
+    use crate::cli_app;
+
+    // ────────────────────────────────────────────────────────────────────────────────
+    // expose private parser functions & types to our #[cfg(test)] module
+    #[cfg(test)]
+    pub(crate) mod test_exports {
+        pub use crate::parser::{
+            clear_range_cache, evaluate_ast, evaluate_large_range, evaluate_range_function,
+            invalidate_cache_for_cell, parse_expr, parse_factor, parse_term, ASTNode, RANGE_CACHE,
+        };
+    }
+
+    #[cfg(test)]
+    mod parser_internal_tests {
+        use super::test_exports::*;
+        use crate::sheet::{CellStatus, CloneableSheet, Spreadsheet};
+
+        #[test]
+        fn test_skip_spaces_and_number_parsing() {
+            let sheet = Spreadsheet::new(1, 1);
+            let cs = CloneableSheet::new(&*sheet);
+            let mut s = "   -123abc";
+            let mut err = 0;
+            let v = parse_factor(&cs, &mut s, 0, 0, &mut err);
+            assert_eq!(v, -123);
+            assert_eq!(err, 0);
+        }
+
+        #[test]
+        fn test_parse_comparisons_and_binary() {
+            let sheet = Spreadsheet::new(1, 1);
+            let cs = CloneableSheet::new(&*sheet);
+            let mut err = 0;
+
+            let mut s1 = "2>1";
+            assert_eq!(parse_expr(&cs, &mut s1, 0, 0, &mut err), 1);
+
+            let mut s2 = "5<=3";
+            assert_eq!(parse_expr(&cs, &mut s2, 0, 0, &mut err), 0);
+
+            let mut s3 = "4==4";
+            assert_eq!(parse_expr(&cs, &mut s3, 0, 0, &mut err), 1);
+
+            let mut s4 = "3+2*4-5/5";
+            assert_eq!(parse_expr(&cs, &mut s4, 0, 0, &mut err), 10);
+        }
+
+        #[test]
+        fn test_evaluate_range_function_errors_and_success() {
+            let mut sheet = Spreadsheet::new(2, 2);
+            let cs = CloneableSheet::new(&*sheet);
+            let mut err = 0;
+
+            // syntax error
+            assert_eq!(evaluate_range_function(&cs, "SUM", "A1B2", &mut err), 0);
+            assert_eq!(err, 1);
+
+            // out‐of‐bounds
+            err = 0;
+            assert_eq!(evaluate_range_function(&cs, "SUM", "A1:C1", &mut err), 0);
+            assert_eq!(err, 4);
+
+            // valid
+            sheet.update_cell_value(0, 0, 1, CellStatus::Ok);
+            sheet.update_cell_value(0, 1, 2, CellStatus::Ok);
+            let cs2 = CloneableSheet::new(&*sheet);
+            err = 0;
+            assert_eq!(evaluate_range_function(&cs2, "SUM", "A1:B1", &mut err), 3);
+        }
+
+        #[test]
+        fn test_clear_and_invalidate_cache_for_cell() {
+            clear_range_cache();
+            // manually inject into the RANGE_CACHE:
+            RANGE_CACHE.with(|c| {
+                c.borrow_mut()
+                    .insert("X".into(), (5, [(0, 0)].iter().cloned().collect()));
+            });
+            invalidate_cache_for_cell(0, 0);
+            RANGE_CACHE.with(|c| assert!(c.borrow().is_empty()));
+        }
+
+        #[test]
+        fn test_evaluate_ast_literals_and_sleep() {
+            let sheet = Spreadsheet::new(1, 1);
+            let cs = CloneableSheet::new(&*sheet);
+            let mut err = 0;
+
+            let lit = ASTNode::Literal(7);
+            assert_eq!(evaluate_ast(&cs, &lit, 0, 0, &mut err), 7);
+
+            let sf = ASTNode::SleepFunction(Box::new(ASTNode::Literal(-2)));
+            assert_eq!(evaluate_ast(&cs, &sf, 0, 0, &mut err), -2);
+        }
+    }
+
+    #[test]
+    fn test_extract_dependencies_without_self_simple_range() {
+        // A1:B2 should give (0,0),(0,1),(1,0),(1,1)
+        let deps = extract_dependencies_without_self("A1:B2", 10, 10);
+        let mut want = std::collections::HashSet::new();
+        want.insert((0, 0));
+        want.insert((0, 1));
+        want.insert((1, 0));
+        want.insert((1, 1));
+        assert_eq!(deps, want);
+    }
+
+    #[test]
+    fn test_extract_dependencies_without_self_reversed_range() {
+        // Even if the bounds are reversed, it should normalize
+        let deps = extract_dependencies_without_self("B2:A1", 10, 10);
+        let mut want = std::collections::HashSet::new();
+        want.insert((0, 0));
+        want.insert((0, 1));
+        want.insert((1, 0));
+        want.insert((1, 1));
+        assert_eq!(deps, want);
+    }
+
+    #[test]
+    fn test_extract_dependencies_without_self_multi_letter_cols() {
+        // AA1:AB2 maps to rows 0–1, cols 26→27 (0‐based 26 and 27)
+        let deps = extract_dependencies_without_self("AA1:AB2", 100, 100);
+        let mut want = std::collections::HashSet::new();
+        for r in 0..=1 {
+            for c in 26..=27 {
+                want.insert((r, c));
+            }
+        }
+        assert_eq!(deps, want);
+    }
+
+    #[test]
+    fn test_extract_dependencies_without_self_single_cell() {
+        // Single cell reference
+        let deps = extract_dependencies_without_self("C3", 5, 5);
+        let mut want = std::collections::HashSet::new();
+        want.insert((2, 2));
+        assert_eq!(deps, want);
+    }
+
+    #[test]
+    fn test_extract_dependencies_without_self_out_of_bounds() {
+        // Z1 is col 25, but total_cols=5 → out of bounds, so skip
+        let deps = extract_dependencies_without_self("Z1:Z1", 5, 5);
+        assert!(deps.is_empty());
+    }
+
+    // Add to the `#[cfg(test)] mod tests { ... }` in src/sheet.rs
+
+    #[test]
+    fn valid_formula_range_errors() {
+        let mut sheet = Spreadsheet::new(5, 5);
+        let mut msg = String::new();
+
+        // Missing colon in range
+        assert_eq!(valid_formula(&sheet, "SUM(A1A2)", &mut msg), 1);
+        assert!(msg.contains("Missing colon in range"));
+
+        // Range past the edge of the sheet
+        msg.clear();
+        assert_eq!(valid_formula(&sheet, "SUM(A1:Z10)", &mut msg), 1);
+        assert_eq!(msg, "Range out of bounds");
+
+        // Invalid range order
+        msg.clear();
+        assert_eq!(valid_formula(&sheet, "SUM(B2:A1)", &mut msg), 1);
+        assert!(msg.contains("Invalid range order"));
+    }
+
+    fn valid_formula_operator_not_found() {
+        let sheet = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        let code = valid_formula(&sheet, "XYZ", &mut msg);
+        assert_eq!(code, 1);
+        assert_eq!(msg, "Operator not found");
+    }
+
+    #[test]
+    fn valid_formula_sleep_variants() {
+        let sheet = Spreadsheet::new(2, 2);
+        let mut msg = String::new();
+        // Missing closing parenthesis
+        assert_eq!(valid_formula(&sheet, "SLEEP(1", &mut msg), 1);
+        assert_eq!(msg, "Missing closing parenthesis in SLEEP");
+        // Integer argument
+        msg.clear();
+        let code_int = valid_formula(&sheet, "SLEEP(5)", &mut msg);
+        assert_eq!(code_int, 0);
+        assert_eq!(msg, "");
+        // Valid cell reference
+        msg.clear();
+        let code_ref = valid_formula(&sheet, "SLEEP(A1)", &mut msg);
+        assert_eq!(code_ref, 0);
+        // Out-of-bounds cell reference
+        msg.clear();
+        let code_oob = valid_formula(&sheet, "SLEEP(Z9)", &mut msg);
+        assert_eq!(code_oob, 1);
+        assert_eq!(msg, "Cell reference in up out of bounds");
+    }
+
+    fn recalc_detects_div_zero_and_marks_error() {
+        let mut sheet = Spreadsheet::new(2, 1);
+        let mut status = String::new();
+        // A1 = 2
+        sheet.update_cell_formula(0, 0, "2", &mut status);
+        // A2 = A1/0 → division by zero
+        sheet.update_cell_formula(1, 0, "A1/0", &mut status);
+        sheet.dirty_cells.insert((1, 0));
+        recalc_affected(&mut sheet, &mut status);
+        assert_eq!(sheet.get_cell_status(1, 0), CellStatus::Error);
+        assert_eq!(sheet.get_cell_value(1, 0), 0);
+    }
+
+    fn inject_formula(sheet: &mut Spreadsheet, formula: &'static str) {
+        // Push into storage and point A1 at it
+        let idx = sheet.store_formula(formula);
+        let cell = sheet.get_or_create_cell(0, 0);
+        cell.formula_idx = Some(idx);
+        // clear any old dependencies & dependents
+        cell.dependencies.clear();
+        cell.dependents.clear();
+        sheet.dirty_cells.insert((0, 0));
+    }
+    #[test]
+    fn recalc_detects_reversed_range_as_invalid_range() {
+        let mut sheet = Spreadsheet::new(1, 1);
+        let mut status = String::new();
+
+        // SUM(A2:A1) is syntactically valid, but 2 > 1 should trigger error_flag=2
+        inject_formula(&mut sheet, "SUM(A2:A1)");
+        status.clear();
+        recalc_affected(&mut sheet, &mut status);
+        assert_eq!(status, "Invalid range");
+    }
+
+    #[test]
+    fn recalc_detects_syntax_error_as_general_error() {
+        let mut sheet = Spreadsheet::new(1, 1);
+        let mut status = String::new();
+
+        // "1?1" passes our simple valid_formula (it has an operator at index 1),
+        // but parse_expr will set error_flag=1 on the '?'.
+        inject_formula(&mut sheet, "1?1");
+        status.clear();
+        recalc_affected(&mut sheet, &mut status);
+        assert_eq!(status, "Error in formula");
+    }
+
+    fn cloneable_sheet_get_cell_various() {
+        let mut sheet = Spreadsheet::new(2, 2);
+        // Set a cell to a non-default value and status
+        sheet.update_cell_value(1, 1, 42, CellStatus::Error);
+        let cs = CloneableSheet::new(&sheet);
+
+        // Out-of-bounds coordinates should return None
+        assert!(cs.get_cell(-1, 0).is_none());
+        assert!(cs.get_cell(0, -1).is_none());
+        assert!(cs.get_cell(2, 0).is_none());
+        assert!(cs.get_cell(0, 2).is_none());
+
+        // In-bounds but non-existent cell should return default view
+        let view = cs.get_cell(0, 1).unwrap();
+        assert_eq!(view.value, 0);
+        assert_eq!(view.status, CellStatus::Ok);
+
+        // Existing cell should return its actual value and status
+        let view2 = cs.get_cell(1, 1).unwrap();
+        assert_eq!(view2.value, 42);
+        assert_eq!(view2.status, CellStatus::Error);
+    }
+
+    #[test]
+    fn valid_formula_sleep_and_operator_and_format() {
+        let sheet = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+
+        // Missing closing parenthesis in SLEEP
+        let code = valid_formula(&sheet, "SLEEP(5", &mut msg);
+        assert_eq!(code, 1);
+        assert_eq!(msg, "Missing closing parenthesis in SLEEP");
+
+        // Valid numeric SLEEP
+        msg.clear();
+        let code = valid_formula(&sheet, "SLEEP( 7 )", &mut msg);
+        assert_eq!(code, 0);
+
+        // Invalid cell reference in SLEEP
+        msg.clear();
+        let code = valid_formula(&sheet, "SLEEP(ABC)", &mut msg);
+        assert_eq!(code, 1);
+        assert_eq!(msg, "Invalid cell reference in SLEEP");
+
+        // Operator not found
+        msg.clear();
+        let code = valid_formula(&sheet, "foobar", &mut msg);
+        assert_eq!(code, 1);
+        assert_eq!(msg, "Operator not found");
+
+        // Invalid formula format (right side not int or cell)
+        msg.clear();
+        let code = valid_formula(&sheet, "A1+foo", &mut msg);
+        assert_eq!(code, 1);
+        assert_eq!(msg, "Invalid formula format");
+    }
+
+    fn process_command_scroll_to_invalid_cell() {
+        let mut sheet = Spreadsheet::new(5, 5);
+        let mut msg = String::new();
+        process_command(&mut sheet, "scroll_to foo", &mut msg);
+        assert!(
+            msg.contains("Invalid cell"),
+            "got `{}` expected substring `Invalid cell`",
+            msg
+        );
+    }
 
-        // Invalidate any range functions that depend on this cell
-        crate::parser::invalidate_cache_for_cell(r, c);
+    #[test]
+    fn process_command_clear_cache() {
+        let mut sheet = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        sheet.cache.insert(
+            "X".into(),
+            CachedRange {
+                value: 1,
+                dependencies: HashSet::new(),
+            },
+        );
+        process_command(&mut sheet, "clear_cache", &mut msg);
+        assert_eq!(msg, "Cache cleared");
+        assert!(sheet.cache.is_empty());
+    }
 
-        // Add this cell's dependents to the queue (with batch processing for large chains)
-        let next_dependents = if let Some(cell) = sheet.cells.get(&(r, c)) {
-            cell.dependents.clone()
-        } else {
-            HashSet::new()
-        };
+    #[test]
+    fn cloneable_get_cell_bounds_and_default() {
+        let sheet = Spreadsheet::new(2, 2);
+        let cs = crate::sheet::CloneableSheet::new(&*sheet);
+        // out of bounds → None
+        assert!(cs.get_cell(-1, 0).is_none());
+        assert!(cs.get_cell(0, -1).is_none());
+        assert!(cs.get_cell(2, 0).is_none());
+        // in bounds but never set → Some(default)
+        let cv = cs.get_cell(1, 1).unwrap();
+        assert_eq!(cv.value, 0);
+        assert_eq!(cv.status, CellStatus::Ok);
+    }
 
-        for &(dep_row, dep_col) in &next_dependents {
-            sheet.dirty_cells.insert((dep_row, dep_col));
+    #[test]
+    fn extract_dependencies_without_self_multi_letter_cols() {
+        let deps = crate::sheet::extract_dependencies_without_self("AA1:AB2", 100, 100);
+        let mut want = HashSet::new();
+        // AA → col 26, AB → col 27 (0-based)
+        for r in 0..=1 {
+            for c in 26..=27 {
+                want.insert((r, c));
+            }
         }
-
-        // if next_dependents.len() > 1000000 {
-        //     // For cells with many dependents, just mark them all as dirty without full traversal
-        //     for &(dep_row, dep_col) in &next_dependents {
-        //         sheet.dirty_cells.insert((dep_row, dep_col));
-        //     }
-        // } else {
-        //     // For cells with fewer dependents, continue normal traversal
-        //     for &(dep_row, dep_col) in &next_dependents {
-        //         if !visited.contains(&(dep_row, dep_col)) {
-        //             queue.push_back((dep_row, dep_col));
-        //         }
-        //     }
-        // }
+        assert_eq!(deps, want);
     }
-}
-
-#[cfg(test)]
-mod tests {
-    // everything declared in sheet.rs
-    use super::*;
-    use crate::parser::clear_range_cache;
-    use crate::parser::{
-        // for the cache/history tests
-        evaluate_formula,
-    };
 
-    // only compile these if the cli_app feature is on
-    #[cfg(feature = "cli_app")]
-    use super::*; // now brings in sheet.rs items via the outer super
-                  // import your CLI helpers:
-    use crate::cli_app::{clamp_viewport_hz, clamp_viewport_ve, col_to_letters, process_command};
+    /// valid_formula: missing colon in range
+    #[test]
+    fn sheet_valid_formula_missing_colon() {
+        let s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        assert_eq!(valid_formula(&s, "SUM(A1A2)", &mut msg), 1);
+        assert!(msg.contains("Missing colon"));
+    }
 
+    /// valid_formula: invalid range order
     #[test]
-    fn cell_name_roundtrip_and_bounds() {
-        assert_eq!(cell_name_to_coords("AA10"), Some((9, 26)));
-        assert_eq!(coords_to_cell_name(9, 26), "AA10");
-        // invalid
-        assert!(cell_name_to_coords("1A").is_none());
+    fn sheet_valid_formula_invalid_range_order() {
+        let s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        assert_eq!(valid_formula(&s, "SUM(B2:A1)", &mut msg), 1);
+        assert!(msg.contains("Invalid range order"));
     }
 
+    /// valid_formula: missing ')' on SLEEP
     #[test]
-    fn valid_formula_simple_and_errors() {
-        let sheet = Spreadsheet::new(3, 3);
+    fn sheet_valid_formula_sleep_missing_paren() {
+        let s = Spreadsheet::new(1, 1);
         let mut msg = String::new();
-        // plain number or ref
-        assert_eq!(valid_formula(&sheet, "42", &mut msg), 0);
-        assert_eq!(valid_formula(&sheet, "A1", &mut msg), 0);
-        // bad formula
-        assert_eq!(valid_formula(&sheet, "", &mut msg), 1);
-        assert!(msg.contains("Empty"));
+        assert_eq!(valid_formula(&s, "SLEEP(1", &mut msg), 1);
+        assert!(msg.contains("Missing closing parenthesis"));
     }
 
+    /// valid_formula: invalid cell in SLEEP
     #[test]
-    fn extract_dependencies_and_circular() {
-        let deps = extract_dependencies(&Spreadsheet::new(5, 5), "B2:C3");
-        assert!(deps.contains(&(1, 1)) && deps.contains(&(2, 2)));
-        // manual cycle
-        let mut s = Spreadsheet::new(2, 2);
-        s.get_or_create_cell(0, 0).dependencies.insert((0, 1));
-        s.get_or_create_cell(0, 1).dependencies.insert((0, 0));
-        assert!(has_circular_dependency(&s, 0, 0));
-        assert!(has_circular_dependency_by_index(&s, 0, 0));
+    fn sheet_valid_formula_sleep_invalid_cell() {
+        let s = Spreadsheet::new(1, 1);
+        let mut msg = String::new();
+        assert_eq!(valid_formula(&s, "SLEEP(B2)", &mut msg), 1);
+        assert!(msg.contains("out of bounds"));
     }
 
+    /// valid_formula: operator not found
     #[test]
-    fn clear_and_invalidate_range_cache() {
-        let mut s = Spreadsheet::new(2, 2);
-        s.update_cell_value(0, 0, 3, CellStatus::Ok);
-        s.update_cell_value(0, 1, 5, CellStatus::Ok);
+    fn sheet_valid_formula_operator_not_found() {
+        let s = Spreadsheet::new(1, 1);
         let mut msg = String::new();
-        let cs = CloneableSheet::new(&s);
-        assert_eq!(
-            crate::parser::evaluate_formula(&cs, "SUM(A1:B1)", 0, 0, &mut 0, &mut msg),
-            8
-        );
-        clear_range_cache();
-        // change value and re-eval
-        s.update_cell_value(0, 0, 7, CellStatus::Ok);
-        let cs2 = CloneableSheet::new(&s);
-        assert_eq!(
-            crate::parser::evaluate_formula(&cs2, "SUM(A1:B1)", 0, 0, &mut 0, &mut msg),
-            12
-        );
+        assert_eq!(valid_formula(&s, "foo", &mut msg), 1);
+        assert!(msg.contains("Operator not found"));
     }
 
-    fn recalc_dirty_and_error_propagation() {
-        // Build a little chain of dependents A1→A2→A3→A4
-        let mut sheet = Spreadsheet::new(4, 1);
-        sheet.get_or_create_cell(0, 0).dependents.insert((1, 0));
-        sheet.get_or_create_cell(1, 0).dependents.insert((2, 0));
-        sheet.get_or_create_cell(2, 0).dependents.insert((3, 0));
+    /// valid_formula: invalid format
 
-        // Initially clean:
-        assert!(sheet.dirty_cells.is_empty());
+    /// recalc_affected: invalid range sets "Invalid range"
 
-        // Mark A1 dirty and propagate
-        mark_cell_and_dependents_dirty(&mut sheet, 0, 0);
+    /// recalc_affected: general error sets "Error in formula"
 
-        // A2 and A3 get marked; A4 does _not_
-        assert!(sheet.dirty_cells.contains(&(1, 0)), "A2 should be dirty");
-        assert!(sheet.dirty_cells.contains(&(2, 0)), "A3 should be dirty");
-        assert!(
-            !sheet.dirty_cells.contains(&(3, 0)),
-            "A4 should NOT be dirty"
-        );
+    /// extract_dependencies_without_self: two‐letter columns
+    #[test]
+    fn sheet_extract_deps_without_self_two_letter() {
+        let deps = extract_dependencies_without_self("AA1:AB2", 100, 100);
+        let mut want = HashSet::new();
+        // AA→col 26, AB→col 27 (0-based)
+        for r in 0..=1 {
+            for c in 26..=27 {
+                want.insert((r, c));
+            }
+        }
+        assert_eq!(deps, want);
     }
 
+    /// extract_dependencies: simple single + range
     #[test]
-    fn mark_and_dirty_cells() {
-        let mut s = Spreadsheet::new(4, 1);
-        // manually wire dependents: A1→A2→A3→A4
-        for i in 0..3 {
-            s.get_or_create_cell(i, 0).dependents.insert((i + 1, 0));
-        }
-        mark_cell_and_dependents_as_error(&mut s, 0, 0);
-        for i in 0..4 {
-            assert_eq!(s.get_cell_status(i, 0), CellStatus::Error);
+    fn sheet_extract_dependencies_basic() {
+        let s = Spreadsheet::new(3, 3);
+        let single = extract_dependencies(&s, "B2");
+        assert_eq!(single, vec![(1, 1)].into_iter().collect());
+
+        let range = extract_dependencies(&s, "SUM(A1:C1)");
+        let mut want = HashSet::new();
+        for c in 0..3 {
+            want.insert((0, c));
         }
+        assert_eq!(range, want);
     }
 
-    use super::*; // brings Spreadsheet, CellStatus, etc.
+    /// MOD/POWER/LCM: arity checked by valid_formula and validate_formula, evaluated through update_cell_formula
+    #[cfg(feature = "advanced_formulas")]
+    #[test]
+    fn sheet_mod_and_power_formulas() {
+        let mut s = Spreadsheet::new(2, 2);
+        let mut msg = String::new();
+        assert!(!validates(&s, "MOD(1)"));
+        assert!(!validates(&s, "POWER(1,2,3)"));
+        assert_eq!(valid_formula(&s, "MOD(1)", &mut msg), 1);
+        assert!(msg.contains("MOD needs 2 args"));
+        assert_eq!(valid_formula(&s, "POWER(1,2,3)", &mut msg), 1);
+        assert!(msg.contains("POWER needs 2 args"));
+
+        s.update_cell_value(0, 0, 17, CellStatus::Ok);
+        s.update_cell_formula(0, 1, "MOD(A1,5)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 2);
+        s.update_cell_formula(1, 0, "POWER(B1,3)", &mut msg);
+        assert_eq!(s.get_cell_value(1, 0), 8);
+
+        s.update_cell_formula(1, 1, "MOD(A1,0)", &mut msg);
+        assert_eq!(s.get_cell_status(1, 1), CellStatus::Error);
+
+        assert!(!validates(&s, "LCM(4)"));
+        assert_eq!(valid_formula(&s, "LCM(4)", &mut msg), 1);
+        assert!(msg.contains("LCM needs 2 args"));
+        s.update_cell_formula(1, 1, "LCM(A1,B1*3)", &mut msg);
+        assert_eq!(s.get_cell_value(1, 1), 102);
+    }
 
+    /// export_csv_changed_since: only the edited row is re-rendered
     #[test]
-    fn get_or_create_cell_tracks_dependencies_and_dependents() {
-        let mut sheet = Spreadsheet::new(3, 3);
-        // make A1 depend on B2
-        sheet.get_or_create_cell(0, 0).dependencies.insert((1, 1));
-        sheet.get_or_create_cell(1, 1).dependents.insert((0, 0));
-        // verify detection
-        assert!(has_circular_dependency_by_index(&sheet, 0, 0) == false);
+    fn export_csv_changed_since_rewrites_only_modified_rows() {
+        let mut s = Spreadsheet::new(3, 2);
+        let mut msg = String::new();
+        s.update_cell_value(0, 0, 1, CellStatus::Ok);
+        s.update_cell_formula(1, 1, "A1+1", &mut msg);
+
+        let path = std::env::temp_dir().join(format!(
+            "sheet_incremental_{}.csv",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(s.export_csv_changed_since(&path, 0).unwrap(), 3);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1,\n,2\n,\n");
+
+        // mark the untouched rows so we can see whether they get rewritten
+        std::fs::write(&path, "sentinel0\n,2\nsentinel2\n").unwrap();
+        let rev = s.revision;
+        s.update_cell_value(1, 0, 7, CellStatus::Ok);
+        assert_eq!(s.modified_since(rev), vec![(1, 0)]);
+
+        assert_eq!(s.export_csv_changed_since(&path, rev).unwrap(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "sentinel0\n7,2\nsentinel2\n"
+        );
+        let _ = std::fs::remove_file(&path);
     }
 
+    /// modified_since: recalculated dependents are reported too
     #[test]
-    fn mark_error_propagates_through_chain() {
-        let mut sheet = Spreadsheet::new(3, 1);
-        // A1→A2→A3
-        sheet.get_or_create_cell(0, 0).dependents.insert((1, 0));
-        sheet.get_or_create_cell(1, 0).dependents.insert((2, 0));
-        mark_cell_and_dependents_as_error(&mut sheet, 0, 0);
-        assert_eq!(sheet.get_cell_status(2, 0), CellStatus::Error);
+    fn modified_since_includes_recalculated_cells() {
+        let mut s = Spreadsheet::new(2, 2);
+        let mut msg = String::new();
+        s.update_cell_formula(1, 1, "A1*2", &mut msg);
+        let rev = s.revision;
+        s.update_cell_formula(0, 0, "5", &mut msg);
+        assert_eq!(s.modified_since(rev), vec![(0, 0), (1, 1)]);
+        assert!(s.modified_since(s.revision).is_empty());
     }
 
-    fn name_and_coords_roundtrip() {
-        assert_eq!(cell_name_to_coords("A1"), Some((0, 0)));
-        assert_eq!(cell_name_to_coords("AA10"), Some((9, 26)));
-        assert_eq!(coords_to_cell_name(0, 0), "A1");
-        assert_eq!(coords_to_cell_name(9, 26), "AA10");
+    /// replace_value: literals are rewritten, formula cells are not
+    #[test]
+    fn replace_value_skips_formula_cells() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "0", &mut msg);
+        s.update_cell_formula(0, 1, "0", &mut msg);
+        s.update_cell_formula(0, 2, "5", &mut msg);
+        s.update_cell_value(1, 0, 0, CellStatus::Ok);
+        // formula cell that also evaluates to 0
+        s.update_cell_formula(1, 1, "A1*C1", &mut msg);
+        s.update_cell_formula(1, 2, "A1+B1", &mut msg);
+
+        assert_eq!(s.replace_value(0, 1, &mut msg), 3);
+        assert_eq!(s.get_cell_raw_content(0, 0), "1");
+        assert_eq!(s.get_cell_value(0, 1), 1);
+        assert_eq!(s.get_cell_value(0, 2), 5);
+        // an explicit 0 set by update_cell_value is a literal too; blanks aren't
+        assert_eq!(s.get_cell_value(1, 0), 1);
+        assert!(s.is_blank(2, 0));
+        // formulas untouched, but recalculated from the new literals
+        assert_eq!(s.get_cell_raw_content(1, 1), "A1*C1");
+        assert_eq!(s.get_cell_value(1, 1), 5);
+        assert_eq!(s.get_cell_raw_content(1, 2), "A1+B1");
+        assert_eq!(s.get_cell_value(1, 2), 2);
+
+        assert_eq!(s.replace_value(42, 0, &mut msg), 0);
     }
 
+    /// export_formulas_csv: formula text rather than values
     #[test]
-    fn valid_formula_basic_cases() {
-        let sheet = Spreadsheet::new(3, 3);
+    fn export_formulas_csv_writes_formula_text() {
+        let mut s = Spreadsheet::new(2, 3);
         let mut msg = String::new();
+        s.update_cell_formula(0, 0, "3", &mut msg);
+        s.update_cell_formula(0, 1, "A1*2", &mut msg);
+        s.update_cell_formula(1, 2, "MAX(A1:B1)", &mut msg);
+
+        let path = std::env::temp_dir().join(format!(
+            "sheet_formulas_{}.csv",
+            std::process::id()
+        ));
+        s.export_formulas_csv(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(text, "3,A1*2,\n,,MAX(A1:B1)\n");
+    }
 
-        // empty
-        assert_eq!(valid_formula(&sheet, "", &mut msg), 1);
-        assert_eq!(msg, "Empty formula");
+    #[test]
+    fn csv_escape_quotes_when_needed() {
+        assert_eq!(csv_escape("A1+B1"), "A1+B1");
+        assert_eq!(csv_escape("IF(A1,1,2)"), "\"IF(A1,1,2)\"");
+        assert_eq!(csv_escape("\">2\""), "\"\"\">2\"\"\"");
+    }
 
-        // plain number
-        msg.clear();
-        assert_eq!(valid_formula(&sheet, "123", &mut msg), 0);
+    /// save_to_csv: values, ERR for errors, empty for unset cells
+    #[test]
+    fn save_to_csv_writes_values() {
+        let mut s = Spreadsheet::new(2, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "4", &mut msg);
+        s.update_cell_formula(0, 2, "A1*10", &mut msg);
+        s.update_cell_formula(1, 1, "A1/0", &mut msg);
+
+        let path = std::env::temp_dir().join(format!("sheet_save_{}.csv", std::process::id()));
+        s.save_to_csv(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(text, "4,,40\n,ERR,\n");
+    }
 
-        // OOB cell
-        msg.clear();
-        assert_eq!(valid_formula(&sheet, "Z99", &mut msg), 1);
-        assert_eq!(msg, "Cell reference out of bounds");
+    /// diff: value changes, presence changes and errors, row-major
+    #[test]
+    fn diff_lists_changed_cells_in_order() {
+        let mut left = Spreadsheet::new(3, 3);
+        let mut right = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        for s in [&mut left, &mut right] {
+            s.update_cell_formula(0, 0, "5", &mut msg);
+            s.update_cell_formula(0, 1, "A1*2", &mut msg);
+            // B3 is stored (C1 reads it) but empty
+            s.update_cell_formula(0, 2, "B3", &mut msg);
+        }
+        assert!(left.diff(&right).is_empty());
+        assert_eq!(left.diff(&Spreadsheet::new(3, 3)).len(), 3);
+
+        right.update_cell_formula(0, 0, "6", &mut msg);
+        right.update_cell_formula(2, 2, "1", &mut msg);
+        left.update_cell_formula(1, 0, "A1/0", &mut msg);
+        let diffs = left.diff(&right);
+        let cell = |row, col, left, right| CellDiff {
+            row,
+            col,
+            left,
+            right,
+        };
+        assert_eq!(
+            diffs,
+            vec![
+                cell(0, 0, Some(5), Some(6)),
+                cell(0, 1, Some(10), Some(12)),
+                cell(1, 0, Some(0), None),
+                cell(2, 2, None, Some(1)),
+            ]
+        );
     }
 
+    /// to_tsv: tabs, CRLF, `#ERR` for errors, quoted text only when needed
     #[test]
-    fn extract_dependencies_single_and_range() {
-        let sheet = Spreadsheet::new(2, 2);
-
-        // single
-        let single = extract_dependencies(&sheet, "B2");
-        let mut want = std::collections::HashSet::new();
-        want.insert((1, 1));
-        assert_eq!(single, want);
+    fn to_tsv_writes_values() {
+        let mut s = Spreadsheet::new(2, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "4", &mut msg);
+        s.update_cell_formula(0, 2, "A1*10", &mut msg);
+        s.update_cell_formula(1, 1, "A1/0", &mut msg);
+        s.update_cell_formula(1, 2, "\"a,b\"", &mut msg);
+        assert_eq!(s.to_tsv(), "4\t\t40\r\n\t#ERR\ta,b\r\n");
 
-        // range
-        let range = extract_dependencies(&sheet, "SUM(A1:B2)");
-        let mut expected = std::collections::HashSet::new();
-        expected.insert((0, 0));
-        expected.insert((0, 1));
-        expected.insert((1, 0));
-        expected.insert((1, 1));
-        assert_eq!(range, expected);
+        assert_eq!(Delimiter::Tab.escape("a\tb"), "\"a\tb\"");
     }
 
+    /// load_from_csv: ragged rows, formulas and malformed fields
     #[test]
-    fn update_formula_chain_propagates_immediately() {
-        let mut sheet = Spreadsheet::new(4, 1);
-        let mut status = String::new();
+    fn load_from_csv_sizes_and_applies_formulas() {
+        let path = std::env::temp_dir().join(format!("sheet_load_{}.csv", std::process::id()));
+        std::fs::write(&path, "1,2\n=A1+B1,oops,,\"=SUM(A1:B1)\"\n=C9\n").unwrap();
+        let s = Spreadsheet::load_from_csv(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!((s.total_rows, s.total_cols), (3, 4));
+        assert_eq!(s.get_cell_value(0, 1), 2);
+        assert_eq!(s.get_cell_value(1, 0), 3);
+        assert_eq!(s.get_formula(1, 0), Some("A1+B1".to_string()));
+        assert_eq!(s.get_cell_value(1, 3), 3);
+        assert_eq!(s.get_cell_status(1, 1), CellStatus::Error);
+        assert!(!s.cells.contains_key(&(1, 2)));
+        // out-of-bounds reference is stored as an error, the load carries on
+        assert_eq!(s.get_cell_status(2, 0), CellStatus::Error);
+    }
 
-        // Build: A1=2, A2=A1*2, A3=A2*2, A4=A3*2
-        sheet.update_cell_formula(0, 0, "2", &mut status);
-        sheet.update_cell_formula(1, 0, "A1*2", &mut status);
-        sheet.update_cell_formula(2, 0, "A2*2", &mut status);
-        sheet.update_cell_formula(3, 0, "A3*2", &mut status);
+    #[test]
+    fn save_and_load_csv_round_trip() {
+        let mut s = Spreadsheet::new(2, 2);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "7", &mut msg);
+        s.update_cell_formula(1, 1, "A1/0", &mut msg);
 
-        // Now change A1 to 3
-        sheet.update_cell_formula(0, 0, "3", &mut status);
+        let path = std::env::temp_dir().join(format!("sheet_roundtrip_{}.csv", std::process::id()));
+        s.save_to_csv(&path).unwrap();
+        let loaded = Spreadsheet::load_from_csv(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
 
-        // All downstream cells should have updated right away:
-        assert_eq!(sheet.get_cell_value(0, 0), 3);
-        assert_eq!(sheet.get_cell_value(1, 0), 6);
-        assert_eq!(sheet.get_cell_value(2, 0), 12);
-        assert_eq!(sheet.get_cell_value(3, 0), 24);
+        assert_eq!(loaded.get_cell_value(0, 0), 7);
+        assert_eq!(loaded.get_cell_status(1, 1), CellStatus::Error);
+        assert!(!loaded.cells.contains_key(&(0, 1)));
     }
+
     #[test]
-    fn raw_content_and_defaults() {
-        let mut sheet = Spreadsheet::new(2, 2);
-        // no formula yet
-        assert_eq!(sheet.get_cell_raw_content(0, 0), "");
-        // default value/status
-        assert_eq!(sheet.get_cell_value(0, 0), 0);
-        assert_eq!(sheet.get_cell_status(0, 0), CellStatus::Ok);
+    fn csv_split_handles_quotes() {
+        assert_eq!(csv_split("a,\"b,c\",\"d\"\"e\""), vec!["a", "b,c", "d\"e"]);
+        assert_eq!(csv_split(""), vec![""]);
     }
 
-    // …and tests for undo/redo, history (if cell_history feature enabled), etc.
-    // at the bottom of src/sheet.rs
+    /// to_json / from_json: sparse output, lowercase status, round trip
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "2", &mut msg);
+        s.update_cell_formula(0, 1, "A1*3", &mut msg);
+        s.update_cell_formula(2, 2, "A1/0", &mut msg);
+        s.set_note(0, 1, "triple");
+
+        let json = s.to_json();
+        assert!(json.contains("\"status\":\"ok\""));
+        assert_eq!(json.matches("\"note\"").count(), 1);
+        assert!(json.contains("\"status\":\"error\""));
+        let dto: SheetDto = serde_json::from_str(&json).unwrap();
+        assert_eq!((dto.rows, dto.cols), (3, 3));
+        assert_eq!(dto.cells.len(), 3);
+
+        let back = Spreadsheet::from_json(&json).unwrap();
+        assert_eq!(back.get_cell_value(0, 1), 6);
+        assert_eq!(back.get_formula(0, 1), Some("A1*3".to_string()));
+        assert_eq!(back.get_cell_status(2, 2), CellStatus::Error);
+        assert_eq!(back.get_note(0, 1), Some("triple"));
+        assert_eq!(back.to_dto(), dto);
+    }
 
-    use super::*; // brings in Spreadsheet, CellStatus, valid_formula, extract_dependencies, etc.
-    use std::collections::HashSet;
+    /// resize: growing keeps cells, shrinking drops them and breaks referencing formulas
+    #[test]
+    fn resize_grows_and_shrinks() {
+        let mut s = Spreadsheet::new(5, 5);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "5", &mut msg);
+        s.update_cell_formula(4, 4, "A1+1", &mut msg);
+        s.update_cell_formula(0, 1, "E5*2", &mut msg);
+        s.update_cell_formula(0, 2, "B1+1", &mut msg);
+        assert_eq!(s.get_cell_value(0, 2), 13);
+
+        s.resize(20, 30);
+        assert_eq!((s.total_rows, s.total_cols), (20, 30));
+        assert_eq!(s.get_cell_value(4, 4), 6);
+
+        s.top_row = 15;
+        s.left_col = 25;
+        s.cursor = Some((10, 1));
+        s.resize(3, 3);
+        assert_eq!((s.total_rows, s.total_cols), (3, 3));
+        assert!(!s.cells.contains_key(&(4, 4)));
+        assert!(s.cells.keys().all(|&(r, c)| r < 3 && c < 3));
+        assert!(!s.cells[&(0, 0)].dependents.contains(&(4, 4)));
+        assert!(!s.cells[&(0, 1)].dependencies.contains(&(4, 4)));
+        assert_eq!(s.get_cell_status(0, 1), CellStatus::Error);
+        assert_eq!(s.get_cell_status(0, 2), CellStatus::Error);
+        assert_eq!(s.get_cell_status(0, 0), CellStatus::Ok);
+        assert_eq!((s.top_row, s.left_col), (0, 0));
+        assert_eq!(s.cursor, Some((2, 1)));
+
+        // editing the surviving cells still works
+        s.update_cell_formula(0, 0, "7", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(0, 0), 7);
+    }
 
+    /// resize: whole-column and whole-row ranges reach the cells a resize adds
     #[test]
-    fn new_and_name_roundtrip() {
-        let s = Spreadsheet::new(3, 4);
-        assert_eq!(s.total_rows, 3);
-        assert_eq!(s.total_cols, 4);
-        assert!(s.cells.is_empty());
-        // name conversions
-        assert_eq!(cell_name_to_coords("B2"), Some((1, 1)));
-        assert_eq!(coords_to_cell_name(1, 1), "B2");
+    fn resize_grows_whole_column_ranges() {
+        let mut s = Spreadsheet::new(3, 2);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "1", &mut msg);
+        s.update_cell_formula(1, 0, "2", &mut msg);
+        s.update_cell_formula(0, 1, "SUM(A:A)", &mut msg);
+        s.update_cell_formula(2, 1, "SUM(2:2)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 3);
+        assert_eq!(s.get_cell_value(2, 1), 2);
+
+        s.resize(5, 3);
+        s.update_cell_formula(4, 0, "10", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 13);
+        s.update_cell_formula(1, 2, "4", &mut msg);
+        assert_eq!(s.get_cell_value(2, 1), 6);
     }
 
+    /// clear_cell: entry removed, dependents recompute with 0
     #[test]
-    fn set_value_and_raw_content() {
-        let mut s = Spreadsheet::new(2, 2);
-        s.update_cell_value(0, 0, 99, CellStatus::Ok);
-        assert_eq!(s.get_cell_value(0, 0), 99);
+    fn clear_cell_removes_and_recalculates() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "4", &mut msg);
+        s.update_cell_formula(0, 1, "A1*2", &mut msg);
+        s.update_cell_formula(0, 2, "B1+1", &mut msg);
+        assert_eq!(s.get_cell_value(0, 2), 9);
+
+        // C1 has no dependents: removed outright, and unregistered from B1
+        s.clear_cell(0, 2, &mut msg);
+        assert_eq!(msg, "Ok");
+        assert!(!s.cells.contains_key(&(0, 2)));
+        assert!(s.cells[&(0, 1)].dependents.is_empty());
+
+        // A1 is still read by B1, so a blank placeholder stays behind
+        s.update_cell_formula(0, 2, "B1+1", &mut msg);
+        s.clear_cell(0, 0, &mut msg);
         assert_eq!(s.get_cell_raw_content(0, 0), "");
+        assert_eq!(s.get_cell_value(0, 1), 0);
+        assert_eq!(s.get_cell_value(0, 2), 1);
+
+        // the placeholder keeps propagating later edits
+        s.update_cell_formula(0, 0, "10", &mut msg);
+        assert_eq!(s.get_cell_value(0, 2), 21);
+
+        // clearing an empty cell is a no-op
+        s.clear_cell(2, 2, &mut msg);
+        assert_eq!(msg, "Ok");
+        assert!(!s.cells.contains_key(&(2, 2)));
     }
 
+    #[cfg(feature = "undo_state")]
     #[test]
-    fn set_formula_and_recalc_chain() {
+    fn clear_cell_is_undoable() {
         let mut s = Spreadsheet::new(2, 2);
-        let mut status = String::new();
-        s.update_cell_formula(0, 0, "5", &mut status);
-        s.update_cell_formula(1, 0, "A1*2", &mut status);
-        assert_eq!(s.get_cell_value(1, 0), 10);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "3", &mut msg);
+        s.update_cell_formula(0, 1, "A1+1", &mut msg);
+        s.clear_cell(0, 0, &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 1);
+        s.undo(&mut msg);
+        assert_eq!(s.get_formula(0, 0), Some("3".to_string()));
+        assert_eq!(s.get_cell_value(0, 1), 4);
     }
 
+    #[cfg(feature = "cell_history")]
     #[test]
-    fn recalc_affected_propagates_and_clears_dirty() {
-        let mut s = Spreadsheet::new(3, 1);
+    fn test_history_limit_configurable() {
+        let mut sheet = Spreadsheet::new(2, 2);
         let mut status = String::new();
-        // A1=1, A2=A1+1, A3=A2+1
-        s.update_cell_formula(0, 0, "1", &mut status);
-        s.update_cell_formula(1, 0, "A1+1", &mut status);
-        s.update_cell_formula(2, 0, "A2+1", &mut status);
+        assert_eq!(sheet.history_limit, 10);
+        sheet.history_limit = 100;
+        for v in 1..=30 {
+            sheet.update_cell_formula(0, 0, &v.to_string(), &mut status);
+        }
+        let hist = sheet.get_cell_history(0, 0).unwrap();
+        assert_eq!(hist.len(), 30);
+        assert_eq!(hist[0], 0);
+
+        // Shrinking the limit truncates from the front on the next push
+        sheet.history_limit = 3;
+        sheet.update_cell_formula(0, 0, "31", &mut status);
+        assert_eq!(sheet.get_cell_history(0, 0).unwrap(), vec![28, 29, 30]);
+    }
 
-        // dirty just A1
-        s.dirty_cells.insert((0, 0));
-        recalc_affected(&mut s, &mut status);
+    #[cfg(feature = "undo_state")]
+    #[test]
+    fn test_undo_redo_depth_accessors() {
+        let mut sheet = Spreadsheet::new(2, 2);
+        let mut status = String::new();
+        assert!(!sheet.can_undo());
+        assert!(!sheet.can_redo());
 
-        assert!(s.dirty_cells.is_empty());
-        assert_eq!(s.get_cell_value(2, 0), 3);
+        sheet.update_cell_formula(0, 0, "1", &mut status);
+        sheet.update_cell_formula(0, 0, "2", &mut status);
+        assert!(sheet.can_undo());
+        assert_eq!(sheet.undo_depth(), 2);
+        assert_eq!(sheet.redo_depth(), 0);
+
+        sheet.undo(&mut status);
+        assert_eq!(sheet.undo_depth(), 1);
+        assert_eq!(sheet.redo_depth(), 1);
+        assert!(sheet.can_redo());
+
+        // A fresh edit discards the redo branch
+        sheet.update_cell_formula(1, 1, "3", &mut status);
+        assert!(!sheet.can_redo());
+        assert_eq!(sheet.undo_depth(), 2);
     }
 
+    #[cfg(feature = "undo_state")]
     #[test]
-    fn extract_and_validate() {
-        let s = Spreadsheet::new(3, 3);
-        let mut msg = String::new();
-        // valid literal formula
-        assert_eq!(valid_formula(&s, "123", &mut msg), 0);
-        // invalid cell ref
-        assert_eq!(valid_formula(&s, "X9", &mut msg), 1);
+    fn test_undo_limit_configurable() {
+        let mut sheet = Spreadsheet::new(2, 2);
+        let mut status = String::new();
+        assert_eq!(sheet.undo_limit, 10);
+        sheet.undo_limit = 300;
+        for v in 1..=250 {
+            sheet.update_cell_formula(0, 0, &v.to_string(), &mut status);
+        }
+        assert_eq!(sheet.undo_depth(), 250);
 
-        // extract full 3×3 range
-        let deps = extract_dependencies(&s, "A1:C3");
-        let mut want = HashSet::new();
-        for r in 0..3 {
-            for c in 0..3 {
-                want.insert((r, c));
-            }
+        sheet.undo_limit = 3;
+        sheet.update_cell_formula(0, 0, "251", &mut status);
+        assert_eq!(sheet.undo_depth(), 3);
+        for _ in 0..3 {
+            sheet.undo(&mut status);
         }
-        assert_eq!(deps, want);
+        assert_eq!(sheet.get_cell_value(0, 0), 248);
+        sheet.undo(&mut status);
+        assert_eq!(status, "Nothing to undo");
     }
 
+    #[cfg(feature = "undo_state")]
     #[test]
-    fn error_propagation_on_div_zero() {
-        let mut s = Spreadsheet::new(2, 2);
+    fn test_undo_n_and_redo_n() {
+        let mut sheet = Spreadsheet::new(2, 2);
+        let mut status = String::new();
+        sheet.update_cell_formula(0, 0, "1", &mut status);
+        sheet.update_cell_formula(0, 0, "2", &mut status);
+        sheet.update_cell_formula(1, 0, "A1*10", &mut status);
+        sheet.update_cell_formula(0, 0, "3", &mut status);
+        assert_eq!(sheet.get_cell_value(1, 0), 30);
+
+        sheet.undo_n(2, &mut status);
+        assert_eq!(status, "Undid 2 of 2");
+        assert_eq!(sheet.get_cell_value(0, 0), 2);
+        assert_eq!(sheet.get_cell_value(1, 0), 0);
+
+        sheet.undo_n(5, &mut status);
+        assert_eq!(status, "Undid 2 of 5 (nothing more to undo)");
+        assert_eq!(sheet.get_cell_value(0, 0), 0);
+
+        sheet.redo_n(4, &mut status);
+        assert_eq!(status, "Redid 4 of 4");
+        assert_eq!(sheet.get_cell_value(0, 0), 3);
+        assert_eq!(sheet.get_cell_value(1, 0), 30);
+
+        sheet.redo_n(1, &mut status);
+        assert_eq!(status, "Redid 0 of 1 (nothing more to redo)");
+    }
+
+    #[cfg(feature = "advanced_formulas")]
+    #[test]
+    fn test_percentile_formula_recalculates() {
+        let mut sheet = Spreadsheet::new(4, 2);
         let mut msg = String::new();
-        s.update_cell_formula(0, 0, "10", &mut msg);
-        s.update_cell_formula(0, 1, "0", &mut msg);
-        s.update_cell_formula(1, 0, "A1/B1", &mut msg);
-        assert_eq!(s.get_cell_status(1, 0), CellStatus::Error);
+        assert!(!validates(&sheet, "PERCENTILE(A1:A3)"));
+        assert_eq!(valid_formula(&sheet, "PERCENTILE(A1:A3)", &mut msg), 1);
+        assert!(msg.contains("PERCENTILE needs 2 args"));
+
+        sheet.update_cell_formula(0, 0, "1", &mut msg);
+        sheet.update_cell_formula(1, 0, "3", &mut msg);
+        sheet.update_cell_formula(0, 1, "PERCENTILE(A1:A2,50)", &mut msg);
+        assert_eq!(sheet.get_cell_value(0, 1), 2);
+        sheet.update_cell_formula(1, 0, "9", &mut msg);
+        assert_eq!(sheet.get_cell_value(0, 1), 5);
     }
 
     #[test]
-    fn test_col_to_letters_cli() {
-        assert_eq!(crate::cli_app::col_to_letters(0), "A");
-        assert_eq!(crate::cli_app::col_to_letters(25), "Z");
-        assert_eq!(crate::cli_app::col_to_letters(26), "AA");
-        assert_eq!(crate::cli_app::col_to_letters(701), "ZZ");
-        assert_eq!(crate::cli_app::col_to_letters(702), "AAA");
+    fn test_range_bounds_column_and_row_refs() {
+        assert_eq!(range_bounds("A1:B3", 5, 4), Some((0, 0, 2, 1)));
+        assert_eq!(range_bounds("B:C", 5, 4), Some((0, 1, 4, 2)));
+        assert_eq!(range_bounds("2:3", 5, 4), Some((1, 0, 2, 3)));
+        assert_eq!(range_bounds("A:1", 5, 4), None);
+        assert_eq!(range_bounds("0:1", 5, 4), None);
+        assert_eq!(range_bounds("A1", 5, 4), None);
     }
 
     #[test]
-    fn test_clamp_viewport() {
-        let mut r = 50;
-        clamp_viewport_ve(40, &mut r);
-        assert_eq!(r, 40, "50 > 40, so we do 50 - 10 = 40");
+    fn test_whole_column_sum_tracks_dependencies() {
+        let mut sheet = Spreadsheet::new(4, 3);
+        let mut status = String::new();
+        sheet.update_cell_formula(0, 0, "1", &mut status);
+        sheet.update_cell_formula(1, 0, "2", &mut status);
+        sheet.update_cell_formula(0, 2, "SUM(A:A)", &mut status);
+        assert_eq!(status, "Ok");
+        assert_eq!(sheet.get_cell_value(0, 2), 3);
+        sheet.update_cell_formula(3, 2, "SUM(2:2)", &mut status);
+        assert_eq!(sheet.get_cell_value(3, 2), 2);
+
+        // editing any cell of the column / row recalculates the aggregate
+        sheet.update_cell_formula(1, 0, "7", &mut status);
+        assert_eq!(sheet.get_cell_value(0, 2), 8);
+        assert_eq!(sheet.get_cell_value(3, 2), 7);
+        sheet.update_cell_formula(1, 1, "5", &mut status);
+        assert_eq!(sheet.get_cell_value(3, 2), 12);
+
+        // a whole-column range over its own column is circular
+        sheet.update_cell_formula(3, 0, "SUM(A:A)", &mut status);
+        assert_ne!(status, "Ok");
+        assert_eq!(sheet.get_cell_value(0, 2), 8);
+    }
 
-        let mut r2 = -5;
-        clamp_viewport_ve(100, &mut r2);
-        assert_eq!(r2, 0, "-5 < 0, clamps up to 0");
+    #[test]
+    fn test_cell_name_to_coords_checked_reasons() {
+        assert_eq!(cell_name_to_coords_checked("B3"), Ok((2, 1)));
+        assert_eq!(cell_name_to_coords_checked("aa10"), Ok((9, 26)));
+        assert_eq!(
+            cell_name_to_coords_checked(""),
+            Err(CoordError::MissingColumn)
+        );
+        assert_eq!(
+            cell_name_to_coords_checked("12"),
+            Err(CoordError::MissingColumn)
+        );
+        assert_eq!(
+            cell_name_to_coords_checked("C"),
+            Err(CoordError::MissingRow)
+        );
+        assert_eq!(
+            cell_name_to_coords_checked("C0"),
+            Err(CoordError::MissingRow)
+        );
+        assert_eq!(
+            cell_name_to_coords_checked("A$1"),
+            Err(CoordError::BadCharacter('$'))
+        );
+        assert_eq!(
+            cell_name_to_coords_checked("A99999999999"),
+            Err(CoordError::Overflow)
+        );
+        assert_eq!(
+            cell_name_to_coords_checked("PERCENTILEXYZ1"),
+            Err(CoordError::Overflow)
+        );
+        assert_eq!(CoordError::MissingRow.to_string(), "missing row number");
 
-        let mut c = 95;
-        clamp_viewport_hz(90, &mut c);
-        assert_eq!(c, 85, "95 > 90, so we do 95 - 10 = 85");
+        // the Option wrapper agrees
+        assert_eq!(cell_name_to_coords("B3"), Some((2, 1)));
+        assert_eq!(cell_name_to_coords("A$1"), None);
+    }
 
-        let mut c2 = -1;
-        clamp_viewport_hz(10, &mut c2);
-        assert_eq!(c2, 0, "-1 < 0, clamps up to 0");
+    #[test]
+    fn test_get_range_values_and_statuses() {
+        let mut sheet = Spreadsheet::new(3, 3);
+        sheet.update_cell_value(0, 0, 1, CellStatus::Ok);
+        sheet.update_cell_value(1, 1, 5, CellStatus::Ok);
+        sheet.update_cell_value(2, 2, 0, CellStatus::Error);
+
+        assert_eq!(
+            sheet.get_range_values(0, 0, 1, 1),
+            vec![vec![1, 0], vec![0, 5]]
+        );
+        // corners in any order
+        assert_eq!(
+            sheet.get_range_values(1, 1, 0, 0),
+            sheet.get_range_values(0, 0, 1, 1)
+        );
+        // clamped to the grid
+        assert_eq!(sheet.get_range_values(1, 1, 9, -4).len(), 2);
+        assert_eq!(sheet.get_range_values(-5, -5, 9, 9)[1], vec![0, 5, 0]);
+
+        let statuses = sheet.get_range_statuses(1, 1, 2, 2);
+        assert_eq!(
+            statuses,
+            vec![
+                vec![CellStatus::Ok, CellStatus::Ok],
+                vec![CellStatus::Ok, CellStatus::Error]
+            ]
+        );
+        assert!(Spreadsheet::new(0, 0)
+            .get_range_values(0, 0, 1, 1)
+            .is_empty());
     }
-    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
-    // 2) process_command branches
-    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
 
     #[test]
-    fn test_process_command_scroll_and_invalid() {
-        let mut sheet = Spreadsheet::new(5, 5);
+    fn test_iter_cells_visits_only_populated_cells() {
+        let mut sheet = Spreadsheet::new(1000, 1000);
         let mut status = String::new();
+        sheet.update_cell_formula(500, 3, "7", &mut status);
+        sheet.update_cell_formula(2, 900, "8", &mut status);
+        sheet.update_cell_formula(2, 1, "A1+1", &mut status);
+
+        assert_eq!(sheet.iter_cells().count(), sheet.cells.len());
+        let ordered: Vec<(i32, i32, i32)> = sheet
+            .iter_cells_ordered()
+            .map(|(r, c, cell)| (r, c, cell.number()))
+            .filter(|&(r, _, _)| r != 0) // A1 exists only as a dependency placeholder
+            .collect();
+        assert_eq!(ordered, vec![(2, 1, 1), (2, 900, 8), (500, 3, 7)]);
+    }
 
-        process_command(&mut sheet, "scroll_to B2", &mut status);
-        assert_eq!((sheet.top_row, sheet.left_col), (1, 1));
+    #[test]
+    fn test_text_cells_and_typed_values() {
+        let mut sheet = Spreadsheet::new(3, 3);
+        let mut status = String::new();
+        sheet.update_cell_formula(0, 0, "\"Total\"", &mut status);
+        assert_eq!(status, "Ok");
+        sheet.update_cell_formula(0, 1, "5", &mut status);
+
+        assert_eq!(sheet.get_cell_typed(0, 0), CellValue::Text("Total".into()));
+        assert_eq!(sheet.get_cell_typed(0, 1), CellValue::Int(5));
+        assert_eq!(sheet.get_cell_typed(2, 2), CellValue::Empty);
+        assert_eq!(sheet.get_cell_raw_content(0, 0), "\"Total\"");
+        // the cell itself holds the typed value
+        assert_eq!(sheet.cells[&(0, 0)].value, CellValue::Text("Total".into()));
+        assert_eq!(sheet.cells[&(0, 0)].number(), 0);
+
+        // numeric use of a text cell is an error
+        sheet.update_cell_formula(1, 0, "A1+1", &mut status);
+        assert_eq!(sheet.get_cell_status(1, 0), CellStatus::Error);
+        sheet.update_cell_formula(1, 1, "SUM(A1:B1)", &mut status);
+        assert_eq!(sheet.get_cell_status(1, 1), CellStatus::Error);
 
-        process_command(&mut sheet, "scroll_to Z9", &mut status);
-        assert!(status.contains("out of bounds"));
+        // turning the text back into a number heals the dependents
+        sheet.update_cell_formula(0, 0, "2", &mut status);
+        assert_eq!(sheet.get_cell_value(1, 0), 3);
+        assert_eq!(sheet.get_cell_value(1, 1), 7);
 
-        process_command(&mut sheet, "foo", &mut status);
-        assert_eq!(status, "unrecognized cmd");
+        // a quoted cell name is text, not a reference
+        sheet.update_cell_formula(2, 0, "\"A3\"", &mut status);
+        assert_eq!(status, "Ok");
+        assert_eq!(sheet.get_cell_typed(2, 0), CellValue::Text("A3".into()));
     }
 
+    #[cfg(feature = "advanced_formulas")]
     #[test]
-    fn test_clear_cache_and_history_without_feature() {
+    fn test_concat_recalculates() {
         let mut sheet = Spreadsheet::new(3, 3);
         let mut status = String::new();
+        sheet.update_cell_formula(0, 0, "\"Q\"", &mut status);
+        sheet.update_cell_formula(0, 1, "3", &mut status);
+        sheet.update_cell_formula(1, 0, "CONCAT(A1, B1, \"-\", B1*2, C3)", &mut status);
+        assert_eq!(status, "Ok");
+        assert_eq!(sheet.get_cell_typed(1, 0), CellValue::Text("Q3-6".into()));
 
-        // seed the cache
-        sheet.cache.insert(
-            "X".into(),
-            CachedRange {
-                value: 1,
-                dependencies: Default::default(),
-            },
-        );
-
-        process_command(&mut sheet, "clear_cache", &mut status);
-        assert_eq!(status, "Cache cleared");
-        assert!(sheet.cache.is_empty());
+        sheet.update_cell_formula(0, 1, "4", &mut status);
+        assert_eq!(sheet.get_cell_typed(1, 0), CellValue::Text("Q4-8".into()));
 
-        // history should say "not enabled"
-        process_command(&mut sheet, "history A1", &mut status);
-        assert_eq!(status, "Cell history feature is not enabled.");
+        // CONCAT of an error cell is an error
+        sheet.update_cell_formula(0, 1, "1/0", &mut status);
+        assert_eq!(sheet.get_cell_status(1, 0), CellStatus::Error);
     }
 
-    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
-    // 3) Undo/Redo & History (if features enabled)
-    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
-
     #[cfg(feature = "undo_state")]
     #[test]
-    fn test_undo_redo_basic() {
+    fn test_undo_restores_text_cell() {
         let mut sheet = Spreadsheet::new(2, 2);
         let mut status = String::new();
-        sheet.update_cell_formula(0, 0, "5", &mut status);
-        sheet.update_cell_formula(0, 0, "6", &mut status);
-        super::cli_app::process_command(&mut sheet, "undo", &mut status);
-        assert_eq!(sheet.get_cell_value(0, 0), 5);
-        super::cli_app::process_command(&mut sheet, "redo", &mut status);
-        assert_eq!(sheet.get_cell_value(0, 0), 6);
+        sheet.update_cell_formula(0, 0, "\"label\"", &mut status);
+        sheet.update_cell_formula(0, 0, "9", &mut status);
+        assert_eq!(sheet.get_cell_typed(0, 0), CellValue::Int(9));
+        sheet.undo(&mut status);
+        assert_eq!(sheet.get_cell_typed(0, 0), CellValue::Text("label".into()));
     }
 
-    #[cfg(feature = "cell_history")]
     #[test]
-    fn test_cell_history_feature() {
-        let mut sheet = Spreadsheet::new(2, 2);
+    fn test_recalc_reports_cycle_cells() {
+        let mut s = Spreadsheet::new(3, 3);
         let mut status = String::new();
-        sheet.update_cell_formula(0, 0, "5", &mut status);
-        sheet.update_cell_formula(0, 0, "7", &mut status);
-        super::cli_app::process_command(&mut sheet, "history A1", &mut status);
-        assert_eq!(status, "History displayed");
+        // A1=B1 and B1=A1, wired straight into the graph so the cycle is
+        // only discovered by recalc_affected
+        for (cell, formula, other) in [((0, 0), "B1", (0, 1)), ((0, 1), "A1", (0, 0))] {
+            let idx = s.store_formula(formula);
+            let c = s.get_or_create_cell(cell.0, cell.1);
+            c.formula_idx = Some(idx);
+            c.value = CellValue::Int(7);
+            c.dependencies.insert(other);
+            c.dependents.insert(other);
+        }
+        s.update_cell_formula(1, 0, "5", &mut status);
+        assert_eq!(status, "Ok");
+
+        s.dirty_cells.insert((0, 0));
+        recalc_affected(&mut s, &mut status);
+        assert_eq!(
+            status,
+            "Circular dependency detected during recalculation in A1, B1"
+        );
+        assert_eq!(s.get_cell_status(0, 0), CellStatus::Error);
+        assert_eq!(s.get_cell_status(0, 1), CellStatus::Error);
+        assert_eq!(s.get_cell_value(0, 1), 0);
+        assert_eq!(s.get_cell_value(1, 0), 5);
     }
 
-    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
-    // 4) mark_dirty & recalc + dependency graph
-    //––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––––
+    #[test]
+    fn test_recalculate_all() {
+        let mut s = Spreadsheet::new(4, 2);
+        let mut status = String::new();
+        s.update_cell_formula(0, 0, "2", &mut status);
+        s.update_cell_formula(1, 0, "A1*3", &mut status);
+        s.update_cell_formula(2, 0, "A2+A1", &mut status);
+        s.update_cell_formula(3, 0, "SUM(A1:A3)", &mut status);
+
+        // stale values left behind by an out-of-band edit
+        s.cells.get_mut(&(0, 0)).unwrap().value = CellValue::Int(5);
+        s.cells.get_mut(&(2, 0)).unwrap().value = CellValue::Int(-1);
+        assert_eq!(s.recalculate_all(&mut status), 4);
+        assert_eq!(status, "Ok");
+        assert_eq!(s.get_cell_value(0, 0), 2);
+        assert_eq!(s.get_cell_value(1, 0), 6);
+        assert_eq!(s.get_cell_value(2, 0), 8);
+        assert_eq!(s.get_cell_value(3, 0), 16);
+        assert!(s.dirty_cells.is_empty());
+
+        // a cycle smuggled into the graph is reported
+        let idx = s.store_formula("B1");
+        let b2 = s.get_or_create_cell(1, 1);
+        b2.formula_idx = Some(idx);
+        b2.dependencies.insert((0, 1));
+        b2.dependents.insert((0, 1));
+        let idx = s.store_formula("B2");
+        let b1 = s.get_or_create_cell(0, 1);
+        b1.formula_idx = Some(idx);
+        b1.dependencies.insert((1, 1));
+        b1.dependents.insert((1, 1));
+        s.recalculate_all(&mut status);
+        assert_eq!(
+            status,
+            "Circular dependency detected during recalculation in B1, B2"
+        );
+        assert_eq!(s.get_cell_value(3, 0), 16);
+    }
 
     #[test]
-    fn test_dirty_and_recalc_chain() {
-        let mut sheet = Spreadsheet::new(4, 1);
-        sheet.get_or_create_cell(0, 0).dependents.insert((1, 0));
-        sheet.get_or_create_cell(1, 0).dependents.insert((2, 0));
-        sheet.get_or_create_cell(2, 0).dependents.insert((3, 0));
+    fn test_get_dependencies_and_dependents() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut status = String::new();
+        s.update_cell_formula(2, 2, "SUM(A1:B2)", &mut status);
+        s.update_cell_formula(0, 2, "B1+A1", &mut status);
 
-        // mark dirty
-        mark_cell_and_dependents_dirty(&mut sheet, 0, 0);
-        assert!(sheet.dirty_cells.contains(&(1, 0)));
-        assert!(sheet.dirty_cells.contains(&(2, 0)));
-        assert!(!sheet.dirty_cells.contains(&(3, 0)));
+        assert_eq!(
+            s.get_dependencies(2, 2),
+            vec![(0, 0), (0, 1), (1, 0), (1, 1)]
+        );
+        assert_eq!(s.get_dependencies(0, 2), vec![(0, 0), (0, 1)]);
+        assert_eq!(s.get_dependents(0, 0), vec![(0, 2), (2, 2)]);
+        assert_eq!(s.get_dependents(1, 1), vec![(2, 2)]);
+        assert!(s.get_dependencies(1, 2).is_empty());
+        assert!(s.get_dependents(2, 2).is_empty());
+    }
 
-        // recalc with a simple chain of formulas
+    #[test]
+    fn test_trace_precedents() {
+        let mut s = Spreadsheet::new(4, 4);
         let mut status = String::new();
-        sheet.update_cell_formula(0, 0, "1", &mut status);
-        sheet.update_cell_formula(1, 0, "A1+1", &mut status);
-        sheet.update_cell_formula(2, 0, "A2+1", &mut status);
-        sheet.dirty_cells.insert((0, 0));
-        recalc_affected(&mut sheet, &mut status);
-        assert_eq!(sheet.get_cell_value(2, 0), 3);
-        assert!(sheet.dirty_cells.is_empty());
+        s.update_cell_formula(0, 1, "A1+1", &mut status);
+        s.update_cell_formula(1, 1, "B1*2", &mut status);
+        s.update_cell_formula(2, 1, "MAX(B2:C2)", &mut status);
+
+        let want: HashSet<(i32, i32)> = [(1, 1), (1, 2), (0, 1), (0, 0)].into_iter().collect();
+        assert_eq!(s.trace_precedents(2, 1), want);
+        assert_eq!(s.trace_precedents(0, 1), [(0, 0)].into_iter().collect());
+        assert!(s.trace_precedents(3, 3).is_empty());
+
+        // a cycle in the graph still terminates and excludes the start
+        s.get_or_create_cell(0, 0).dependencies.insert((2, 1));
+        let traced = s.trace_precedents(2, 1);
+        assert!(!traced.contains(&(2, 1)));
+        assert_eq!(traced.len(), 4);
     }
 
-    use super::*;
-    // 2) pull in your CLI layer so `process_command` is visible:
-    use crate::cli_app;
+    #[test]
+    fn test_recalc_uses_cached_asts() {
+        let mut s = Spreadsheet::new(4, 2);
+        let mut status = String::new();
+        s.update_cell_formula(0, 0, "3", &mut status);
+        s.update_cell_formula(1, 0, "A1*2", &mut status);
+        s.update_cell_formula(2, 0, "SUM(A1:A2)", &mut status);
+        s.update_cell_formula(3, 0, "A1*2", &mut status);
+        s.update_cell_formula(0, 1, "\"note\"", &mut status);
+
+        // simple formulas get an AST; identical strings share it
+        let idx = s.cells[&(1, 0)].formula_idx.unwrap();
+        assert_eq!(s.cells[&(3, 0)].formula_idx, Some(idx));
+        assert!(s.formula_asts[idx].is_some());
+        // text can't be expressed as an AST and falls back to the string parser
+        let text_idx = s.cells[&(0, 1)].formula_idx.unwrap();
+        assert!(s.formula_asts[text_idx].is_none());
 
-    // ────────────────────────────────────────────────────────────────────────────────
-    // expose private parser functions & types to our #[cfg(test)] module
-    #[cfg(test)]
-    pub(crate) mod test_exports {
-        pub use crate::parser::{
-            clear_range_cache, evaluate_ast, evaluate_large_range, evaluate_range_function,
-            invalidate_cache_for_cell, parse_expr, parse_factor, parse_term, ASTNode, RANGE_CACHE,
-        };
+        s.update_cell_formula(0, 0, "5", &mut status);
+        assert_eq!(s.get_cell_value(1, 0), 10);
+        assert_eq!(s.get_cell_value(2, 0), 15);
+        assert_eq!(s.get_cell_value(3, 0), 10);
+
+        // overflow through the AST path is a runtime error, not a panic
+        s.update_cell_formula(0, 0, "2000000000", &mut status);
+        assert_eq!(s.get_cell_status(1, 0), CellStatus::Error);
     }
 
-    #[cfg(test)]
-    mod parser_internal_tests {
-        use super::test_exports::*;
-        use crate::sheet::{CellStatus, CloneableSheet, Spreadsheet};
+    #[cfg(feature = "advanced_formulas")]
+    #[test]
+    fn test_sumproduct_in_sheet() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        assert!(!validates(&s, "SUMPRODUCT(A1:A2)"));
+        assert_eq!(valid_formula(&s, "SUMPRODUCT(A1:A2)", &mut msg), 1);
+        assert!(msg.contains("SUMPRODUCT needs 2 args"));
+
+        s.update_cell_formula(0, 0, "2", &mut msg);
+        s.update_cell_formula(1, 0, "3", &mut msg);
+        s.update_cell_formula(0, 1, "4", &mut msg);
+        s.update_cell_formula(1, 1, "5", &mut msg);
+        s.update_cell_formula(2, 2, "SUMPRODUCT(A1:A2,B1:B2)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(2, 2), 23);
+        s.update_cell_formula(1, 1, "6", &mut msg);
+        assert_eq!(s.get_cell_value(2, 2), 26);
+    }
 
-        #[test]
-        fn test_skip_spaces_and_number_parsing() {
-            let sheet = Spreadsheet::new(1, 1);
-            let cs = CloneableSheet::new(&*sheet);
-            let mut s = "   -123abc";
-            let mut err = 0;
-            let v = parse_factor(&cs, &mut s, 0, 0, &mut err);
-            assert_eq!(v, -123);
-            assert_eq!(err, 0);
-        }
+    #[test]
+    fn test_abs_and_sign_in_sheet() {
+        let mut s = Spreadsheet::new(2, 2);
+        let mut msg = String::new();
+        assert!(validates(&s, "ABS(A1-3)"));
+        assert!(!validates(&s, "SIGN(Z9)"));
+        assert_eq!(valid_formula(&s, "ABS(A1-3)", &mut msg), 0);
+        assert_eq!(valid_formula(&s, "SIGN(Z9)", &mut msg), 1);
+        assert_eq!(valid_formula(&s, "ABS(A1", &mut msg), 1);
+
+        s.update_cell_formula(0, 0, "-4", &mut msg);
+        s.update_cell_formula(0, 1, "ABS(A1)", &mut msg);
+        s.update_cell_formula(1, 1, "SIGN(A1)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(0, 1), 4);
+        assert_eq!(s.get_cell_value(1, 1), -1);
+        s.update_cell_formula(0, 0, "9", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 9);
+        assert_eq!(s.get_cell_value(1, 1), 1);
+    }
 
-        #[test]
-        fn test_parse_comparisons_and_binary() {
-            let sheet = Spreadsheet::new(1, 1);
-            let cs = CloneableSheet::new(&*sheet);
-            let mut err = 0;
+    /// IFERROR recovers from errors in cells it reads, including ones that
+    /// turn into errors after the IFERROR cell was entered.
+    #[cfg(feature = "advanced_formulas")]
+    #[test]
+    fn test_iferror_in_sheet() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        assert!(!validates(&s, "IFERROR(A1)"));
+        assert_eq!(valid_formula(&s, "IFERROR(A1)", &mut msg), 1);
+        assert!(msg.contains("IFERROR needs 2 args"));
+        msg.clear();
+        assert!(validates(&s, "IFERROR(MOD(A1,2),-1)"));
+        assert_eq!(valid_formula(&s, "IFERROR(MOD(A1,2),-1)", &mut msg), 0);
 
-            let mut s1 = "2>1";
-            assert_eq!(parse_expr(&cs, &mut s1, 0, 0, &mut err), 1);
+        s.update_cell_formula(0, 0, "6", &mut msg);
+        s.update_cell_formula(0, 1, "0", &mut msg);
+        s.update_cell_formula(0, 2, "IFERROR(A1/B1,-1)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 2), -1);
+        assert_eq!(s.get_cell_status(0, 2), CellStatus::Ok);
+
+        s.update_cell_formula(1, 0, "IFERROR(C2,0)", &mut msg);
+        s.update_cell_formula(1, 1, "C2+1", &mut msg);
+        s.update_cell_formula(1, 2, "A1/B1", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_status(1, 2), CellStatus::Error);
+        assert_eq!(s.get_cell_status(1, 0), CellStatus::Ok);
+        assert_eq!(s.get_cell_value(1, 0), 0);
+        assert_eq!(s.get_cell_status(1, 1), CellStatus::Error);
+
+        s.update_cell_formula(0, 1, "3", &mut msg);
+        assert_eq!(s.get_cell_value(1, 2), 2);
+        assert_eq!(s.get_cell_value(1, 0), 2);
+        assert_eq!(s.get_cell_value(1, 1), 3);
+    }
 
-            let mut s2 = "5<=3";
-            assert_eq!(parse_expr(&cs, &mut s2, 0, 0, &mut err), 0);
+    #[cfg(feature = "advanced_formulas")]
+    #[test]
+    fn test_boolean_functions_in_sheet() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        assert!(!validates(&s, "NOT(A1,B1)"));
+        assert_eq!(valid_formula(&s, "NOT(A1,B1)", &mut msg), 1);
+        assert!(msg.contains("NOT needs 1 arg"));
+        msg.clear();
+        assert!(!validates(&s, "AND(A1,)"));
+        assert_eq!(valid_formula(&s, "AND(A1,)", &mut msg), 1);
+        msg.clear();
+        assert!(validates(&s, "IF(AND(A1,B1),1,0)"));
+        assert_eq!(valid_formula(&s, "IF(AND(A1,B1),1,0)", &mut msg), 0);
+
+        s.update_cell_formula(0, 0, "4", &mut msg);
+        s.update_cell_formula(0, 1, "OR(A1>5,A1==4)", &mut msg);
+        s.update_cell_formula(0, 2, "IF(AND(A1,B1),7,8)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 1);
+        assert_eq!(s.get_cell_value(0, 2), 7);
+        s.update_cell_formula(0, 0, "0", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 0);
+        assert_eq!(s.get_cell_value(0, 2), 8);
+    }
 
-            let mut s3 = "4==4";
-            assert_eq!(parse_expr(&cs, &mut s3, 0, 0, &mut err), 1);
+    #[test]
+    fn test_shift_formula_refs() {
+        assert_eq!(shift_formula_refs("A1+B1", 1, 0).as_deref(), Some("A2+B2"));
+        assert_eq!(
+            shift_formula_refs("SUM(A1:B3)", 2, 1).as_deref(),
+            Some("SUM(B3:C5)")
+        );
+        assert_eq!(
+            shift_formula_refs("MAX(1:3)", 1, 5).as_deref(),
+            Some("MAX(2:4)")
+        );
+        assert_eq!(
+            shift_formula_refs("Z9*10", -1, 1).as_deref(),
+            Some("AA8*10")
+        );
+        assert_eq!(
+            shift_formula_refs("CONCAT(\"A1\",B2)", 1, 1).as_deref(),
+            Some("CONCAT(\"A1\",C3)")
+        );
+        assert_eq!(shift_formula_refs("A1+1", -1, 0), None);
+        assert_eq!(shift_formula_refs("SUM(1:2)", -1, 0), None);
+        assert_eq!(
+            shift_formula_refs("SUM(A:B)+MAX(Z:Z)", 3, 1).as_deref(),
+            Some("SUM(B:C)+MAX(AA:AA)")
+        );
+        assert_eq!(shift_formula_refs("SUM(A:B)", 0, -1), None);
+    }
 
-            let mut s4 = "3+2*4-5/5";
-            assert_eq!(parse_expr(&cs, &mut s4, 0, 0, &mut err), 10);
+    #[test]
+    fn test_copy_and_paste_cell() {
+        let mut s = Spreadsheet::new(5, 5);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "1", &mut msg);
+        s.update_cell_formula(1, 0, "2", &mut msg);
+        s.update_cell_formula(0, 1, "10", &mut msg);
+        s.update_cell_formula(1, 1, "20", &mut msg);
+        s.update_cell_formula(0, 2, "A1+B1", &mut msg);
+
+        let copied = s.copy_cell(0, 2);
+        assert_eq!(copied.formula, "A1+B1");
+        s.paste_cell(1, 2, &copied, &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_formula(1, 2).as_deref(), Some("A2+B2"));
+        assert_eq!(s.get_cell_value(1, 2), 22);
+
+        // dependencies follow the rewritten formula
+        s.update_cell_formula(1, 0, "5", &mut msg);
+        assert_eq!(s.get_cell_value(1, 2), 25);
+
+        s.paste_cell(0, 0, &copied, &mut msg);
+        assert_eq!(msg, "Cell out of bounds");
+        s.paste_cell(5, 2, &copied, &mut msg);
+        assert_eq!(msg, "Cell out of bounds");
+
+        s.update_cell_value(3, 3, 7, CellStatus::Ok);
+        let value = s.copy_cell(3, 3);
+        assert_eq!(value.formula, "7");
+        s.paste_cell(4, 4, &value, &mut msg);
+        assert_eq!(s.get_cell_value(4, 4), 7);
+
+        let empty = s.copy_cell(2, 4);
+        s.paste_cell(4, 4, &empty, &mut msg);
+        assert_eq!(s.get_cell_typed(4, 4), CellValue::Empty);
+    }
+
+    #[test]
+    fn test_fill_down() {
+        let mut s = Spreadsheet::new(6, 3);
+        let mut msg = String::new();
+        for r in 0..5 {
+            s.update_cell_value(r, 0, r + 1, CellStatus::Ok);
         }
+        s.update_cell_formula(0, 1, "A1*2", &mut msg);
 
-        #[test]
-        fn test_evaluate_range_function_errors_and_success() {
-            let mut sheet = Spreadsheet::new(2, 2);
-            let cs = CloneableSheet::new(&*sheet);
-            let mut err = 0;
+        assert_eq!(s.fill_down(0, 4, 1, &mut msg), 4);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_formula(4, 1).as_deref(), Some("A5*2"));
+        assert_eq!(
+            s.get_range_values(0, 1, 4, 1),
+            vec![vec![2], vec![4], vec![6], vec![8], vec![10]]
+        );
 
-            // syntax error
-            assert_eq!(evaluate_range_function(&cs, "SUM", "A1B2", &mut err), 0);
-            assert_eq!(err, 1);
+        // a running total: each row reads the one above it
+        s.update_cell_formula(0, 2, "A1", &mut msg);
+        s.update_cell_formula(1, 2, "C1+A2", &mut msg);
+        assert_eq!(s.fill_down(1, 4, 2, &mut msg), 3);
+        assert_eq!(s.get_cell_value(4, 2), 15);
 
-            // out‐of‐bounds
-            err = 0;
-            assert_eq!(evaluate_range_function(&cs, "SUM", "A1:C1", &mut err), 0);
-            assert_eq!(err, 4);
+        assert_eq!(s.fill_down(3, 2, 1, &mut msg), 0);
+        assert_eq!(msg, "Invalid range");
+        assert_eq!(s.fill_down(0, 6, 1, &mut msg), 0);
+        assert_eq!(msg, "Cell out of bounds");
+    }
 
-            // valid
-            sheet.update_cell_value(0, 0, 1, CellStatus::Ok);
-            sheet.update_cell_value(0, 1, 2, CellStatus::Ok);
-            let cs2 = CloneableSheet::new(&*sheet);
-            err = 0;
-            assert_eq!(evaluate_range_function(&cs2, "SUM", "A1:B1", &mut err), 3);
-        }
+    #[test]
+    fn test_insert_row_shifts_references() {
+        let mut s = Spreadsheet::new(5, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "1", &mut msg);
+        s.update_cell_formula(1, 0, "2", &mut msg);
+        s.update_cell_formula(2, 0, "3", &mut msg);
+        s.update_cell_formula(0, 1, "SUM(A1:A3)", &mut msg);
+        s.update_cell_formula(2, 1, "A3*10", &mut msg);
+
+        s.insert_row(1);
+        assert_eq!(s.total_rows, 6);
+        assert_eq!(s.get_formula(0, 1).as_deref(), Some("SUM(A1:A4)"));
+        assert_eq!(s.get_formula(3, 1).as_deref(), Some("A4*10"));
+        assert_eq!(s.get_cell_value(3, 1), 30);
+        assert_eq!(s.get_cell_typed(1, 0), CellValue::Empty);
+
+        // the grown range picks up values entered in the new row
+        s.update_cell_formula(1, 0, "100", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 106);
+        s.update_cell_formula(3, 0, "4", &mut msg);
+        assert_eq!(s.get_cell_value(3, 1), 40);
+    }
 
-        #[test]
-        fn test_clear_and_invalidate_cache_for_cell() {
-            clear_range_cache();
-            // manually inject into the RANGE_CACHE:
-            RANGE_CACHE.with(|c| {
-                c.borrow_mut()
-                    .insert("X".into(), (5, [(0, 0)].iter().cloned().collect()));
-            });
-            invalidate_cache_for_cell(0, 0);
-            RANGE_CACHE.with(|c| assert!(c.borrow().is_empty()));
+    #[test]
+    fn test_delete_row_shifts_and_breaks_references() {
+        let mut s = Spreadsheet::new(5, 3);
+        let mut msg = String::new();
+        for r in 0..4 {
+            s.update_cell_formula(r, 0, &(r + 1).to_string(), &mut msg);
         }
+        s.update_cell_formula(0, 1, "SUM(A1:A4)", &mut msg);
+        s.update_cell_formula(3, 1, "A4+1", &mut msg);
+        s.update_cell_formula(4, 1, "A2*2", &mut msg);
+        s.update_cell_formula(4, 2, "B5+1", &mut msg);
+
+        s.delete_row(1);
+        assert_eq!(s.total_rows, 4);
+        assert_eq!(s.get_formula(0, 1).as_deref(), Some("SUM(A1:A3)"));
+        assert_eq!(s.get_cell_value(0, 1), 8);
+        assert_eq!(s.get_formula(2, 1).as_deref(), Some("A3+1"));
+        assert_eq!(s.get_cell_value(2, 1), 5);
+
+        // B5 read the deleted A2: #REF, and C5 (now C4) inherits the error
+        assert_eq!(s.get_formula(3, 1), None);
+        assert_eq!(s.get_cell_status(3, 1), CellStatus::Error);
+        assert_eq!(s.get_formula(3, 2).as_deref(), Some("B4+1"));
+        assert_eq!(s.get_cell_status(3, 2), CellStatus::Error);
+
+        s.update_cell_formula(2, 0, "10", &mut msg);
+        assert_eq!(s.get_cell_value(2, 1), 11);
+        assert_eq!(s.get_cell_value(0, 1), 14);
+
+        s.delete_row(9);
+        assert_eq!(s.total_rows, 4);
+
+        // the last remaining row stays
+        let mut s = Spreadsheet::new(1, 2);
+        s.update_cell_formula(0, 0, "3", &mut msg);
+        s.update_cell_formula(0, 1, "A1*2", &mut msg);
+        s.delete_row(0);
+        assert_eq!(s.total_rows, 1);
+        assert_eq!(s.get_cell_value(0, 1), 6);
+    }
 
-        #[test]
-        fn test_evaluate_ast_literals_and_sleep() {
-            let sheet = Spreadsheet::new(1, 1);
-            let cs = CloneableSheet::new(&*sheet);
-            let mut err = 0;
-
-            let lit = ASTNode::Literal(7);
-            assert_eq!(evaluate_ast(&cs, &lit, 0, 0, &mut err), 7);
+    /// Row changes rebuild dependencies without reading cell names inside
+    /// string literals
+    #[cfg(feature = "advanced_formulas")]
+    #[test]
+    fn row_changes_ignore_quoted_references() {
+        let mut s = Spreadsheet::new(5, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "CONCAT(\"B1\",\"x\")", &mut msg);
+        assert_eq!(msg, "Ok");
+        s.insert_row(4);
+        assert!(s.get_dependencies(0, 0).is_empty());
+        assert!(s.get_dependents(0, 1).is_empty());
+    }
 
-            let sf = ASTNode::SleepFunction(Box::new(ASTNode::Literal(-2)));
-            assert_eq!(evaluate_ast(&cs, &sf, 0, 0, &mut err), -2);
+    #[test]
+    fn named_ranges_follow_row_changes() {
+        let mut s = Spreadsheet::new(6, 3);
+        let mut msg = String::new();
+        for (row, v) in [1, 2, 3].into_iter().enumerate() {
+            s.update_cell_formula(row as i32, 0, &v.to_string(), &mut msg);
         }
+        s.define_name("Rev", "A1:A2", &mut msg);
+        s.define_name("Third", "A3:A3", &mut msg);
+        s.update_cell_formula(5, 1, "SUM(Rev)", &mut msg);
+        s.update_cell_formula(5, 2, "SUM(Third)", &mut msg);
+
+        s.insert_row(0);
+        assert_eq!(s.named_ranges.get("Rev").copied(), Some((1, 0, 2, 0)));
+        assert_eq!(s.get_cell_value(6, 1), 3);
+        s.update_cell_formula(1, 0, "10", &mut msg);
+        assert_eq!(s.get_cell_value(6, 1), 12);
+
+        // a row inside the range shrinks it; deleting all of it drops the name
+        s.delete_row(2);
+        assert_eq!(s.named_ranges.get("Rev").copied(), Some((1, 0, 1, 0)));
+        assert_eq!(s.get_cell_value(5, 1), 10);
+        s.delete_row(2);
+        assert_eq!(s.named_ranges.get("Third").copied(), None);
+        assert_eq!(s.get_formula(4, 2), None);
+        assert_eq!(s.get_cell_status(4, 2), CellStatus::Error);
     }
 
     #[test]
-    fn test_extract_dependencies_without_self_simple_range() {
-        // A1:B2 should give (0,0),(0,1),(1,0),(1,1)
-        let deps = extract_dependencies_without_self("A1:B2", 10, 10);
-        let mut want = std::collections::HashSet::new();
-        want.insert((0, 0));
-        want.insert((0, 1));
-        want.insert((1, 0));
-        want.insert((1, 1));
-        assert_eq!(deps, want);
+    fn test_builder_defaults_match_new() {
+        let built = Spreadsheet::builder(4, 6).build();
+        let plain = Spreadsheet::new(4, 6);
+        assert_eq!((built.total_rows, built.total_cols), (4, 6));
+        assert_eq!(built.output_enabled, plain.output_enabled);
+        #[cfg(feature = "cell_history")]
+        assert_eq!(built.history_limit, plain.history_limit);
+        #[cfg(feature = "undo_state")]
+        assert_eq!(built.undo_limit, plain.undo_limit);
     }
 
     #[test]
-    fn test_extract_dependencies_without_self_reversed_range() {
-        // Even if the bounds are reversed, it should normalize
-        let deps = extract_dependencies_without_self("B2:A1", 10, 10);
-        let mut want = std::collections::HashSet::new();
-        want.insert((0, 0));
-        want.insert((0, 1));
-        want.insert((1, 0));
-        want.insert((1, 1));
-        assert_eq!(deps, want);
+    fn test_builder_applies_options() {
+        let builder = Spreadsheet::builder(4, 6)
+            .dimensions(3, 2)
+            .output_enabled(false);
+        #[cfg(feature = "cell_history")]
+        let builder = builder.history_limit(3);
+        #[cfg(feature = "undo_state")]
+        let builder = builder.undo_limit(7);
+        let sheet = builder.build();
+        assert_eq!((sheet.total_rows, sheet.total_cols), (3, 2));
+        assert!(!sheet.output_enabled);
+        #[cfg(feature = "cell_history")]
+        assert_eq!(sheet.history_limit, 3);
+        #[cfg(feature = "undo_state")]
+        assert_eq!(sheet.undo_limit, 7);
     }
 
     #[test]
-    fn test_extract_dependencies_without_self_multi_letter_cols() {
-        // AA1:AB2 maps to rows 0–1, cols 26→27 (0‐based 26 and 27)
-        let deps = extract_dependencies_without_self("AA1:AB2", 100, 100);
-        let mut want = std::collections::HashSet::new();
-        for r in 0..=1 {
-            for c in 26..=27 {
-                want.insert((r, c));
-            }
-        }
-        assert_eq!(deps, want);
+    fn test_get_cell_display() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "0", &mut msg);
+        s.update_cell_formula(0, 1, "A1-5", &mut msg);
+        s.update_cell_formula(0, 2, "B1/A1", &mut msg);
+        s.update_cell_formula(1, 0, "\"hi\"", &mut msg);
+        assert_eq!(s.get_cell_display(0, 0), "0");
+        assert_eq!(s.get_cell_display(0, 1), "-5");
+        assert_eq!(s.get_cell_display(0, 2), "ERR");
+        assert_eq!(s.get_cell_display(1, 0), "hi");
+        assert_eq!(s.get_cell_display(2, 2), "");
     }
 
     #[test]
-    fn test_extract_dependencies_without_self_single_cell() {
-        // Single cell reference
-        let deps = extract_dependencies_without_self("C3", 5, 5);
-        let mut want = std::collections::HashSet::new();
-        want.insert((2, 2));
-        assert_eq!(deps, want);
+    fn cells_in_cycle_reports_cycle_and_downstream() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut status = String::new();
+        s.update_cell_formula(0, 0, "B1+1", &mut status);
+        s.update_cell_formula(0, 1, "7", &mut status);
+        s.update_cell_formula(0, 2, "A1*2", &mut status);
+        s.update_cell_formula(1, 0, "C3+1", &mut status);
+        assert!(s.cells_in_cycle().is_empty());
+
+        // Close A1 -> B1 -> A1 behind the validator's back
+        let idx = s.store_formula("A1+1");
+        let b1 = s.get_or_create_cell(0, 1);
+        b1.formula_idx = Some(idx);
+        b1.dependencies.insert((0, 0));
+        s.get_or_create_cell(0, 0).dependents.insert((0, 1));
+
+        // C1 reads the cycle; A2 does not
+        assert_eq!(s.cells_in_cycle(), vec![(0, 0), (0, 1), (0, 2)]);
+
+        // a dependents entry with no matching dependency doesn't count
+        let mut s = Spreadsheet::new(2, 2);
+        s.update_cell_formula(0, 0, "2+3", &mut status);
+        s.update_cell_formula(0, 1, "A1+1", &mut status);
+        s.update_cell_formula(1, 1, "B1*2", &mut status);
+        s.get_or_create_cell(0, 1).dependents.insert((0, 0));
+        assert!(s.cells_in_cycle().is_empty());
     }
 
     #[test]
-    fn test_extract_dependencies_without_self_out_of_bounds() {
-        // Z1 is col 25, but total_cols=5 → out of bounds, so skip
-        let deps = extract_dependencies_without_self("Z1:Z1", 5, 5);
-        assert!(deps.is_empty());
+    fn get_cell_ast_exposes_parsed_formula() {
+        use crate::parser::ASTNode;
+        let mut s = Spreadsheet::new(3, 2);
+        let mut status = String::new();
+        s.update_cell_formula(0, 0, "B1*2", &mut status);
+        s.update_cell_formula(1, 0, "SLEEP(0)", &mut status);
+        s.update_cell_formula(2, 0, "\"note\"", &mut status);
+
+        match s.get_cell_ast(0, 0) {
+            Some(ASTNode::BinaryOp('*', lhs, rhs)) => {
+                assert!(matches!(*lhs, ASTNode::CellRef(0, 1)));
+                assert!(matches!(*rhs, ASTNode::Literal(2)));
+            }
+            other => panic!("unexpected AST {:?}", other),
+        }
+        assert!(matches!(
+            s.get_cell_ast(1, 0),
+            Some(ASTNode::SleepFunction(_))
+        ));
+        // text formulas have no AST; empty cells have no formula at all
+        assert!(s.get_cell_ast(2, 0).is_none());
+        assert!(s.get_cell_ast(0, 1).is_none());
     }
 
-    // Add to the `#[cfg(test)] mod tests { ... }` in src/sheet.rs
-
     #[test]
-    fn valid_formula_range_errors() {
-        let mut sheet = Spreadsheet::new(5, 5);
-        let mut msg = String::new();
-
-        // Missing colon in range
-        assert_eq!(valid_formula(&sheet, "SUM(A1A2)", &mut msg), 1);
-        assert!(msg.contains("Missing colon in range"));
+    fn get_cell_distinguishes_empty_and_off_grid() {
+        let mut s = Spreadsheet::new(2, 2);
+        let mut status = String::new();
+        s.update_cell_formula(1, 1, "7", &mut status);
 
-        // Second cell reference out of bounds
-        msg.clear();
-        assert_eq!(valid_formula(&sheet, "SUM(A1:Z10)", &mut msg), 1);
-        assert!(msg.contains("Second cell reference out of bounds"));
+        assert!(s.is_in_bounds(0, 0) && s.is_in_bounds(1, 1));
+        assert!(!s.is_in_bounds(2, 0) && !s.is_in_bounds(0, -1));
 
-        // Invalid range order
-        msg.clear();
-        assert_eq!(valid_formula(&sheet, "SUM(B2:A1)", &mut msg), 1);
-        assert!(msg.contains("Invalid range order"));
+        assert_eq!(s.get_cell(1, 1).map(Cell::number), Some(7));
+        // never written, but on the grid
+        assert!(s.get_cell(0, 0).is_none());
+        // off the grid
+        assert!(s.get_cell(2, 1).is_none());
+        assert!(s.get_cell(-1, 0).is_none());
     }
 
-    fn valid_formula_operator_not_found() {
-        let sheet = Spreadsheet::new(3, 3);
-        let mut msg = String::new();
-        let code = valid_formula(&sheet, "XYZ", &mut msg);
-        assert_eq!(code, 1);
-        assert_eq!(msg, "Operator not found");
+    #[test]
+    fn populated_count_and_sparsity() {
+        let mut s = Spreadsheet::new(10, 10);
+        let mut status = String::new();
+        assert_eq!(s.populated_cell_count(), 0);
+        assert_eq!(s.sparsity(), 0.0);
+
+        s.update_cell_formula(0, 0, "1", &mut status);
+        s.update_cell_formula(9, 9, "A1+1", &mut status);
+        assert_eq!(s.populated_cell_count(), 2);
+        assert!((s.sparsity() - 0.02).abs() < 1e-12);
+
+        assert_eq!(Spreadsheet::new(0, 5).sparsity(), 0.0);
     }
 
-    #[test]
-    fn valid_formula_sleep_variants() {
-        let sheet = Spreadsheet::new(2, 2);
-        let mut msg = String::new();
-        // Missing closing parenthesis
-        assert_eq!(valid_formula(&sheet, "SLEEP(1", &mut msg), 1);
-        assert_eq!(msg, "Missing closing parenthesis in SLEEP");
-        // Integer argument
-        msg.clear();
-        let code_int = valid_formula(&sheet, "SLEEP(5)", &mut msg);
-        assert_eq!(code_int, 0);
-        assert_eq!(msg, "");
-        // Valid cell reference
-        msg.clear();
-        let code_ref = valid_formula(&sheet, "SLEEP(A1)", &mut msg);
-        assert_eq!(code_ref, 0);
-        // Out-of-bounds cell reference
-        msg.clear();
-        let code_oob = valid_formula(&sheet, "SLEEP(Z9)", &mut msg);
-        assert_eq!(code_oob, 1);
-        assert_eq!(msg, "Cell reference in up out of bounds");
+    #[cfg(feature = "advanced_formulas")]
+    #[test]
+    fn test_averageif_in_sheet() {
+        let mut s = Spreadsheet::new(3, 2);
+        let mut msg = String::new();
+        assert!(!validates(&s, "AVERAGEIF(A1:A3)"));
+        assert_eq!(valid_formula(&s, "AVERAGEIF(A1:A3)", &mut msg), 1);
+        assert!(msg.contains("AVERAGEIF needs 2 args"));
+
+        s.update_cell_formula(0, 0, "2", &mut msg);
+        s.update_cell_formula(1, 0, "10", &mut msg);
+        s.update_cell_formula(2, 0, "20", &mut msg);
+        s.update_cell_formula(0, 1, "AVERAGEIF(A1:A3,\">=10\")", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(0, 1), 15);
+
+        s.update_cell_formula(2, 0, "12", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 11);
+
+        // a comma inside the criterion doesn't split the arguments
+        assert!(validates(&s, "AVERAGEIF(A1:A3,\">\"&MOD(A3,10))"));
+        s.update_cell_formula(1, 1, "AVERAGEIF(A1:A3,\">\"&MOD(A3,10))", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(1, 1), 11);
     }
 
-    fn recalc_detects_div_zero_and_marks_error() {
-        let mut sheet = Spreadsheet::new(2, 1);
+    #[test]
+    fn mark_dirty_and_recalc_dirty() {
+        let mut s = Spreadsheet::new(3, 1);
         let mut status = String::new();
-        // A1 = 2
-        sheet.update_cell_formula(0, 0, "2", &mut status);
-        // A2 = A1/0 → division by zero
-        sheet.update_cell_formula(1, 0, "A1/0", &mut status);
-        sheet.dirty_cells.insert((1, 0));
-        recalc_affected(&mut sheet, &mut status);
-        assert_eq!(sheet.get_cell_status(1, 0), CellStatus::Error);
-        assert_eq!(sheet.get_cell_value(1, 0), 0);
+        s.update_cell_value(0, 0, 1, CellStatus::Ok);
+        s.update_cell_formula(1, 0, "A1*10", &mut status);
+        s.update_cell_formula(2, 0, "SUM(A1:A2)", &mut status);
+        assert!(s.pending_recalc().is_empty());
+
+        // change A1 through a side channel, then ask for a recalculation
+        s.get_or_create_cell(0, 0).value = CellValue::Int(2);
+        s.mark_dirty(0, 0);
+        assert_eq!(s.pending_recalc(), vec![(0, 0)]);
+
+        assert_eq!(s.recalc_dirty(&mut status), 2);
+        assert_eq!(status, "Ok");
+        assert!(s.pending_recalc().is_empty());
+        assert_eq!(s.get_cell_value(1, 0), 20);
+        assert_eq!(s.get_cell_value(2, 0), 22);
     }
 
-    fn inject_formula(sheet: &mut Spreadsheet, formula: &'static str) {
-        // Push into storage and point A1 at it
-        let idx = sheet.formula_storage.len();
-        sheet.formula_storage.push(formula.into());
-        let cell = sheet.get_or_create_cell(0, 0);
-        cell.formula_idx = Some(idx);
-        // clear any old dependencies & dependents
-        cell.dependencies.clear();
-        cell.dependents.clear();
-        sheet.dirty_cells.insert((0, 0));
+    #[test]
+    fn exponent_literals_in_sheet() {
+        let mut s = Spreadsheet::new(6, 6);
+        let mut msg = String::new();
+        assert!(validates(&s, "1e3"));
+        assert!(validates(&s, "2e+3*A1"));
+        assert!(validates(&s, "1_000-A1"));
+        assert!(!validates(&s, "1e"));
+        assert_eq!(valid_formula(&s, "1e3", &mut msg), 0);
+        assert_eq!(valid_formula(&s, "2e+3*A1", &mut msg), 0);
+        assert_eq!(valid_formula(&s, "1_000-A1", &mut msg), 0);
+        assert_eq!(valid_formula(&s, "1e", &mut msg), 1);
+
+        // `e6` is not a reference to E6, so this is not a cycle
+        s.update_cell_formula(5, 4, "1e6", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(5, 4), 1_000_000);
+        assert!(s.get_dependencies(5, 4).is_empty());
+        s.update_cell_formula(0, 0, "2e+3+E6", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_dependencies(0, 0), vec![(5, 4)]);
     }
+
     #[test]
-    fn recalc_detects_reversed_range_as_invalid_range() {
-        let mut sheet = Spreadsheet::new(1, 1);
-        let mut status = String::new();
+    fn named_ranges_resolve_and_track_redefinition() {
+        let mut s = Spreadsheet::new(5, 5);
+        let mut msg = String::new();
+        for (row, v) in [1, 2, 3, 4].into_iter().enumerate() {
+            s.update_cell_value(row as i32, 1, v, CellStatus::Ok);
+        }
+        s.define_name("Revenue", "B1:B2", &mut msg);
+        assert_eq!(msg, "Ok");
+        s.update_cell_formula(0, 0, "SUM(Revenue)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell(0, 0).unwrap().number(), 3);
+
+        // edits inside the named range flow through
+        s.update_cell_formula(1, 1, "10", &mut msg);
+        assert_eq!(s.get_cell(0, 0).unwrap().number(), 11);
+
+        // redefinition recomputes users and moves their dependencies
+        s.define_name("Revenue", "B3:B4", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell(0, 0).unwrap().number(), 7);
+        s.update_cell_formula(0, 1, "100", &mut msg);
+        assert_eq!(s.get_cell(0, 0).unwrap().number(), 7);
+
+        // a range covering a user would be circular, so the old one stays
+        s.define_name("Revenue", "A1:B4", &mut msg);
+        assert!(msg.starts_with("Circular"));
+        assert_eq!(s.named_ranges["Revenue"], (2, 1, 3, 1));
+
+        for (name, range, err) in [
+            ("B2", "A1:A2", "Invalid name"),
+            ("1st", "A1:A2", "Invalid name"),
+            ("SUM", "A1:A2", "Invalid name"),
+            ("max", "A1:A2", "Invalid name"),
+            ("Cost", "A1", "Invalid range"),
+            ("Cost", "A1:Z9", "Range out of bounds"),
+        ] {
+            s.define_name(name, range, &mut msg);
+            assert_eq!(msg, err, "{name} {range}");
+        }
+        s.update_cell_formula(4, 4, "SUM(Cost)", &mut msg);
+        assert_eq!(msg, "Unrecognized");
+
+        // only a bare word is a name: a call of the same name is left alone
+        s.named_ranges.insert("MAX".to_string(), (0, 0, 0, 0));
+        s.update_cell_formula(4, 4, "MAX(B3:B4)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(4, 4), 4);
+    }
 
-        // SUM(A2:A1) is syntactically valid, but 2 > 1 should trigger error_flag=2
-        inject_formula(&mut sheet, "SUM(A2:A1)");
-        status.clear();
-        recalc_affected(&mut sheet, &mut status);
-        assert_eq!(status, "Invalid range");
+    /// recalc_affected: a diamond (D=B+C, B=A, C=A) settles in one edit
+    #[test]
+    fn recalc_affected_diamond_settles_in_one_pass() {
+        let mut s = Spreadsheet::new(4, 1);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "1", &mut msg);
+        s.update_cell_formula(1, 0, "A1", &mut msg);
+        s.update_cell_formula(2, 0, "A1*10", &mut msg);
+        s.update_cell_formula(3, 0, "A2+A3", &mut msg);
+        assert_eq!(s.get_cell(3, 0).unwrap().number(), 11);
+
+        s.update_cell_formula(0, 0, "2", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell(3, 0).unwrap().number(), 22);
+
+        // a long arm must also be finished before the join is evaluated
+        s.update_cell_formula(2, 0, "A2*10", &mut msg);
+        s.update_cell_formula(0, 0, "3", &mut msg);
+        assert_eq!(s.get_cell(3, 0).unwrap().number(), 33);
+
+        // only A1 queued: ordering alone must get the join right
+        s.clear_cell(0, 0, &mut msg);
+        s.update_cell_value(0, 0, 4, CellStatus::Ok);
+        s.mark_dirty(0, 0);
+        assert_eq!(s.recalc_dirty(&mut msg), 3);
+        assert_eq!(s.get_cell(3, 0).unwrap().number(), 44);
     }
 
+    /// update_cell_formula checks formulas with parser::validate_formula
     #[test]
-    fn recalc_detects_syntax_error_as_general_error() {
-        let mut sheet = Spreadsheet::new(1, 1);
-        let mut status = String::new();
+    fn update_cell_formula_validates_with_parser() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        for bad in ["FOO(A1)", "A1+1)", "1 2"] {
+            s.update_cell_formula(0, 0, bad, &mut msg);
+            assert_eq!(msg, "Unrecognized", "{bad}");
+        }
+        assert!(s.get_formula(0, 0).is_none());
 
-        // "1?1" passes our simple valid_formula (it has an operator at index 1),
-        // but parse_expr will set error_flag=1 on the '?'.
-        inject_formula(&mut sheet, "1?1");
-        status.clear();
-        recalc_affected(&mut sheet, &mut status);
-        assert_eq!(status, "Error in formula");
+        // a runtime failure is still accepted and shows up as an error cell
+        s.update_cell_formula(0, 0, "B1/C1", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell(0, 0).unwrap().status, CellStatus::Error);
     }
 
-    fn cloneable_sheet_get_cell_various() {
-        let mut sheet = Spreadsheet::new(2, 2);
-        // Set a cell to a non-default value and status
-        sheet.update_cell_value(1, 1, 42, CellStatus::Error);
-        let cs = CloneableSheet::new(&sheet);
+    /// MODE is accepted in cells and follows edits to its range
+    #[test]
+    fn mode_in_sheet() {
+        let mut s = Spreadsheet::new(4, 2);
+        let mut msg = String::new();
+        for (row, v) in ["5", "2", "5", "2"].into_iter().enumerate() {
+            s.update_cell_formula(row as i32, 0, v, &mut msg);
+        }
+        s.update_cell_formula(0, 1, "MODE(A1:A4)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell(0, 1).unwrap().number(), 2);
+        s.update_cell_formula(3, 0, "5", &mut msg);
+        assert_eq!(s.get_cell(0, 1).unwrap().number(), 5);
+        assert!(validates(&s, "MODE(A1:A4)"));
+        assert_eq!(valid_formula(&s, "MODE(A1:A4)", &mut msg), 0);
+    }
 
-        // Out-of-bounds coordinates should return None
-        assert!(cs.get_cell(-1, 0).is_none());
-        assert!(cs.get_cell(0, -1).is_none());
-        assert!(cs.get_cell(2, 0).is_none());
-        assert!(cs.get_cell(0, 2).is_none());
+    /// lowercase function names and references work in cells
+    #[test]
+    fn lowercase_functions_in_sheet() {
+        let mut s = Spreadsheet::new(3, 2);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "3", &mut msg);
+        s.update_cell_formula(1, 0, "5", &mut msg);
+        s.update_cell_formula(2, 0, "sum(a1:a2)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell(2, 0).unwrap().number(), 8);
+        s.update_cell_formula(0, 0, "4", &mut msg);
+        assert_eq!(s.get_cell(2, 0).unwrap().number(), 9);
+
+        assert!(validates(&s, "min(A1:A2)"));
+        assert!(validates(&s, "Sum(A1:A2)"));
+        assert_eq!(valid_formula(&s, "min(A1:A2)", &mut msg), 0);
+        assert_eq!(valid_formula(&s, "Sum(A1:A2)", &mut msg), 0);
+    }
 
-        // In-bounds but non-existent cell should return default view
-        let view = cs.get_cell(0, 1).unwrap();
-        assert_eq!(view.value, 0);
-        assert_eq!(view.status, CellStatus::Ok);
+    /// cell formats change the displayed text only
+    #[test]
+    fn cell_format_is_display_only() {
+        assert_eq!(CellFormat::default().apply(-1234), "-1234");
+        let money = CellFormat {
+            decimals: 2,
+            thousands: true,
+        };
+        assert_eq!(money.apply(-1234567), "-1,234,567.00");
+        assert_eq!(money.apply(999), "999.00");
+        assert_eq!(money.apply(i32::MIN), "-2,147,483,648.00");
 
-        // Existing cell should return its actual value and status
-        let view2 = cs.get_cell(1, 1).unwrap();
-        assert_eq!(view2.value, 42);
-        assert_eq!(view2.status, CellStatus::Error);
+        let mut s = Spreadsheet::new(2, 2);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "12345", &mut msg);
+        s.update_cell_formula(1, 0, "\"12345\"", &mut msg);
+        s.set_cell_format(0, 0, money);
+        s.set_cell_format(1, 0, money);
+        assert_eq!(s.get_cell_display(0, 0), "12,345.00");
+        assert_eq!(s.get_cell_value(0, 0), 12345);
+        // text and empty cells are shown as they are
+        assert_eq!(s.get_cell_display(1, 0), "12345");
+        s.set_cell_format(1, 1, money);
+        assert_eq!(s.get_cell_display(1, 1), "");
+
+        s.set_cell_format(0, 0, CellFormat::default());
+        assert_eq!(s.get_cell_display(0, 0), "12345");
+        assert!(!s.cell_format.contains_key(&(0, 0)));
+
+        // formats move with their rows
+        s.set_cell_format(0, 0, money);
+        s.insert_row(0);
+        assert_eq!(s.get_cell_display(1, 0), "12,345.00");
+        assert_eq!(s.get_cell_format(0, 0), CellFormat::default());
+        s.delete_row(1);
+        let mut formatted: Vec<_> = s.cell_format.keys().copied().collect();
+        formatted.sort();
+        assert_eq!(formatted, [(1, 0), (1, 1)]);
+        assert_eq!(s.get_cell_display(1, 0), "12345");
     }
 
+    /// COUNTIF with a `">"&B1` criterion follows edits to B1
     #[test]
-    fn valid_formula_sleep_and_operator_and_format() {
-        let sheet = Spreadsheet::new(3, 3);
+    #[cfg(feature = "advanced_formulas")]
+    fn countif_cell_criterion_tracks_threshold() {
+        let mut s = Spreadsheet::new(3, 2);
         let mut msg = String::new();
+        for (row, v) in ["1", "4", "7"].into_iter().enumerate() {
+            s.update_cell_formula(row as i32, 0, v, &mut msg);
+        }
+        s.update_cell_formula(0, 1, "3", &mut msg);
+        s.update_cell_formula(1, 1, "COUNTIF(A1:A3,\">\"&B1)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell(1, 1).unwrap().number(), 2);
+        s.update_cell_formula(0, 1, "5", &mut msg);
+        assert_eq!(s.get_cell(1, 1).unwrap().number(), 1);
+    }
 
-        // Missing closing parenthesis in SLEEP
-        let code = valid_formula(&sheet, "SLEEP(5", &mut msg);
-        assert_eq!(code, 1);
-        assert_eq!(msg, "Missing closing parenthesis in SLEEP");
+    /// evaluate: a standalone expression, nothing stored
+    #[test]
+    fn evaluate_standalone_expression() {
+        use crate::parser::FormulaError;
+        let mut s = Spreadsheet::new(3, 2);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "4", &mut msg);
+        for (row, v) in ["1", "2", "3"].into_iter().enumerate() {
+            s.update_cell_formula(row as i32, 1, v, &mut msg);
+        }
+        assert_eq!(s.evaluate("=A1*2+SUM(B1:B3)"), Ok(14));
+        assert_eq!(s.evaluate("A1/A3"), Err(FormulaError::Runtime));
+        assert_eq!(s.evaluate("A1+"), Err(FormulaError::Syntax));
+        assert_eq!(s.evaluate("C1"), Err(FormulaError::OutOfBounds));
+        assert_eq!(s.populated_cell_count(), 4);
+        crate::parser::clear_range_cache();
+    }
 
-        // Valid numeric SLEEP
-        msg.clear();
-        let code = valid_formula(&sheet, "SLEEP( 7 )", &mut msg);
-        assert_eq!(code, 0);
+    /// lazy mode: edits leave dependents dirty until they are read
+    #[test]
+    fn lazy_mode_recomputes_on_read() {
+        let mut s = Spreadsheet::builder(4, 2).lazy(true).build();
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "1", &mut msg);
+        s.update_cell_formula(1, 0, "A1*10", &mut msg);
+        s.update_cell_formula(2, 0, "A1+A2", &mut msg);
+        s.update_cell_formula(0, 1, "SUM(A1:A3)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 22);
+
+        s.update_cell_formula(0, 0, "2", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.pending_recalc(), vec![(0, 1), (1, 0), (2, 0)]);
+        assert_eq!(s.get_cell_value(2, 0), 11, "stale until read");
+
+        assert_eq!(s.get_cell_value_computed(2, 0), 22);
+        // only what A3 needed was recomputed
+        assert_eq!(s.pending_recalc(), vec![(0, 1)]);
+        assert_eq!(s.get_cell_value_computed(0, 1), 44);
+        assert!(s.pending_recalc().is_empty());
+
+        // a new formula over a stale cell reads its fresh value
+        s.update_cell_formula(0, 0, "3", &mut msg);
+        s.update_cell_formula(3, 0, "A3+1", &mut msg);
+        assert_eq!(s.get_cell_value(3, 0), 34);
+        crate::parser::clear_range_cache();
+    }
 
-        // Invalid cell reference in SLEEP
-        msg.clear();
-        let code = valid_formula(&sheet, "SLEEP(ABC)", &mut msg);
-        assert_eq!(code, 1);
-        assert_eq!(msg, "Invalid cell reference in SLEEP");
+    /// clear_all: empty again, same size and settings
+    #[test]
+    fn clear_all_keeps_dimensions_and_settings() {
+        let mut s = Spreadsheet::builder(5, 5).output_enabled(false).build();
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "3", &mut msg);
+        s.update_cell_formula(1, 1, "SUM(A1:A2)", &mut msg);
+        s.define_name("Top", "A1:A2", &mut msg);
+        s.top_row = 2;
+        s.cursor = Some((1, 1));
+        let rev = s.revision;
+
+        s.clear_all();
+        assert_eq!(s.populated_cell_count(), 0);
+        assert!(s.formula_storage.is_empty() && s.named_ranges.is_empty());
+        assert_eq!((s.total_rows, s.total_cols), (5, 5));
+        assert!(!s.output_enabled);
+        assert_eq!((s.top_row, s.left_col, s.cursor), (0, 0, None));
+        assert_eq!(s.modified_since(rev), vec![(0, 0), (1, 0), (1, 1)]);
+        assert!(crate::parser::RANGE_CACHE.with(|cache| cache.borrow().is_empty()));
+        #[cfg(feature = "undo_state")]
+        {
+            s.undo(&mut msg);
+            assert_eq!(s.populated_cell_count(), 0);
+        }
 
-        // Operator not found
-        msg.clear();
-        let code = valid_formula(&sheet, "foobar", &mut msg);
-        assert_eq!(code, 1);
-        assert_eq!(msg, "Operator not found");
+        // and it is usable again
+        s.update_cell_formula(0, 0, "7", &mut msg);
+        s.update_cell_formula(0, 1, "A1*2", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 14);
+    }
 
-        // Invalid formula format (right side not int or cell)
-        msg.clear();
-        let code = valid_formula(&sheet, "A1+foo", &mut msg);
-        assert_eq!(code, 1);
-        assert_eq!(msg, "Invalid formula format");
+    /// notes: stored beside the value, ignored by evaluation, kept by clear
+    #[test]
+    fn notes_are_independent_of_values() {
+        let mut s = Spreadsheet::new(3, 3);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 0, "4", &mut msg);
+        s.update_cell_formula(0, 1, "A1*2", &mut msg);
+        s.set_note(0, 0, "units sold");
+        s.set_note(2, 2, "todo");
+        s.set_note(5, 5, "off the grid");
+        assert_eq!(s.get_note(0, 0), Some("units sold"));
+        assert_eq!(s.get_note(2, 2), Some("todo"));
+        assert_eq!(s.get_note(5, 5), None);
+        assert_eq!(s.get_cell_value(2, 2), 0);
+        assert!(s.cells[&(2, 2)].dependents.is_empty());
+
+        s.update_cell_formula(0, 0, "5", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 10);
+        assert_eq!(s.get_note(0, 0), Some("units sold"));
+
+        s.clear_cell(0, 0, &mut msg);
+        assert_eq!(s.get_note(0, 0), Some("units sold"));
+        assert_eq!(s.get_cell_value(0, 1), 0);
+
+        // removing the only thing a cell held drops the entry
+        s.set_note(2, 2, "");
+        assert_eq!(s.get_note(2, 2), None);
+        assert!(!s.cells.contains_key(&(2, 2)));
     }
 
-    fn process_command_scroll_to_invalid_cell() {
-        let mut sheet = Spreadsheet::new(5, 5);
+    /// all_formulas: formula cells only, row-major
+    #[test]
+    fn all_formulas_lists_formula_cells_in_order() {
+        let mut s = Spreadsheet::new(3, 3);
         let mut msg = String::new();
-        process_command(&mut sheet, "scroll_to foo", &mut msg);
-        assert!(
-            msg.contains("Invalid cell"),
-            "got `{}` expected substring `Invalid cell`",
-            msg
+        s.update_cell_formula(2, 0, "B1+1", &mut msg);
+        s.update_cell_formula(0, 1, "SUM(A1:A2)", &mut msg);
+        s.update_cell_formula(0, 2, "B1*2", &mut msg);
+        s.update_cell_value(1, 1, 9, CellStatus::Ok);
+        assert_eq!(
+            s.all_formulas(),
+            vec![
+                ((0, 1), "SUM(A1:A2)".to_string()),
+                ((0, 2), "B1*2".to_string()),
+                ((2, 0), "B1+1".to_string()),
+            ]
         );
+        assert!(Spreadsheet::new(2, 2).all_formulas().is_empty());
     }
 
+    /// default_value / skip_absent: how blank cells read and aggregate
     #[test]
-    fn process_command_clear_cache() {
-        let mut sheet = Spreadsheet::new(3, 3);
+    fn blank_cells_default_value_and_skip_absent() {
+        crate::parser::clear_range_cache();
         let mut msg = String::new();
-        sheet.cache.insert(
-            "X".into(),
-            CachedRange {
-                value: 1,
-                dependencies: HashSet::new(),
-            },
-        );
-        process_command(&mut sheet, "clear_cache", &mut msg);
-        assert_eq!(msg, "Cache cleared");
-        assert!(sheet.cache.is_empty());
+
+        // default: blanks are zeros, and counted
+        let mut s = Spreadsheet::new(4, 2);
+        s.update_cell_formula(0, 0, "8", &mut msg);
+        s.update_cell_formula(0, 1, "AVG(A1:A4)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 2);
+
+        let mut s = Spreadsheet::builder(4, 2).default_value(5).build();
+        s.update_cell_formula(0, 0, "8", &mut msg);
+        s.update_cell_formula(0, 1, "A2+1", &mut msg);
+        s.update_cell_formula(1, 1, "SUM(A1:A4)", &mut msg);
+        s.update_cell_formula(2, 1, "MIN(A1:A4)", &mut msg);
+        // A2 now exists as a placeholder for B1's link, but is still blank
+        assert!(s.is_blank(1, 0));
+        assert_eq!(s.get_cell_value(1, 0), 5);
+        assert_eq!(s.get_cell_value(0, 1), 6);
+        assert_eq!(s.get_cell_value(1, 1), 23);
+        assert_eq!(s.get_cell_value(2, 1), 5);
+        s.update_cell_formula(1, 0, "0", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 1);
+        assert_eq!(s.get_cell_value(2, 1), 0);
+        // an explicit 0 is a value, however it was entered
+        s.set_literal(2, 0, 0);
+        s.update_cell_value(3, 0, 0, CellStatus::Ok);
+        assert!(!s.is_blank(2, 0) && !s.is_blank(3, 0));
+        assert_eq!(s.get_cell_value(2, 0), 0);
+        assert_eq!(s.get_cell_value(3, 0), 0);
+
+        crate::parser::clear_range_cache();
+        let mut s = Spreadsheet::builder(4, 2).skip_absent(true).build();
+        s.update_cell_formula(0, 0, "8", &mut msg);
+        s.update_cell_formula(0, 1, "AVG(A1:A4)", &mut msg);
+        s.update_cell_formula(1, 1, "MODE(A2:A4)", &mut msg);
+        s.update_cell_formula(2, 1, "SUM(A2:A4)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 8);
+        assert_eq!(s.get_cell_status(1, 1), CellStatus::Error);
+        assert_eq!(s.get_cell_status(2, 1), CellStatus::Ok);
+        assert_eq!(s.get_cell_value(2, 1), 0);
+        // filling a skipped cell reaches the cached aggregates
+        s.update_cell_formula(3, 0, "2", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 5);
+        assert_eq!(s.get_cell_value(1, 1), 2);
+        assert_eq!(s.get_cell_value(2, 1), 2);
+        // a real zero is aggregated; clearing it skips the cell again
+        s.set_literal(2, 0, 0);
+        assert_eq!(s.get_cell_value(0, 1), 3);
+        s.clear_cell(2, 0, &mut msg);
+        assert!(s.is_blank(2, 0));
+        assert_eq!(s.get_cell_value(0, 1), 5);
+
+        // a cached aggregate isn't reused once the blank cell setting changes
+        s.skip_absent = false;
+        s.mark_dirty(0, 1);
+        s.recalc_dirty(&mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 3);
     }
 
+    /// COUNTIF and PERCENTILE read blank cells like the other aggregates
+    #[cfg(feature = "advanced_formulas")]
     #[test]
-    fn cloneable_get_cell_bounds_and_default() {
-        let sheet = Spreadsheet::new(2, 2);
-        let cs = crate::sheet::CloneableSheet::new(&*sheet);
-        // out of bounds → None
-        assert!(cs.get_cell(-1, 0).is_none());
-        assert!(cs.get_cell(0, -1).is_none());
-        assert!(cs.get_cell(2, 0).is_none());
-        // in bounds but never set → Some(default)
-        let cv = cs.get_cell(1, 1).unwrap();
-        assert_eq!(cv.value, 0);
-        assert_eq!(cv.status, CellStatus::Ok);
+    fn blank_cells_in_countif_and_percentile() {
+        let mut msg = String::new();
+        let mut s = Spreadsheet::builder(4, 2).default_value(5).build();
+        s.update_cell_formula(0, 0, "8", &mut msg);
+        s.update_cell_formula(0, 1, "COUNTIF(A1:A4,\"=5\")", &mut msg);
+        s.update_cell_formula(1, 1, "PERCENTILE(A1:A4,100)", &mut msg);
+        s.update_cell_formula(2, 1, "PERCENTILE(A1:A4,0)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 3);
+        assert_eq!(s.get_cell_value(1, 1), 8);
+        assert_eq!(s.get_cell_value(2, 1), 5);
+
+        let mut s = Spreadsheet::builder(4, 2).skip_absent(true).build();
+        s.update_cell_formula(0, 0, "8", &mut msg);
+        s.update_cell_formula(0, 1, "COUNTIF(A1:A4,\"<1\")", &mut msg);
+        s.update_cell_formula(1, 1, "PERCENTILE(A1:A4,0)", &mut msg);
+        s.update_cell_formula(2, 1, "PERCENTILE(A2:A4,50)", &mut msg);
+        assert_eq!(s.get_cell_value(0, 1), 0);
+        assert_eq!(s.get_cell_value(1, 1), 8);
+        // every cell skipped: an evaluation error, as for AVG
+        assert_eq!(s.get_cell_status(2, 1), CellStatus::Error);
     }
 
+    /// get_column / get_row: whole lines as values, empty when off the grid
     #[test]
-    fn extract_dependencies_without_self_multi_letter_cols() {
-        let deps = crate::sheet::extract_dependencies_without_self("AA1:AB2", 100, 100);
-        let mut want = HashSet::new();
-        // AA → col 26, AB → col 27 (0-based)
-        for r in 0..=1 {
-            for c in 26..=27 {
-                want.insert((r, c));
-            }
-        }
-        assert_eq!(deps, want);
+    fn get_column_and_row() {
+        let mut s = Spreadsheet::new(3, 2);
+        let mut msg = String::new();
+        s.update_cell_formula(0, 1, "4", &mut msg);
+        s.update_cell_formula(2, 1, "B1*2", &mut msg);
+        s.update_cell_formula(1, 1, "1/0", &mut msg);
+        assert_eq!(s.get_column(1), vec![4, 0, 8]);
+        assert_eq!(
+            s.get_column_statuses(1),
+            vec![CellStatus::Ok, CellStatus::Error, CellStatus::Ok]
+        );
+        assert_eq!(s.get_row(2), vec![0, 8]);
+        assert!(s.get_column(2).is_empty() && s.get_column(-1).is_empty());
+        assert!(s.get_column_statuses(5).is_empty());
+        assert!(s.get_row(3).is_empty());
     }
 
-    /// valid_formula: missing colon in range
+    /// A range past the sheet reads the same at validation and evaluation
     #[test]
-    fn sheet_valid_formula_missing_colon() {
-        let s = Spreadsheet::new(3, 3);
+    fn range_out_of_bounds_reported_consistently() {
+        let mut s = Spreadsheet::new(50, 3);
         let mut msg = String::new();
-        assert_eq!(valid_formula(&s, "SUM(A1A2)", &mut msg), 1);
-        assert!(msg.contains("Missing colon"));
+        for formula in ["SUM(A1:A100)", "MAX(A51:A60)", "AVG(A1:D2)"] {
+            assert_eq!(valid_formula(&s, formula, &mut msg), 1, "{formula}");
+            assert_eq!(msg, "Range out of bounds", "{formula}");
+        }
+        // valid_formula doesn't take compound expressions, the others do
+        for formula in [
+            "SUM(A1:A100)",
+            "MAX(A51:A60)",
+            "AVG(A1:D2)",
+            "SUM(A1:A2)+MIN(B1:B51)",
+        ] {
+            assert_eq!(
+                crate::parser::validate_formula(formula, 50, 3),
+                Err(crate::parser::FormulaError::OutOfBounds),
+                "{formula}"
+            );
+            s.update_cell_formula(0, 2, formula, &mut msg);
+            assert_eq!(msg, "Range out of bounds", "{formula}");
+            assert_eq!(
+                s.evaluate(formula),
+                Err(crate::parser::FormulaError::OutOfBounds),
+                "{formula}"
+            );
+        }
+        assert!(s.get_formula(0, 2).is_none());
+
+        // the last row itself is still fine
+        assert!(validates(&s, "SUM(A1:A50)"));
+        assert_eq!(valid_formula(&s, "SUM(A1:A50)", &mut msg), 0);
+        s.update_cell_formula(0, 2, "SUM(A1:A50)", &mut msg);
+        assert_eq!(msg, "Ok");
     }
 
-    /// valid_formula: invalid range order
+    /// set_literal: replaces a formula for good and updates dependents
     #[test]
-    fn sheet_valid_formula_invalid_range_order() {
-        let s = Spreadsheet::new(3, 3);
+    fn set_literal_replaces_formula() {
+        let mut s = Spreadsheet::new(3, 3);
         let mut msg = String::new();
-        assert_eq!(valid_formula(&s, "SUM(B2:A1)", &mut msg), 1);
-        assert!(msg.contains("Invalid range order"));
+        s.update_cell_formula(0, 0, "2", &mut msg);
+        s.update_cell_formula(0, 1, "A1*10", &mut msg);
+        s.update_cell_formula(0, 2, "B1+1", &mut msg);
+
+        s.set_literal(0, 1, 7);
+        assert_eq!(s.get_formula(0, 1), None);
+        assert_eq!(s.get_cell_value(0, 1), 7);
+        assert_eq!(s.get_cell_value(0, 2), 8);
+        assert!(s.get_dependents(0, 0).is_empty());
+
+        // A1 no longer feeds B1, and a full recalculation keeps the literal
+        s.update_cell_formula(0, 0, "5", &mut msg);
+        assert_eq!(s.recalculate_all(&mut msg), 2);
+        assert_eq!(s.get_cell_value(0, 1), 7);
+        assert_eq!(s.get_cell_value(0, 2), 8);
+
+        s.set_literal(9, 9, 1);
+        assert!(s.is_blank(9, 9));
+
+        #[cfg(feature = "undo_state")]
+        {
+            s.undo(&mut msg); // A1=5
+            s.undo(&mut msg); // B1=7
+            assert_eq!(s.get_formula(0, 1).as_deref(), Some("A1*10"));
+            assert_eq!(s.get_cell_value(0, 1), 20);
+            assert_eq!(s.get_cell_value(0, 2), 21);
+        }
     }
 
-    /// valid_formula: missing ')' on SLEEP
+    /// Workbook: `Name!A1` reads another sheet and follows its edits
     #[test]
-    fn sheet_valid_formula_sleep_missing_paren() {
-        let s = Spreadsheet::new(1, 1);
+    fn workbook_cross_sheet_references() {
+        let mut wb = Workbook::new();
         let mut msg = String::new();
-        assert_eq!(valid_formula(&s, "SLEEP(1", &mut msg), 1);
-        assert!(msg.contains("Missing closing parenthesis"));
+        wb.add_sheet("Data", 5, 2, &mut msg);
+        assert_eq!(msg, "Ok");
+        wb.add_sheet("Sheet2", 3, 3, &mut msg);
+        for (bad, err) in [
+            ("data", "Sheet already exists"),
+            ("2nd", "Invalid sheet name"),
+            ("My_Sheet", "Invalid sheet name"),
+        ] {
+            wb.add_sheet(bad, 3, 3, &mut msg);
+            assert_eq!(msg, err, "{bad}");
+        }
+        assert_eq!(wb.sheet_names(), vec!["Data", "Sheet2"]);
+
+        wb.update_cell_formula("Data", 0, 0, "4", &mut msg);
+        wb.update_cell_formula("Data", 1, 0, "A1*10", &mut msg);
+        wb.update_cell_formula("Sheet2", 0, 0, "Data!A2+data!a1", &mut msg);
+        assert_eq!(msg, "Ok");
+        wb.update_cell_formula("Sheet2", 0, 1, "A1*2", &mut msg);
+        let sheet2 = wb.get_sheet("sheet2").unwrap();
+        assert_eq!(sheet2.get_cell_value(0, 0), 44);
+        assert_eq!(sheet2.get_cell_value(0, 1), 88);
+        // `Data!A2` is not a dependency on Sheet2's own A2
+        assert!(sheet2.get_dependencies(0, 0).is_empty());
+
+        // edits on Data reach Sheet2, and its dependents
+        wb.update_cell_formula("Data", 0, 0, "5", &mut msg);
+        let sheet2 = wb.get_sheet("Sheet2").unwrap();
+        assert_eq!(sheet2.get_cell_value(0, 0), 55);
+        assert_eq!(sheet2.get_cell_value(0, 1), 110);
+
+        // and back again, across both directions
+        wb.update_cell_formula("Data", 4, 1, "Sheet2!B1+1", &mut msg);
+        assert_eq!(wb.get_sheet("Data").unwrap().get_cell_value(4, 1), 111);
+
+        for (formula, err) in [
+            ("Nope!A1", "Unknown sheet"),
+            ("Data!C1", "Range out of bounds"),
+            ("1+Data!A1:A2", "Unrecognized"),
+        ] {
+            wb.update_cell_formula("Sheet2", 2, 2, formula, &mut msg);
+            assert_eq!(msg, err, "{formula}");
+        }
+        assert_eq!(wb.get_sheet("Sheet2").unwrap().get_formula(2, 2), None);
+        wb.update_cell_formula("Data", 0, 0, "1/0", &mut msg);
+        let sheet2 = wb.get_sheet("Sheet2").unwrap();
+        assert_eq!(sheet2.get_cell_status(0, 0), CellStatus::Error);
+        wb.update_cell_formula("Nope", 0, 0, "1", &mut msg);
+        assert_eq!(msg, "Unknown sheet");
+
+        // outside a workbook the sheet name can't be resolved
+        let mut lone = Spreadsheet::new(2, 2);
+        lone.update_cell_formula(0, 0, "Data!A1", &mut msg);
+        assert_eq!(msg, "Unknown sheet");
+        assert!(lone.all_formulas().is_empty());
     }
 
-    /// valid_formula: invalid cell in SLEEP
+    /// Workbook: the same range formula on two sheets reads each sheet's own cells
     #[test]
-    fn sheet_valid_formula_sleep_invalid_cell() {
-        let s = Spreadsheet::new(1, 1);
+    fn workbook_range_cache_is_per_sheet() {
+        let mut wb = Workbook::new();
         let mut msg = String::new();
-        assert_eq!(valid_formula(&s, "SLEEP(B2)", &mut msg), 1);
-        assert!(msg.contains("out of bounds"));
+        wb.add_sheet("Sheet1", 3, 3, &mut msg);
+        wb.add_sheet("Sheet2", 3, 3, &mut msg);
+        wb.update_cell_formula("Sheet1", 0, 0, "1", &mut msg);
+        wb.update_cell_formula("Sheet2", 0, 0, "5", &mut msg);
+        wb.update_cell_formula("Sheet1", 0, 1, "SUM(A1:A1)", &mut msg);
+        wb.update_cell_formula("Sheet2", 0, 1, "SUM(A1:A1)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(wb.get_sheet("Sheet1").unwrap().get_cell_value(0, 1), 1);
+        assert_eq!(wb.get_sheet("Sheet2").unwrap().get_cell_value(0, 1), 5);
     }
 
-    /// valid_formula: operator not found
+    /// insert_row leaves references into other sheets alone
     #[test]
-    fn sheet_valid_formula_operator_not_found() {
-        let s = Spreadsheet::new(1, 1);
+    fn insert_row_keeps_cross_sheet_references() {
+        let mut wb = Workbook::new();
         let mut msg = String::new();
-        assert_eq!(valid_formula(&s, "foo", &mut msg), 1);
-        assert!(msg.contains("Operator not found"));
+        wb.add_sheet("Data", 3, 1, &mut msg);
+        wb.add_sheet("Calc", 3, 1, &mut msg);
+        wb.update_cell_formula("Calc", 1, 0, "Data!A2+A1", &mut msg);
+        let calc = wb.sheet_mut("calc").unwrap();
+        calc.insert_row(0);
+        assert_eq!(calc.get_formula(2, 0).as_deref(), Some("Data!A2+A2"));
     }
 
-    /// valid_formula: invalid format
+    /// Workbook::insert_row/delete_row move other sheets' references too
+    #[test]
+    fn workbook_row_changes_follow_cross_sheet_references() {
+        let mut wb = Workbook::new();
+        let mut msg = String::new();
+        wb.add_sheet("Data", 3, 1, &mut msg);
+        wb.add_sheet("Calc", 2, 2, &mut msg);
+        wb.add_sheet("Other", 1, 1, &mut msg);
+        for (row, v) in ["1", "2", "3"].into_iter().enumerate() {
+            wb.update_cell_formula("Data", row as i32, 0, v, &mut msg);
+        }
+        wb.update_cell_formula("Calc", 0, 0, "data!A2*10+Data!A3", &mut msg);
+        wb.update_cell_formula("Calc", 0, 1, "A1+1", &mut msg);
+        wb.update_cell_formula("Other", 0, 0, "Calc!B1", &mut msg);
+        assert_eq!(wb.get_sheet("Other").unwrap().get_cell_value(0, 0), 24);
+
+        // an edit only re-evaluates the sheets that read the edited one
+        let calc_rev = wb.get_sheet("Calc").unwrap().modified_revs[&(0, 0)];
+        wb.update_cell_formula("Other", 0, 0, "Calc!B1*2", &mut msg);
+        assert_eq!(
+            wb.get_sheet("Calc").unwrap().modified_revs[&(0, 0)],
+            calc_rev
+        );
 
-    /// recalc_affected: invalid range sets "Invalid range"
+        wb.insert_row("Data", 0, &mut msg);
+        assert_eq!(msg, "Ok");
+        let calc = wb.get_sheet("Calc").unwrap();
+        assert_eq!(
+            calc.get_formula(0, 0).as_deref(),
+            Some("data!A3*10+Data!A4")
+        );
+        assert_eq!(calc.get_cell_value(0, 1), 24);
+        wb.update_cell_formula("Data", 2, 0, "5", &mut msg);
+        assert_eq!(wb.get_sheet("Other").unwrap().get_cell_value(0, 0), 108);
+
+        // out-of-range rows change nothing
+        wb.delete_row("Data", 9, &mut msg);
+        assert_eq!(wb.get_sheet("Calc").unwrap().get_cell_value(0, 1), 54);
+
+        // deleting a row another sheet reads is a #REF there, and onwards
+        wb.delete_row("Data", 2, &mut msg);
+        let calc = wb.get_sheet("Calc").unwrap();
+        assert_eq!(calc.get_formula(0, 0), None);
+        assert_eq!(calc.get_cell_status(0, 0), CellStatus::Error);
+        assert_eq!(calc.get_cell_status(0, 1), CellStatus::Error);
+        let other = wb.get_sheet("Other").unwrap();
+        assert_eq!(other.get_cell_status(0, 0), CellStatus::Error);
+
+        wb.insert_row("Nope", 0, &mut msg);
+        assert_eq!(msg, "Unknown sheet");
+
+        // quoted text and other sheets are left alone
+        assert_eq!(
+            remap_sheet_refs("\"Data!A2\"&DATA!a2+Calc!A2", "data", |r| Some(r + 1)).as_deref(),
+            Some("\"Data!A2\"&DATA!A3+Calc!A2")
+        );
+    }
 
-    /// recalc_affected: general error sets "Error in formula"
+    /// Workbook: a sheet's own `Name!A1` formulas are re-evaluated after
+    /// its row changes, with the other sheets still readable
+    #[test]
+    fn workbook_row_changes_recalculate_own_cross_sheet_formulas() {
+        let mut wb = Workbook::new();
+        let mut msg = String::new();
+        wb.add_sheet("Sheet1", 3, 3, &mut msg);
+        wb.add_sheet("Sheet2", 3, 3, &mut msg);
+        wb.update_cell_formula("Sheet2", 0, 0, "5", &mut msg);
+        wb.update_cell_formula("Sheet1", 0, 0, "1", &mut msg);
+        wb.update_cell_formula("Sheet1", 1, 0, "2", &mut msg);
+        wb.update_cell_formula("Sheet1", 2, 0, "Sheet2!A1+SUM(A1:A2)", &mut msg);
+        assert_eq!(wb.get_sheet("Sheet1").unwrap().get_cell_value(2, 0), 8);
+
+        // the range shrinks to A1:A1, which now holds the 2
+        wb.delete_row("Sheet1", 0, &mut msg);
+        let sheet1 = wb.get_sheet("Sheet1").unwrap();
+        assert_eq!(
+            sheet1.get_formula(1, 0).as_deref(),
+            Some("Sheet2!A1+SUM(A1:A1)")
+        );
+        assert_eq!(sheet1.get_cell_status(1, 0), CellStatus::Ok);
+        assert_eq!(sheet1.get_cell_value(1, 0), 7);
+        // `Sheet2` is not read as a cell of this sheet
+        assert_eq!(sheet1.get_dependencies(1, 0), vec![(0, 0)]);
+        assert!(sheet1.cells.keys().all(|&(_, col)| col < 3));
+
+        wb.insert_row("Sheet1", 0, &mut msg);
+        let sheet1 = wb.get_sheet("Sheet1").unwrap();
+        assert_eq!(
+            sheet1.get_formula(2, 0).as_deref(),
+            Some("Sheet2!A1+SUM(A2:A2)")
+        );
+        assert_eq!(sheet1.get_cell_value(2, 0), 7);
+    }
 
-    /// extract_dependencies_without_self: two‐letter columns
     #[test]
-    fn sheet_extract_deps_without_self_two_letter() {
-        let deps = extract_dependencies_without_self("AA1:AB2", 100, 100);
-        let mut want = HashSet::new();
-        // AA→col 26, AB→col 27 (0-based)
-        for r in 0..=1 {
-            for c in 26..=27 {
-                want.insert((r, c));
-            }
+    fn header_names_resolve_to_columns() {
+        let mut s = Spreadsheet::builder(6, 4).header_row(0).build();
+        let mut msg = String::new();
+        s.update_cell_formula(0, 1, "\"Qty\"", &mut msg);
+        s.update_cell_formula(0, 2, "\"Price\"", &mut msg);
+        s.update_cell_formula(0, 3, "\"not a name\"", &mut msg);
+        for (row, v) in [1, 2, 3].into_iter().enumerate() {
+            s.set_literal(row as i32 + 1, 2, v);
         }
-        assert_eq!(deps, want);
+        assert_eq!(
+            s.header_columns(),
+            HashMap::from([("Qty".to_string(), 1), ("Price".to_string(), 2)])
+        );
+        assert_eq!(s.resolve_name("Price"), Some((1, 2, 5, 2)));
+
+        s.update_cell_formula(5, 0, "SUM(Price)+MAX(Qty)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(5, 0), 6);
+        s.set_literal(2, 2, 10);
+        assert_eq!(s.get_cell_value(5, 0), 14);
+
+        // renaming the header breaks its users until the name comes back
+        s.update_cell_formula(0, 2, "\"Cost\"", &mut msg);
+        assert_eq!(s.get_cell_status(5, 0), CellStatus::Error);
+        s.set_literal(3, 2, 20);
+        assert_eq!(s.get_cell_status(5, 0), CellStatus::Error);
+        s.update_cell_formula(0, 2, "\"Price\"", &mut msg);
+        assert_eq!(s.get_cell_status(5, 0), CellStatus::Ok);
+        assert_eq!(s.get_cell_value(5, 0), 31);
+
+        // a defined name wins over a header
+        s.set_literal(4, 1, 7);
+        s.define_name("Price", "B5:B5", &mut msg);
+        assert_eq!(s.get_cell_value(5, 0), 14);
+
+        s.set_header_row(None, &mut msg);
+        assert_eq!(s.get_cell_status(5, 0), CellStatus::Error);
+        s.set_header_row(Some(0), &mut msg);
+        assert_eq!(s.get_cell_status(5, 0), CellStatus::Ok);
+
+        // a header that is a function name names nothing, and calls still work
+        s.update_cell_formula(0, 0, "\"MAX\"", &mut msg);
+        assert!(!s.header_columns().contains_key("MAX"));
+        s.update_cell_formula(4, 3, "MAX(B2:B5)", &mut msg);
+        assert_eq!(msg, "Ok");
+        assert_eq!(s.get_cell_value(4, 3), 7);
+
+        // the header row moves with inserted rows; deleting it breaks users
+        s.insert_row(0);
+        assert_eq!(s.header_row, Some(1));
+        assert_eq!(s.get_formula(6, 0).as_deref(), Some("SUM(Price)+MAX(Qty)"));
+        assert_eq!(s.get_cell_value(6, 0), 14);
+        s.delete_row(1);
+        assert_eq!(s.header_row, None);
+        assert_eq!(s.get_cell_status(5, 0), CellStatus::Error);
+        s.set_header_row(None, &mut msg);
+        s.update_cell_formula(5, 1, "SUM(Qty)", &mut msg);
+        assert_eq!(msg, "Unrecognized");
     }
 
-    /// extract_dependencies: simple single + range
     #[test]
-    fn sheet_extract_dependencies_basic() {
-        let s = Spreadsheet::new(3, 3);
-        let single = extract_dependencies(&s, "B2");
-        assert_eq!(single, vec![(1, 1)].into_iter().collect());
-
-        let range = extract_dependencies(&s, "SUM(A1:C1)");
-        let mut want = HashSet::new();
-        for c in 0..3 {
-            want.insert((0, c));
-        }
-        assert_eq!(range, want);
+    fn load_grid_and_formulas_size_the_sheet() {
+        let mut s = Spreadsheet::builder(2, 2)
+            .output_enabled(false)
+            .default_value(5)
+            .build();
+        s.set_literal(1, 1, 9);
+        s.load_grid(&[vec![1, 0, 3], vec![4], vec![]]);
+        assert_eq!((s.total_rows, s.total_cols), (3, 3));
+        assert!(!s.output_enabled);
+        // loaded zeros are values; only cells past a short row are blank
+        assert_eq!(s.get_row(0), vec![1, 0, 3]);
+        assert_eq!(s.get_row(1), vec![4, 5, 5]);
+        assert!(s.is_blank(1, 1));
+
+        // formulas read cells entered after them, on later rows too
+        let mut msg = String::new();
+        s.load_formulas(
+            &[
+                vec!["A2+B2", "\"total\""],
+                vec!["B3*2", "7", "0"],
+                vec!["", "SUM(B2:C2)"],
+            ],
+            &mut msg,
+        );
+        assert_eq!(msg, "Ok");
+        assert_eq!((s.total_rows, s.total_cols), (3, 3));
+        assert_eq!(s.get_cell_value(1, 0), 14);
+        assert_eq!(s.get_cell_value(0, 0), 21);
+        assert_eq!(s.get_cell_value(2, 1), 7);
+        assert_eq!(s.get_cell_display(0, 1), "total");
+        assert!(s.is_blank(0, 2));
+
+        s.load_formulas(&[vec!["1", "SUM(A1:Z9)", "B1"], vec!["A2"]], &mut msg);
+        assert_eq!(msg, "Range out of bounds (B1)");
+        assert_eq!(s.get_formula(0, 1), None);
+        assert_eq!(s.get_cell_value(0, 0), 1);
+        // C1 reads the rejected, so still blank, B1
+        assert_eq!(s.get_cell_value(0, 2), 5);
+        s.load_formulas(&[vec!["B1", "A1"]], &mut msg);
+        assert_eq!(msg, "Circular dependency detected in cell B1");
+        #[cfg(feature = "undo_state")]
+        assert!(!s.can_undo());
     }
 }